@@ -19,6 +19,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         File::create(out.join("vpd.bin"))?.set_len(0)?;
     }
 
+    // Optionally build in a corrupt copy of the given VPD, parsed in place
+    // of the OTP, to test booting with unreadable product data. The last
+    // byte is the final chunk's body checksum.
+    println!("cargo:rerun-if-env-changed=TEST_CORRUPT_VPD");
+    if let Ok(path) = std::env::var("TEST_CORRUPT_VPD") {
+        let mut vpd = pack(&load(File::open(path)?)?);
+        if let Some(last) = vpd.last_mut() {
+            *last ^= 0xff;
+        }
+        File::create(out.join("corrupt_vpd.bin"))?.write_all(&vpd)?;
+    } else {
+        File::create(out.join("corrupt_vpd.bin"))?.set_len(0)?;
+    }
+
+    // Flash layout. Generated because the DFU memory map must be a constant
+    // string. The device runs in dual bank mode and firmware updates are
+    // written to the inactive bank, so only one bank is advertised. Keep
+    // `memory.x` in sync when changing the flash size.
+    println!("cargo:rerun-if-env-changed=FLASH_SIZE_KB");
+    let flash_size_kb: u32 = match std::env::var("FLASH_SIZE_KB") {
+        Ok(size) => size.parse()?,
+        Err(_) => 512,
+    };
+    let bank_size = flash_size_kb * 1024 / 2;
+    let bank_pages = bank_size / 2048;
+    write!(
+        File::create(out.join("flash.rs"))?,
+        "/// Total flash size in KiB.\n\
+        pub const FLASH_SIZE_KB: u16 = {flash_size_kb};\n\
+        /// Size of each bank in dual bank mode.\n\
+        pub const BANK_SIZE: u32 = {bank_size:#x};\n\
+        /// Pages in each bank.\n\
+        pub const BANK_PAGES: u32 = {bank_pages};\n\
+        /// DFU memory map of one bank.\n\
+        pub const MEM_INFO: &str = \"@Flash/0x08000000/{bank_pages}*2Kf\";\n",
+    )?;
+
+    // Software receive queue depth per interface. Each slot costs 80 bytes
+    // of RAM per interface.
+    println!("cargo:rerun-if-env-changed=RX_QUEUE_DEPTH");
+    let rx_queue_depth: usize = match std::env::var("RX_QUEUE_DEPTH") {
+        Ok(depth) => depth.parse()?,
+        Err(_) => 16,
+    };
+    if rx_queue_depth == 0 {
+        return Err("RX_QUEUE_DEPTH must be at least 1".into());
+    }
+    write!(
+        File::create(out.join("rx.rs"))?,
+        "/// Frames queued per interface on their way to the host.\n\
+        pub const RX_QUEUE_DEPTH: usize = {rx_queue_depth};\n",
+    )?;
+
     // put `memory.x` in our output directory and ensure it's on the linker
     // search path.
     File::create(out.join("memory.x"))?
@@ -39,6 +92,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     )?;
     println!("cargo:rustc-env=CRATE_GIT_HASH={}", git_hash);
 
+    // Release channel (e.g. "stable", "beta" or "internal") so fleet tooling
+    // can tell test builds from released firmware.
+    println!("cargo:rerun-if-env-changed=RELEASE_CHANNEL");
+    let channel =
+        std::env::var("RELEASE_CHANNEL").unwrap_or_else(|_| "dev".into());
+    println!("cargo:rustc-env=CRATE_RELEASE_CHANNEL={}", channel);
+
     // ensure the project is rebuilt when memory.x is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
@@ -1,5 +1,7 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  /* Last three 2K pages of each bank are reserved for the image record
+     and settings. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 250K
   RAM : ORIGIN = 0x20000000, LENGTH = 96K
 }
@@ -1,18 +1,41 @@
 //! Controller area network.
 
-use crate::hal::{
-    can::Can,
-    stm32::{FDCAN2, FDCAN3},
-    time::Hertz,
+use crate::{
+    echo::Echoes,
+    error::Error,
+    event,
+    hal::{
+        can::Can,
+        cortex_m::asm,
+        stm32::{FDCAN1, FDCAN2, FDCAN3},
+        time::Hertz,
+    },
+    history::TxHistory,
+    latency::LatencyTest,
+    limit::RateLimit,
+    marker::Completions,
+    pattern::Pattern,
+    route::RoutingTable,
+    rx::RxQueue,
+    settings::Settings,
+    transceiver::Transceivers,
+    tx::{Duration, Instant, TxFrame, TxQueue, CORE_CLOCK_MHZ},
+    Mono,
 };
 use core::num::{NonZeroU16, NonZeroU8};
 use embedded_can::{Frame as _, Id};
 use fdcan::{
-    config::{DataBitTiming, InterruptLine, NominalBitTiming},
-    frame::FrameFormat,
-    FdCan, ReceiveErrorOverflow,
+    config::{
+        DataBitTiming, FrameTransmissionConfig, Interrupt, InterruptLine,
+        NominalBitTiming, TxBufferMode,
+    },
+    id::StandardId,
+    Activity, BusMonitoringMode, BusOffStatus, ConfigMode, ErrorCounters,
+    ExternalLoopbackMode, FdCan, InternalLoopbackMode, LastErrorCode,
+    NormalOperationMode, ProtocolStatus, Receive, ReceiveErrorOverflow,
+    Transmit,
 };
-use fdcan::{frame::TxFrameHeader, NormalOperationMode};
+use rtic_monotonics::Monotonic;
 use usbd_gscan::{
     host::{
         CanBitTimingConst, CanState, DeviceBitTiming, DeviceBitTimingConst,
@@ -22,6 +45,10 @@ use usbd_gscan::{
     Device,
 };
 
+/// Interfaces the firmware drives: FDCAN2, FDCAN3 and, on SKUs that route
+/// it, FDCAN1.
+pub const INTERFACES: usize = 3;
+
 const TIMING_NOMINAL: CanBitTimingConst = CanBitTimingConst {
     tseg1_min: 1,
     tseg1_max: 255,
@@ -43,250 +70,2677 @@ const TIMING_DATA: CanBitTimingConst = CanBitTimingConst {
     brp_inc: 1,
 };
 
-pub struct UsbCanDevice {
-    /// CAN peripheral clock. Used by the host for bit timing calculations.
+/// Fewest time quanta per data bit considered usable. Fewer leave too little
+/// resolution to place the sample point or absorb phase errors.
+const MIN_PRACTICAL_QUANTA: u32 = 8;
+
+/// Sample point of the default nominal bit timing, in per mille.
+const DEFAULT_NOMINAL_SAMPLE_POINT: u32 = 875;
+/// Sample point of the default data bit timing, in per mille.
+const DEFAULT_DATA_SAMPLE_POINT: u32 = 750;
+
+/// Bit timing as programmed into the controller registers.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct ProgrammedTiming {
+    /// Nominal bit timing and prescaler register (`FDCAN_NBTP`).
+    pub nbtp: u32,
+    /// Data bit timing and prescaler register (`FDCAN_DBTP`).
+    pub dbtp: u32,
+}
+
+impl ProgrammedTiming {
+    /// Nominal bit rate in bit/s and sample point in per mille.
+    pub fn nominal(&self, clock: Hertz) -> (u32, u32) {
+        let prescaler = ((self.nbtp >> 16) & 0x1ff) + 1;
+        let seg1 = ((self.nbtp >> 8) & 0xff) + 1;
+        let seg2 = (self.nbtp & 0x7f) + 1;
+        rate_and_sample_point(clock, prescaler, seg1, seg2)
+    }
+
+    /// Data bit rate in bit/s and sample point in per mille.
+    pub fn data(&self, clock: Hertz) -> (u32, u32) {
+        let prescaler = ((self.dbtp >> 16) & 0x1f) + 1;
+        let seg1 = ((self.dbtp >> 8) & 0x1f) + 1;
+        let seg2 = ((self.dbtp >> 4) & 0xf) + 1;
+        rate_and_sample_point(clock, prescaler, seg1, seg2)
+    }
+
+    /// Nominal bit timing in the form the host sends it.
+    pub fn nominal_bit_timing(&self) -> DeviceBitTiming {
+        DeviceBitTiming {
+            prop_seg: 0,
+            phase_seg1: ((self.nbtp >> 8) & 0xff) + 1,
+            phase_seg2: (self.nbtp & 0x7f) + 1,
+            sjw: ((self.nbtp >> 25) & 0x7f) + 1,
+            brp: ((self.nbtp >> 16) & 0x1ff) + 1,
+        }
+    }
+
+    /// Data bit timing in the form the host sends it.
+    pub fn data_bit_timing(&self) -> DeviceBitTiming {
+        DeviceBitTiming {
+            prop_seg: 0,
+            phase_seg1: ((self.dbtp >> 8) & 0x1f) + 1,
+            phase_seg2: ((self.dbtp >> 4) & 0xf) + 1,
+            sjw: (self.dbtp & 0xf) + 1,
+            brp: ((self.dbtp >> 16) & 0x1f) + 1,
+        }
+    }
+}
+
+/// Bit rate and sample point (per mille) from time quanta.
+fn rate_and_sample_point(
     clock: Hertz,
-    /// CAN interface labeled "CAN1" on PCB.
-    pub can1: Option<FdCan<Can<FDCAN2>, NormalOperationMode>>,
-    /// CAN interface labeled "CAN2" on PCB.
-    pub can2: Option<FdCan<Can<FDCAN3>, NormalOperationMode>>,
+    prescaler: u32,
+    seg1: u32,
+    seg2: u32,
+) -> (u32, u32) {
+    // one time quantum for the synchronisation segment.
+    let quanta = 1 + seg1 + seg2;
+    let rate = clock.to_Hz() / (prescaler * quanta);
+    let sample_point = (1 + seg1) * 1000 / quanta;
+    (rate, sample_point)
 }
 
-impl UsbCanDevice {
-    pub fn new(
-        clock: Hertz,
-        can1: FdCan<Can<FDCAN2>, NormalOperationMode>,
-        can2: FdCan<Can<FDCAN3>, NormalOperationMode>,
-    ) -> Self {
+/// Bit timing from the host that was rejected, leaving the previous timing
+/// in place.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct RejectedTiming {
+    /// Data rather than nominal bit timing.
+    pub data: bool,
+    /// Bit rate the timing asked for in bit/s, or zero if it can't be
+    /// worked out.
+    pub requested: u32,
+    /// Closest bit rate the timing limits allow in bit/s.
+    pub nearest: u32,
+}
+
+impl RejectedTiming {
+    fn new(clock: Hertz, timing: &DeviceBitTiming, data: bool) -> Self {
+        let limits = match data {
+            true => &TIMING_DATA,
+            false => &TIMING_NOMINAL,
+        };
+        let requested = requested_rate(clock.to_Hz(), timing);
+
         Self {
-            clock,
-            can1: Some(can1),
-            can2: Some(can2),
+            data,
+            requested,
+            nearest: nearest_rate(clock.to_Hz(), requested, limits),
         }
     }
 }
 
-impl Device for UsbCanDevice {
-    fn config(&self) -> DeviceConfig {
-        DeviceConfig::new(2)
+/// Why an interface last went down, or stopped transmitting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum StopReason {
+    /// Hasn't gone down since boot.
+    #[default]
+    None = 0,
+    /// Stopped by the host, including on USB reset.
+    Host = 1,
+    /// Went bus-off. Recovery is attempted automatically.
+    BusOff = 2,
+    /// Entered restricted operation and stopped transmitting.
+    Restricted = 3,
+    /// Left off the bus after repeated failed bus-off recoveries.
+    Fault = 4,
+    /// Taken off the bus ahead of a device reset requested by the host.
+    Reset = 5,
+    /// Taken off the bus as its transceiver was put in standby.
+    Standby = 6,
+}
+
+/// Automatic retransmission setting of an interface.
+///
+/// Takes precedence over the gs_usb one shot feature unless left at
+/// [`Retransmit::Feature`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Retransmit {
+    /// Disabled only while the host has the interface started with the one
+    /// shot feature.
+    #[default]
+    Feature = 0,
+    /// Always disabled.
+    Disabled = 1,
+    /// Always enabled, even if the host asks for one shot.
+    Enabled = 2,
+}
+
+impl Retransmit {
+    /// Whether frames are retransmitted with the interface started with
+    /// `features`.
+    fn enabled(self, features: Feature) -> bool {
+        match self {
+            Retransmit::Feature => !features.intersects(Feature::ONE_SHOT),
+            Retransmit::Disabled => false,
+            Retransmit::Enabled => true,
+        }
     }
+}
 
-    fn bit_timing(&self) -> DeviceBitTimingConst {
-        DeviceBitTimingConst {
-            features: Feature::FD | Feature::BT_CONST_EXT | Feature::ONE_SHOT,
-            fclk_can: self.clock.to_Hz(),
-            timing: TIMING_NOMINAL,
+impl TryFrom<u16> for Retransmit {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Feature as u16 => Ok(Self::Feature),
+            x if x == Self::Disabled as u16 => Ok(Self::Disabled),
+            x if x == Self::Enabled as u16 => Ok(Self::Enabled),
+            _ => Err(value),
         }
     }
+}
 
-    fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended {
-        DeviceBitTimingConstExtended {
-            features: Feature::FD | Feature::BT_CONST_EXT | Feature::ONE_SHOT,
-            fclk_can: self.clock.to_Hz(),
-            timing_nominal: TIMING_NOMINAL,
-            timing_data: TIMING_DATA,
+/// Format of a frame from its FD and remote flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameKind {
+    Classic,
+    Fd,
+    Remote,
+    /// FD has no remote frames, so a frame flagged as both is malformed.
+    Invalid,
+}
+
+impl FrameKind {
+    pub fn classify(fd: bool, remote: bool) -> Self {
+        match (fd, remote) {
+            (false, false) => Self::Classic,
+            (true, false) => Self::Fd,
+            (false, true) => Self::Remote,
+            (true, true) => Self::Invalid,
         }
     }
+}
 
-    fn configure_bit_timing(&mut self, interface: u8, timing: DeviceBitTiming) {
-        let seg1 = timing.prop_seg + timing.phase_seg1;
+/// Per-interface diagnostic counters.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct Counters {
+    /// Received frames dropped because their identifier was out of range.
+    pub invalid_id_rx: u32,
+    /// Frames from the host dropped because their identifier was out of
+    /// range.
+    pub invalid_id_tx: u32,
+    /// Last identifier dropped as out of range, with bit 31 set if it was
+    /// extended. Zero if none has been.
+    pub last_invalid_id: u32,
+    /// RX FIFO 0 overruns.
+    pub overrun_fifo0: u32,
+    /// RX FIFO 1 overruns.
+    pub overrun_fifo1: u32,
+    /// Frames dropped because the software receive queue was full.
+    pub overrun_queue: u32,
+    /// Most frames seen waiting in RX FIFO 0 and 1 by the receive
+    /// interrupts.
+    pub peak_fifo: [u8; 2],
+    /// Receive interrupts that found the controller taken for
+    /// reconfiguration. Its frames are left in the FIFO and forwarded once
+    /// reconfiguration finishes.
+    pub rx_deferred: u32,
+    /// Frames dropped because the transmit queue was full.
+    pub tx_dropped: u32,
+    /// Times the interface was found bus-off.
+    pub bus_off: u32,
+    /// Frames accepted by a hardware filter.
+    pub filter_matched: u32,
+    /// Frames accepted without matching a hardware filter.
+    pub filter_unmatched: u32,
+    /// Filter that accepted the most recent frame, if any.
+    pub last_filter: Option<u8>,
+    /// Times the controller entered restricted operation.
+    pub restricted: u32,
+    /// Error samples where the last protocol error was a missing
+    /// acknowledgement.
+    pub ack_errors: u32,
+    /// Bit timing from the host rejected as out of range.
+    pub timing_rejected: u32,
+    /// FD frames dropped because the interface was started without FD, and
+    /// remote frames flagged as FD, received or from the host.
+    pub fd_dropped: u32,
+    /// Received frames whose length their format can't encode, clamped to
+    /// the maximum or rounded up to the next FD length.
+    pub bad_length: u32,
+    /// Length of the last such frame as reported by the controller.
+    pub last_bad_length: u8,
+    /// Times the interface was found in error warning, either counter at
+    /// 96 or more.
+    pub error_warning: u32,
+    /// Times the interface was found error passive, either counter at 128
+    /// or more.
+    pub error_passive: u32,
+    /// Times the bus was found stuck dominant.
+    pub stuck_dominant: u32,
+    /// Frames received, wrapping. Frames dropped for an invalid identifier
+    /// or as remote FD frames aren't counted.
+    pub rx_frames: u32,
+    /// Frames handed to the hardware for transmission, wrapping.
+    pub tx_frames: u32,
+    /// Payload bytes of received data frames, wrapping.
+    pub rx_bytes: u32,
+    /// Payload bytes handed to the hardware for transmission, wrapping.
+    pub tx_bytes: u32,
+}
+
+impl Counters {
+    /// Count a frame dropped because its identifier was out of range.
+    pub fn reject_id(&mut self, received: bool, raw: u32) {
+        match received {
+            true => self.invalid_id_rx += 1,
+            false => self.invalid_id_tx += 1,
+        }
+        self.last_invalid_id = raw;
+    }
+}
+
+/// Fault confinement state of an interface, ordered from best to worst.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format,
+)]
+pub enum ErrorState {
+    #[default]
+    Active = 0,
+    Warning = 1,
+    Passive = 2,
+    BusOff = 3,
+}
+
+impl ErrorState {
+    /// Error counter level at which the controller warns.
+    const WARNING_LEVEL: u8 = 96;
+    /// Error counter level at which the controller turns error passive.
+    const PASSIVE_LEVEL: u8 = 128;
 
-        let btr = NominalBitTiming {
-            prescaler: NonZeroU16::new(timing.brp as u16).unwrap(),
-            seg1: NonZeroU8::new(seg1 as u8).unwrap(),
-            seg2: NonZeroU8::new(timing.phase_seg2 as u8).unwrap(),
-            sync_jump_width: NonZeroU8::new(timing.sjw as u8).unwrap(),
+    /// State given by the error counters.
+    fn read(counters: &ErrorCounters, bus_off: bool) -> Self {
+        let (rx_errors, rx_passive) = match counters.receive_err {
+            ReceiveErrorOverflow::Normal(count) => (count, false),
+            ReceiveErrorOverflow::Overflow(count) => (count, true),
         };
+        let worst = counters.transmit_err.max(rx_errors);
 
-        match interface {
-            0 => {
-                if let Some(can) = self.can1.take() {
-                    let mut config = can.into_config_mode();
-                    config.set_nominal_bit_timing(btr);
-                    self.can1.replace(config.into_normal());
-                }
+        if bus_off {
+            Self::BusOff
+        } else if rx_passive || worst >= Self::PASSIVE_LEVEL {
+            Self::Passive
+        } else if worst >= Self::WARNING_LEVEL {
+            Self::Warning
+        } else {
+            Self::Active
+        }
+    }
+}
+
+impl From<ErrorState> for CanState {
+    fn from(state: ErrorState) -> Self {
+        match state {
+            ErrorState::Active => CanState::Active,
+            ErrorState::Warning => CanState::Warning,
+            ErrorState::Passive => CanState::Passive,
+            ErrorState::BusOff => CanState::BusOff,
+        }
+    }
+}
+
+/// Counts since an interface was last started, giving bench tests a clean
+/// baseline per session.
+#[derive(Debug, Default, Clone, Copy, defmt::Format)]
+pub struct Session {
+    /// Frames received, not counting dropped malformed frames.
+    pub rx_frames: u32,
+    /// Frames handed to the hardware for transmission.
+    pub tx_frames: u32,
+    /// Protocol errors that incremented the transmit or receive error
+    /// counter.
+    pub errors: u32,
+    /// RX FIFO overruns.
+    pub overruns: u32,
+}
+
+/// Transmitter delay compensation for the data phase.
+///
+/// At high data bit rates the transceiver loop delay exceeds a bit time, so
+/// the transmitter checks its own bits at a secondary sample point measured
+/// from the received edge instead. The best values depend on the transceiver
+/// and cable length. Both are in minimum time quanta (CAN clock periods).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Tdc {
+    /// Secondary sample point offset from the measured delay (`TDCO`).
+    pub offset: u8,
+    /// Minimum delay accepted, filtering out dominant glitches that would
+    /// otherwise end the delay measurement early (`TDCF`).
+    pub filter: u8,
+}
+
+impl Tdc {
+    /// Largest offset or filter the controller accepts.
+    const MAX: u8 = 127;
+
+    /// `FDCAN_TDCR` register value, rejecting values out of range.
+    fn tdcr(&self) -> Result<u32, Error> {
+        if self.offset > Self::MAX || self.filter > Self::MAX {
+            return Err(Error::BitTiming);
+        }
+
+        Ok((self.offset as u32) << 8 | self.filter as u32)
+    }
+}
+
+/// Transmitter delay compensation enable bit of `FDCAN_DBTP`.
+const DBTP_TDC: u32 = 1 << 23;
+/// Initialization bit of `FDCAN_CCCR`. Set by the controller when it goes
+/// bus-off.
+const CCCR_INIT: u32 = 1 << 0;
+/// Restricted operation mode bit of `FDCAN_CCCR`. Set by the controller
+/// when it can't read a frame from message RAM in time to transmit it.
+const CCCR_ASM: u32 = 1 << 2;
+/// Disable automatic retransmission bit of `FDCAN_CCCR`.
+const CCCR_DAR: u32 = 1 << 6;
+/// FD operation enable bit of `FDCAN_CCCR`.
+const CCCR_FDOE: u32 = 1 << 8;
+/// Bit rate switching enable bit of `FDCAN_CCCR`.
+const CCCR_BRSE: u32 = 1 << 9;
+/// Extended identifier flag of a raw identifier in the diagnostics.
+pub const ID_EXTENDED: u32 = 1 << 31;
+/// Protocol exception handling disable bit of `FDCAN_CCCR`.
+const CCCR_PXHD: u32 = 1 << 12;
+/// Transmit queue mode bit of `FDCAN_TXBC`, clear in FIFO mode.
+const TXBC_TFQM: u32 = 1 << 24;
+
+/// Consecutive failed bus-off recoveries before an interface is marked as
+/// faulted.
+const MAX_FAILED_RECOVERIES: u8 = 5;
+/// Default bus-off restart delay in milliseconds.
+const DEFAULT_RESTART_MS: u16 = 100;
+/// Consecutive error samples with an acknowledgement error before missing
+/// bus termination is suspected.
+const ACK_ERROR_HINT: u8 = 10;
+/// Consecutive error samples of a started controller still synchronising
+/// before the bus is considered stuck dominant.
+const STUCK_DOMINANT_HINT: u8 = 10;
+
+/// Payload of the frame sent by the self-tests.
+const SELF_TEST_DATA: [u8; 8] =
+    [0x55, 0xaa, 0x55, 0xaa, 0x00, 0xff, 0x00, 0xff];
+/// All transmit buffers in `FDCAN_TXBCR`.
+const TXBCR_ALL: u32 = 0x7;
+
+/// Bus activity samples taken at boot, one per millisecond.
+const LOADED_BUS_SAMPLES: u32 = 10;
+/// Samples that must find the bus busy for it to be treated as loaded.
+const LOADED_BUS_THRESHOLD: u32 = 8;
+/// Polls, 100 µs apart, for the power-on self-test frame to be sent.
+const POST_POLLS: u32 = 100;
+
+/// FDCAN peripheral in one of the operating modes used by the adapter.
+pub enum Mode<I: fdcan::Instance> {
+    /// Initialization and configuration mode (`CCCR.INIT` and `CCCR.CCE`
+    /// set). The controller is off the bus: it doesn't receive, transmit or
+    /// acknowledge frames.
+    Config(FdCan<I, ConfigMode>),
+    /// Bus monitoring mode (`CCCR.MON` set). The controller receives
+    /// frames but never drives the bus, so it doesn't acknowledge frames or
+    /// send error frames.
+    Monitoring(FdCan<I, BusMonitoringMode>),
+    /// Normal operation. The controller takes part in bus communication.
+    Normal(FdCan<I, NormalOperationMode>),
+    /// Internal loopback mode (`TEST.LBCK` and `CCCR.MON` set). Transmitted
+    /// frames are received back by the controller without driving the bus.
+    Loopback(FdCan<I, InternalLoopbackMode>),
+    /// Bus monitoring mode started by the host for listen-only operation.
+    /// Received frames are forwarded to the host as in normal operation.
+    ListenOnly(FdCan<I, BusMonitoringMode>),
+    /// External loopback mode (`TEST.LBCK` set). Transmitted frames drive
+    /// the bus through the transceiver and are received back from it, with
+    /// missing acknowledgements ignored. Only used by the self-test.
+    ExternalLoopback(FdCan<I, ExternalLoopbackMode>),
+}
+
+/// Evaluate an expression with the peripheral in whichever mode it's in.
+macro_rules! any_mode {
+    ($mode:expr, $can:ident => $body:expr) => {
+        match $mode {
+            Mode::Config($can) => $body,
+            Mode::Monitoring($can) => $body,
+            Mode::Normal($can) => $body,
+            Mode::Loopback($can) => $body,
+            Mode::ListenOnly($can) => $body,
+            Mode::ExternalLoopback($can) => $body,
+        }
+    };
+}
+
+impl<I: fdcan::Instance> Mode<I> {
+    /// Enter configuration mode, leaving the bus.
+    pub fn into_config(self) -> FdCan<I, ConfigMode> {
+        match self {
+            Mode::Config(can) => can,
+            Mode::Monitoring(can) => can.into_config_mode(),
+            Mode::Normal(can) => can.into_config_mode(),
+            Mode::Loopback(can) => can.into_config_mode(),
+            Mode::ListenOnly(can) => can.into_config_mode(),
+            Mode::ExternalLoopback(can) => can.into_config_mode(),
+        }
+    }
+
+    /// Check if the controller has been started by the host.
+    pub fn is_started(&self) -> bool {
+        matches!(
+            self,
+            Mode::Normal(_) | Mode::Loopback(_) | Mode::ListenOnly(_)
+        )
+    }
+
+    pub fn error_counters(&self) -> ErrorCounters {
+        any_mode!(self, can => can.error_counters())
+    }
+
+    pub fn protocol_status(&self) -> ProtocolStatus {
+        any_mode!(self, can => can.protocol_status())
+    }
+}
+
+/// Change configuration, returning the controller to the mode it was in.
+fn reconfigure<I: fdcan::Instance>(
+    can: &mut Option<Mode<I>>,
+    f: impl FnOnce(&mut FdCan<I, ConfigMode>),
+) {
+    if let Some(mode) = can.take() {
+        let restore: fn(FdCan<I, ConfigMode>) -> Mode<I> = match mode {
+            Mode::Config(_) => Mode::Config,
+            Mode::Monitoring(_) => {
+                |c| Mode::Monitoring(c.into_bus_monitoring())
             }
-            1 => {
-                if let Some(can) = self.can2.take() {
-                    let mut config = can.into_config_mode();
-                    config.set_nominal_bit_timing(btr);
-                    self.can2.replace(config.into_normal());
-                }
+            Mode::Normal(_) => |c| Mode::Normal(c.into_normal()),
+            Mode::Loopback(_) => |c| Mode::Loopback(c.into_internal_loopback()),
+            Mode::ListenOnly(_) => {
+                |c| Mode::ListenOnly(c.into_bus_monitoring())
             }
-            _ => {
-                defmt::error!("Interface number {} not in use", interface);
+            Mode::ExternalLoopback(_) => {
+                |c| Mode::ExternalLoopback(c.into_external_loopback())
             }
+        };
+        let mut config = mode.into_config();
+        f(&mut config);
+        let mode = restore(config);
+        let receiving = mode.is_started();
+        can.replace(mode);
+
+        // Frames may have been left in the RX FIFOs while the controller
+        // was out of reach, with their new message interrupt already taken.
+        // Service them now rather than when the next frame arrives.
+        if receiving {
+            rtic::pend(crate::hal::stm32::Interrupt::FDCAN2_INTR0);
         }
     }
+}
 
-    fn configure_bit_timing_data(
+/// Bus presence check progress of an interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// No check has completed. Also the result if the host started or
+    /// stopped the interface during the check.
+    #[default]
+    Unknown,
+    /// Listening in bus monitoring mode until `until`, then returning to
+    /// configuration mode, or bus monitoring if `monitoring` is set.
+    Checking { until: Instant, monitoring: bool },
+    /// No valid frame was received.
+    Absent,
+    /// At least one valid frame was received.
+    Present,
+}
+
+/// Bus-off restart delay in milliseconds, encoded like SocketCAN's
+/// `restart-ms`: zero leaves recovery to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartMs(pub u16);
+
+impl Default for RestartMs {
+    fn default() -> Self {
+        Self(DEFAULT_RESTART_MS)
+    }
+}
+
+/// External loopback self-test progress of an interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTest {
+    /// No test has completed. Also the result if the host started or
+    /// stopped the interface during the test.
+    #[default]
+    Unknown,
+    /// Waiting until `until` for the test frame to be sent, then returning
+    /// to configuration mode, or bus monitoring if `monitoring` is set.
+    Running { until: Instant, monitoring: bool },
+    /// The test frame wasn't sent.
+    Failed,
+    /// The test frame was sent and read back through the transceiver.
+    Passed,
+}
+
+/// Software state kept for each interface.
+#[derive(Default)]
+pub struct Interface {
+    /// Diagnostic counters.
+    pub counters: Counters,
+    /// Software transmit queue.
+    pub tx: TxQueue,
+    /// Received frames waiting to be forwarded to the host.
+    pub rx: RxQueue,
+    /// Consecutive bus-off recoveries that didn't get the interface back on
+    /// the bus.
+    failed_recoveries: u8,
+    /// Consecutive error samples with an acknowledgement error.
+    ack_streak: u8,
+    /// Consecutive error samples still synchronising to the bus.
+    stuck_streak: u8,
+    /// The bus hasn't gone recessive for long enough to synchronise to
+    /// since the interface was started, see [`STUCK_DOMINANT_HINT`].
+    pub stuck_dominant: bool,
+    /// Recovery has been given up on after repeated failures. The interface
+    /// stays bus-off until the host restarts it.
+    pub faulted: bool,
+    /// Transmit test pattern.
+    pub pattern: Pattern,
+    /// Don't forward frames received in loopback mode to the host.
+    pub suppress_loopback: bool,
+    /// Bus presence check.
+    pub presence: Presence,
+    /// External loopback self-test.
+    pub self_test: SelfTest,
+    /// Features the interface was started with, `None` while stopped.
+    pub features: Option<Feature>,
+    /// Rate limit for frames forwarded to the host.
+    pub host_limit: RateLimit,
+    /// Counts since the interface was last started.
+    pub session: Session,
+    /// Transmitter delay compensation, `None` if disabled.
+    pub tdc: Option<Tdc>,
+    /// The controller is in restricted operation. It receives and
+    /// acknowledges frames but doesn't transmit until cleared.
+    pub restricted: bool,
+    /// Timestamp of the last received frame extended to 32 bits, see
+    /// [`crate::timestamp`].
+    pub rx_timestamp: u32,
+    /// Frames recently handed to the controller.
+    pub history: TxHistory,
+    /// Why the interface last went down and when, in milliseconds since
+    /// boot. Kept across restarts.
+    pub last_stop: (StopReason, u32),
+    /// Markers of sent frames not yet read by the host.
+    pub completions: Completions,
+    /// gs_usb frames waiting to be echoed to the host.
+    pub echoes: Echoes,
+    /// USB to CAN latency measurement.
+    pub latency: LatencyTest,
+    /// Automatic retransmission, kept across restarts.
+    pub retransmit: Retransmit,
+    /// How long to wait in bus-off before recovering. Kept across restarts.
+    pub restart_ms: RestartMs,
+    /// When the current bus-off was first seen.
+    bus_off_at: Option<Instant>,
+    /// Start requested by the host during the startup delay.
+    pub deferred_start: Option<Feature>,
+    /// Fault confinement state as of the last error sample.
+    pub error_state: ErrorState,
+    /// Last bit timing from the host that was rejected.
+    pub rejected_timing: Option<RejectedTiming>,
+}
+
+impl Interface {
+    /// Record why the interface went down.
+    fn went_down(&mut self, reason: StopReason) {
+        let now = Mono::now().duration_since_epoch().to_millis() as u32;
+        self.last_stop = (reason, now);
+    }
+
+    /// Count and refuse an FD frame if the interface was started without
+    /// FD, as the controller would send it in classic format, truncated.
+    fn refuse_fd(&mut self, frame: &TxFrame) -> bool {
+        let classic = self.features.is_some_and(|f| !f.intersects(Feature::FD));
+        let refused = classic && frame.is_fd();
+        if refused {
+            self.counters.fd_dropped += 1;
+        }
+        refused
+    }
+
+    /// Refuse a frame if the interface was started listen-only, as the
+    /// controller can't transmit.
+    fn refuse_listen_only(&self) -> bool {
+        self.features.is_some_and(|f| {
+            f.intersects(Feature::LISTEN_ONLY)
+                && !f.intersects(Feature::LOOP_BACK)
+        })
+    }
+
+    /// Queue a frame for transmission.
+    ///
+    /// Returns `false` if the queue was full and its oldest frame was
+    /// dropped to make room. The drop is counted, and the dropped frame is
+    /// echoed if it came from gs_usb.
+    fn push_tx(&mut self, frame: TxFrame, interface: u8) -> bool {
+        let Some(dropped) = self.tx.push(frame) else {
+            return true;
+        };
+
+        defmt::warn!("Interface {} TX queue full", interface);
+        self.counters.tx_dropped += 1;
+        if let Some(marker) = dropped.header.marker {
+            self.echoes.abandon(marker);
+        }
+
+        false
+    }
+
+    /// Clear fault tracking so recovery is attempted again.
+    pub fn clear_fault(&mut self) {
+        self.failed_recoveries = 0;
+        self.faulted = false;
+    }
+
+    /// Bring the interface onto the bus.
+    ///
+    /// The controller is left in normal operation, or internal loopback if
+    /// requested, with both interrupt lines enabled. Frames queued before
+    /// the interface was started are discarded.
+    fn start<I: fdcan::Instance>(
         &mut self,
-        interface: u8,
-        timing: DeviceBitTiming,
+        can: &mut Option<Mode<I>>,
+        features: Feature,
     ) {
-        let seg1 = timing.prop_seg + timing.phase_seg1;
+        self.clear_fault();
+        self.tx.clear();
+        self.latency.abandon();
+        self.rx.clear();
+        self.completions.clear();
+        self.echoes.clear();
+        self.echoes.timestamps = features.intersects(Feature::HW_TIMESTAMP);
+        self.pattern.stop();
+        self.features = Some(features);
+        self.session = Session::default();
+        self.bus_off_at = None;
+        self.error_state = ErrorState::Active;
+
+        if let Some(mode) = can.take() {
+            let mut can_config = mode.into_config();
+            can_config
+                .set_automatic_retransmit(self.retransmit.enabled(features));
+            can_config.set_frame_transmit(
+                match features.intersects(Feature::FD) {
+                    true => FrameTransmissionConfig::AllowFdCanAndBRS,
+                    false => FrameTransmissionConfig::ClassicCanOnly,
+                },
+            );
+            can_config.enable_interrupt_line(InterruptLine::_0, true);
+            can_config.enable_interrupt_line(InterruptLine::_1, true);
+            can.replace(
+                match (
+                    features.intersects(Feature::LOOP_BACK),
+                    features.intersects(Feature::LISTEN_ONLY),
+                ) {
+                    // internal loopback doesn't drive the bus either.
+                    (true, _) => {
+                        Mode::Loopback(can_config.into_internal_loopback())
+                    }
+                    (false, true) => {
+                        Mode::ListenOnly(can_config.into_bus_monitoring())
+                    }
+                    (false, false) => Mode::Normal(can_config.into_normal()),
+                },
+            );
+        }
+    }
+
+    /// Take the interface off the bus.
+    ///
+    /// The controller is left in configuration mode with both interrupt
+    /// lines disabled and the transmit queue is flushed. Frames already in
+    /// the RX FIFOs are discarded when the interface is next started.
+    fn stop<I: fdcan::Instance>(&mut self, can: &mut Option<Mode<I>>) {
+        self.tx.clear();
+        self.echoes.clear();
+        self.latency.abandon();
+        self.pattern.stop();
+        self.features = None;
+        self.deferred_start = None;
+        self.stuck_streak = 0;
+        self.stuck_dominant = false;
+
+        if let Some(mode) = can.take() {
+            let mut can_config = mode.into_config();
+            can_config.enable_interrupt_line(InterruptLine::_0, false);
+            can_config.enable_interrupt_line(InterruptLine::_1, false);
+            can.replace(Mode::Config(can_config));
+        }
+    }
+
+    /// Top up the transmit queue from the test pattern and move queued
+    /// frames into the hardware transmit buffers.
+    fn transmit<I: fdcan::Instance, M: Transmit>(
+        &mut self,
+        can: &mut FdCan<I, M>,
+        interface: u8,
+    ) -> Option<Instant> {
+        if !self.faulted {
+            self.pattern.refill(&mut self.tx);
+        }
+
+        self.completions.collect(
+            interface,
+            &mut self.latency,
+            &mut self.echoes,
+        );
+
+        let sent = self.tx.sent;
+        let sent_bytes = self.tx.sent_bytes;
+        let next =
+            self.tx
+                .drain(can, interface, &mut self.history, &mut self.echoes);
+        let frames = self.tx.sent.wrapping_sub(sent);
+        let bytes = self.tx.sent_bytes.wrapping_sub(sent_bytes);
+        self.session.tx_frames += frames;
+        self.counters.tx_frames = self.counters.tx_frames.wrapping_add(frames);
+        self.counters.tx_bytes = self.counters.tx_bytes.wrapping_add(bytes);
+
+        next
+    }
 
-        let btr = DataBitTiming {
-            transceiver_delay_compensation: false,
-            prescaler: NonZeroU8::new(timing.brp as u8).unwrap(),
-            seg1: NonZeroU8::new(seg1 as u8).unwrap(),
-            seg2: NonZeroU8::new(timing.phase_seg2 as u8).unwrap(),
-            sync_jump_width: NonZeroU8::new(timing.sjw as u8).unwrap(),
+    /// Add protocol errors logged by the controller to the session.
+    ///
+    /// The controller's error log counter is cleared on read and saturates
+    /// at 255, so it's sampled regularly. Errors logged just before the
+    /// host reads the interface state are missed.
+    ///
+    /// The last error code is sampled too, to count acknowledgement errors.
+    /// Nothing acknowledging frames usually means the bus isn't terminated,
+    /// so a hint is logged when they persist.
+    ///
+    /// A started controller that is still synchronising hasn't seen the 11
+    /// recessive bits that end every frame, so a bus that stays that way is
+    /// stuck dominant, usually CANH shorted to CANL or a faulty node.
+    fn sample_errors<I: fdcan::Instance>(
+        &mut self,
+        can: &Option<Mode<I>>,
+        interface: u8,
+    ) {
+        let Some(mode) = can else {
+            return;
         };
 
-        match interface {
-            0 => {
-                if let Some(can) = self.can1.take() {
-                    let mut config = can.into_config_mode();
-                    config.set_data_bit_timing(btr);
-                    self.can1.replace(config.into_normal());
-                }
-            }
-            1 => {
-                if let Some(can) = self.can2.take() {
-                    let mut config = can.into_config_mode();
-                    config.set_data_bit_timing(btr);
-                    self.can2.replace(config.into_normal());
-                }
-            }
-            _ => {
-                defmt::error!("Interface number {} not in use", interface);
-            }
+        let counters = mode.error_counters();
+        let status = mode.protocol_status();
+        self.session.errors += counters.can_errors as u32;
+
+        let bus_off = status.bus_off_status == BusOffStatus::BusOffState;
+        self.track_error_state(ErrorState::read(&counters, bus_off), interface);
+        self.track_stuck_dominant(
+            mode.is_started()
+                && matches!(status.activity, Activity::Synchronizing),
+            interface,
+        );
+
+        if status.last_error != LastErrorCode::AckError {
+            self.ack_streak = 0;
+            return;
+        }
+
+        self.counters.ack_errors += 1;
+        self.ack_streak = self.ack_streak.saturating_add(1);
+        if self.ack_streak == ACK_ERROR_HINT {
+            defmt::warn!(
+                "Interface {} frames not acknowledged, check termination",
+                interface
+            );
+            event::record(
+                event::Kind::AckErrors,
+                interface,
+                ACK_ERROR_HINT as u16,
+            );
         }
     }
 
-    fn reset(&mut self, interface: u8) {
-        match interface {
-            0 => {
-                if let Some(mut can) = self.can1.take() {
-                    can.enable_interrupt_line(InterruptLine::_0, false);
-                    can.enable_interrupt_line(InterruptLine::_1, false);
-                    self.can1.replace(can);
-                }
-            }
-            1 => {
-                if let Some(mut can) = self.can2.take() {
-                    can.enable_interrupt_line(InterruptLine::_0, false);
-                    can.enable_interrupt_line(InterruptLine::_1, false);
-                    self.can2.replace(can);
-                }
-            }
-            _ => defmt::error!("Interface {} not in use", interface),
+    /// Count and log changes of fault confinement state.
+    ///
+    /// Bus-off is counted and logged by recovery, so only entering error
+    /// warning and error passive are counted here.
+    fn track_error_state(&mut self, state: ErrorState, interface: u8) {
+        let last = core::mem::replace(&mut self.error_state, state);
+        if state == last {
+            return;
+        }
+
+        defmt::info!("Interface {} {} -> {}", interface, last, state);
+
+        match state {
+            ErrorState::Warning => self.counters.error_warning += 1,
+            ErrorState::Passive => self.counters.error_passive += 1,
+            _ => {}
+        }
+        if state > last {
+            event::record(event::Kind::ErrorState, interface, state as u16);
         }
     }
 
-    fn start(&mut self, interface: u8, features: Feature) {
-        match interface {
-            0 => {
-                if let Some(can) = self.can1.take() {
-                    let mut can = can.into_config_mode();
-                    can.set_automatic_retransmit(
-                        !features.intersects(Feature::ONE_SHOT),
-                    );
-                    can.enable_interrupt_line(InterruptLine::_0, true);
-                    can.enable_interrupt_line(InterruptLine::_1, true);
-                    self.can1.replace(can.into_normal());
-                }
-            }
-            1 => {
-                if let Some(can) = self.can2.take() {
-                    let mut can = can.into_config_mode();
-                    can.set_automatic_retransmit(
-                        !features.intersects(Feature::ONE_SHOT),
-                    );
-                    can.enable_interrupt_line(InterruptLine::_0, true);
-                    can.enable_interrupt_line(InterruptLine::_1, true);
-                    self.can2.replace(can.into_normal());
-                }
+    /// Count and log the bus becoming stuck dominant and released again.
+    fn track_stuck_dominant(&mut self, synchronising: bool, interface: u8) {
+        if !synchronising {
+            if self.stuck_dominant {
+                defmt::info!("Interface {} bus released", interface);
             }
-            _ => defmt::error!("Interface {} not in use", interface),
+            self.stuck_streak = 0;
+            self.stuck_dominant = false;
+            return;
+        }
+
+        self.stuck_streak = self.stuck_streak.saturating_add(1);
+        if self.stuck_streak == STUCK_DOMINANT_HINT {
+            defmt::warn!(
+                "Interface {} bus stuck dominant, check for a short",
+                interface
+            );
+            self.stuck_dominant = true;
+            self.counters.stuck_dominant += 1;
+            event::record(
+                event::Kind::StuckDominant,
+                interface,
+                STUCK_DOMINANT_HINT as u16,
+            );
         }
     }
 
-    fn state(&self, interface: u8) -> usbd_gscan::host::DeviceState {
-        defmt::info!("Interface number: {}", interface);
+    /// Start listening for valid frames without taking part in the bus.
+    ///
+    /// Returns `false` if the interface is started, as that would mean
+    /// taking it off the bus.
+    fn check_presence<I: fdcan::Instance>(
+        &mut self,
+        can: &mut Option<Mode<I>>,
+        until: Instant,
+    ) -> bool {
+        if can.as_ref().map_or(true, Mode::is_started)
+            || matches!(self.self_test, SelfTest::Running { .. })
+        {
+            return false;
+        }
 
-        let counters = match interface {
-            0 => self.can1.as_ref().unwrap().error_counters(),
-            1 => self.can2.as_ref().unwrap().error_counters(),
-            _ => panic!("Interface {} not in use", interface),
-        };
+        if let Some(mode) = can.take() {
+            let monitoring = matches!(mode, Mode::Monitoring(_));
+            let mut can_config = mode.into_config();
+            can_config.enable_interrupt_line(InterruptLine::_0, false);
+            can_config.enable_interrupt_line(InterruptLine::_1, false);
 
-        let rx_errors = match counters.receive_err {
-            ReceiveErrorOverflow::Normal(count) => count,
-            ReceiveErrorOverflow::Overflow(count) => count,
-        };
+            let mut can_monitoring = can_config.into_bus_monitoring();
+            can_monitoring.clear_interrupt(Interrupt::RxFifo0NewMsg);
+            can_monitoring.clear_interrupt(Interrupt::RxFifo1NewMsg);
+            can.replace(Mode::Monitoring(can_monitoring));
 
-        DeviceState {
-            state: CanState::Active,
-            tx_errors: counters.transmit_err as u32,
-            rx_errors: rx_errors as u32,
+            self.presence = Presence::Checking { until, monitoring };
         }
+
+        true
     }
 
-    fn receive(&mut self, interface: u8, frame: &usbd_gscan::host::Frame) {
-        let header = TxFrameHeader {
-            len: frame.data().len() as u8,
-            frame_format: if frame.flags.intersects(FrameFlag::FD) {
-                FrameFormat::Fdcan
-            } else {
-                FrameFormat::Standard
-            },
-            id: id_to_fdcan(frame.id()),
-            bit_rate_switching: frame
-                .flags
-                .intersects(FrameFlag::BIT_RATE_SWITCH),
-            marker: None,
+    /// Finish the bus presence check once its window has passed.
+    ///
+    /// A valid frame sets an RX FIFO new message flag whether or not it's
+    /// accepted by a filter, while bit rate mismatches and noise only raise
+    /// protocol errors.
+    fn poll_presence<I: fdcan::Instance>(
+        &mut self,
+        can: &mut Option<Mode<I>>,
+        now: Instant,
+    ) {
+        let Presence::Checking { until, monitoring } = self.presence else {
+            return;
         };
 
-        match interface {
-            0 => {
-                if let Some(can) = &mut self.can1 {
-                    let overflow =
-                        nb::block!(can.transmit(header, frame.data()))
-                            .unwrap()
-                            .is_some();
-                    if overflow {
-                        defmt::warn!("CAN1 overflow");
-                    }
-                }
-            }
-            1 => {
-                if let Some(can) = &mut self.can2 {
-                    let overflow =
-                        nb::block!(can.transmit(header, frame.data()))
-                            .unwrap()
-                            .is_some();
-                    if overflow {
-                        defmt::warn!("CAN2 overflow");
-                    }
-                }
+        if now < until {
+            return;
+        }
+
+        let Some(Mode::Monitoring(can_monitoring)) = can else {
+            self.presence = Presence::Unknown;
+            return;
+        };
+
+        let seen = can_monitoring.has_interrupt(Interrupt::RxFifo0NewMsg)
+            || can_monitoring.has_interrupt(Interrupt::RxFifo1NewMsg);
+        self.presence = match seen {
+            true => Presence::Present,
+            false => Presence::Absent,
+        };
+
+        if !monitoring {
+            if let Some(mode) = can.take() {
+                can.replace(Mode::Config(mode.into_config()));
             }
-            _ => {}
         }
     }
-}
 
-/// Convert fdcan id type to embedded-hal id type.
-pub fn id_to_embedded(id: fdcan::id::Id) -> embedded_can::Id {
-    match id {
-        fdcan::id::Id::Extended(id) => {
-            Id::Extended(embedded_can::ExtendedId::new(id.as_raw()).unwrap())
+    /// Send a frame in external loopback mode to test the path through the
+    /// transceiver and back.
+    ///
+    /// Returns `false` if the interface is started or a presence check is
+    /// running.
+    fn start_self_test<I: fdcan::Instance>(
+        &mut self,
+        can: &mut Option<Mode<I>>,
+        until: Instant,
+    ) -> bool {
+        if can.as_ref().map_or(true, Mode::is_started)
+            || matches!(self.presence, Presence::Checking { .. })
+        {
+            return false;
         }
-        fdcan::id::Id::Standard(id) => {
-            Id::Standard(embedded_can::StandardId::new(id.as_raw()).unwrap())
+
+        if let Some(mode) = can.take() {
+            let monitoring = matches!(mode, Mode::Monitoring(_));
+            let mut can_config = mode.into_config();
+            can_config.enable_interrupt_line(InterruptLine::_0, false);
+            can_config.enable_interrupt_line(InterruptLine::_1, false);
+
+            let mut can_loopback = can_config.into_external_loopback();
+            can_loopback.clear_interrupt(Interrupt::TxComplete);
+
+            let frame = self_test_frame();
+            let queued = can_loopback.transmit(frame.header, frame.data());
+            can.replace(Mode::ExternalLoopback(can_loopback));
+
+            self.self_test = match queued {
+                Ok(_) => SelfTest::Running { until, monitoring },
+                Err(_) => SelfTest::Failed,
+            };
         }
+
+        true
     }
-}
 
-/// Convert embedded-hal id type to fdcan id type.
-pub fn id_to_fdcan(id: embedded_can::Id) -> fdcan::id::Id {
-    match id {
-        Id::Extended(id) => fdcan::id::Id::Extended(
-            fdcan::id::ExtendedId::new(id.as_raw()).unwrap(),
-        ),
-        Id::Standard(id) => fdcan::id::Id::Standard(
-            fdcan::id::StandardId::new(id.as_raw()).unwrap(),
-        ),
+    /// Finish the self-test once the test frame is sent or its window has
+    /// passed.
+    ///
+    /// The controller only completes a transmission if every bit it drives
+    /// is read back from the transceiver, so a transceiver in standby, a
+    /// broken TX or RX connection or a stuck bus fails the test. A frame
+    /// still pending at the end of the window is cancelled.
+    fn poll_self_test<I: fdcan::Instance>(
+        &mut self,
+        can: &mut Option<Mode<I>>,
+        now: Instant,
+        interface: u8,
+    ) {
+        let SelfTest::Running { until, monitoring } = self.self_test else {
+            return;
+        };
+
+        let Some(Mode::ExternalLoopback(can_loopback)) = can else {
+            self.self_test = SelfTest::Unknown;
+            return;
+        };
+
+        let sent = can_loopback.has_interrupt(Interrupt::TxComplete);
+        if !sent && now < until {
+            return;
+        }
+
+        if !sent {
+            cancel_tx(interface);
+        }
+
+        self.self_test = match sent {
+            true => SelfTest::Passed,
+            false => SelfTest::Failed,
+        };
+        defmt::info!("Interface {} self-test {}", interface, sent);
+
+        if let Some(mode) = can.take() {
+            let can_config = mode.into_config();
+            can.replace(match monitoring {
+                true => Mode::Monitoring(can_config.into_bus_monitoring()),
+                false => Mode::Config(can_config),
+            });
+        }
+    }
+
+    /// Recover from bus-off if needed.
+    fn recover<I: fdcan::Instance>(
+        &mut self,
+        can: &mut Option<Mode<I>>,
+        interface: u8,
+    ) {
+        let Some(Mode::Normal(normal)) = can else {
+            return;
+        };
+
+        if !is_bus_off(normal) {
+            self.failed_recoveries = 0;
+            self.bus_off_at = None;
+            return;
+        }
+
+        if self.faulted {
+            return;
+        }
+
+        let now = Mono::now();
+        if self.bus_off_at.is_none() {
+            self.counters.bus_off += 1;
+            self.failed_recoveries += 1;
+
+            if self.failed_recoveries > MAX_FAILED_RECOVERIES {
+                defmt::error!("Interface {} faulted", interface);
+                event::record(event::Kind::Fault, interface, 0);
+                self.went_down(StopReason::Fault);
+                self.faulted = true;
+                self.tx.clear();
+                self.echoes.abandon_all();
+                return;
+            }
+
+            defmt::warn!("Interface {} bus-off", interface);
+            self.went_down(StopReason::BusOff);
+            event::record(
+                event::Kind::BusOff,
+                interface,
+                self.failed_recoveries as u16,
+            );
+            self.bus_off_at = Some(now);
+        }
+
+        let delay = match self.restart_ms {
+            RestartMs(0) => return,
+            RestartMs(ms) => Duration::millis(ms as u64),
+        };
+        if self.bus_off_at.is_some_and(|at| now < at + delay) {
+            return;
+        }
+
+        defmt::info!("Interface {} recovering from bus-off", interface);
+        self.bus_off_at = None;
+
+        // Leaving initialization starts the bus-off recovery sequence.
+        reconfigure(can, |_| {});
+    }
+}
+
+pub struct UsbCanDevice {
+    /// CAN peripheral clock. Used by the host for bit timing calculations.
+    clock: Hertz,
+    /// CAN interface labeled "CAN1" on PCB.
+    pub can1: Option<Mode<Can<FDCAN2>>>,
+    /// CAN interface labeled "CAN2" on PCB.
+    pub can2: Option<Mode<Can<FDCAN3>>>,
+    /// CAN interface on the FDCAN1 header, interface 2. Only present on
+    /// SKUs that route it.
+    pub can0: Option<Mode<Can<FDCAN1>>>,
+    /// Software state indexed by interface number.
+    pub interfaces: [Interface; INTERFACES],
+    /// Gateway rules forwarding received frames between interfaces.
+    pub routes: RoutingTable,
+    /// Transceiver standby pins.
+    pub transceivers: Transceivers,
+    /// Interfaces advertised to the host, see [`Self::set_channels`].
+    channels: u8,
+    /// Interfaces can't be started before this, see
+    /// [`Self::set_startup_delay`].
+    ready_at: Instant,
+}
+
+impl UsbCanDevice {
+    pub fn new(
+        clock: Hertz,
+        can1: FdCan<Can<FDCAN2>, ConfigMode>,
+        can2: FdCan<Can<FDCAN3>, ConfigMode>,
+        can0: Option<FdCan<Can<FDCAN1>, ConfigMode>>,
+    ) -> Self {
+        Self {
+            clock,
+            can1: Some(Mode::Config(can1)),
+            can2: Some(Mode::Config(can2)),
+            can0: can0.map(Mode::Config),
+            interfaces: Default::default(),
+            routes: RoutingTable::default(),
+            transceivers: Transceivers::default(),
+            channels: 2,
+            ready_at: Instant::from_ticks(0),
+        }
+    }
+
+    /// Set the number of interfaces advertised to the host from the SKU.
+    ///
+    /// Capped at the interfaces with a controller, so interface 2 is only
+    /// advertised if FDCAN1 was given to [`Self::new`]. The host reads it
+    /// when the device is probed, so it's only set at boot.
+    pub fn set_channels(&mut self, count: u8) {
+        let driven = 2 + self.can0.is_some() as u8;
+        self.channels = count.clamp(1, driven);
+    }
+
+    /// Hold back starting interfaces until `delay` after now.
+    ///
+    /// Called once at boot. Starts the host requests in the meantime are
+    /// applied when the delay ends, see [`Self::start_deferred`].
+    pub fn set_startup_delay(&mut self, delay: Duration) {
+        self.ready_at = Mono::now() + delay;
+    }
+
+    /// Start interfaces whose start was held back by the startup delay, once
+    /// it has ended.
+    pub fn start_deferred(&mut self) {
+        if Mono::now() < self.ready_at {
+            return;
+        }
+
+        for interface in 0..self.interfaces.len() as u8 {
+            let i = &mut self.interfaces[interface as usize];
+            if let Some(features) = i.deferred_start.take() {
+                defmt::info!("Interface {} starting after delay", interface);
+                Device::start(self, interface, features);
+            }
+        }
+    }
+
+    /// Program default bit rates on every interface so they can observe a
+    /// bus before the host configures them.
+    ///
+    /// Called once at boot. Bit timing from the host replaces it as usual.
+    /// A rate of zero is skipped.
+    pub fn set_default_timing(&mut self, nominal: u32, data: u32) {
+        let clock = self.clock.to_Hz();
+
+        for interface in 0..self.interfaces.len() as u8 {
+            if nominal != 0 {
+                let result = timing_for_rate(
+                    clock,
+                    nominal,
+                    &TIMING_NOMINAL,
+                    DEFAULT_NOMINAL_SAMPLE_POINT,
+                )
+                .and_then(|t| self.set_nominal_timing(interface, &t));
+                if let Err(e) = result {
+                    defmt::warn!(
+                        "Default nominal rate {} unset: {}",
+                        nominal,
+                        e
+                    );
+                }
+            }
+
+            if data != 0 {
+                let result = timing_for_rate(
+                    clock,
+                    data,
+                    &TIMING_DATA,
+                    DEFAULT_DATA_SAMPLE_POINT,
+                )
+                .and_then(|t| self.set_data_timing(interface, &t));
+                if let Err(e) = result {
+                    defmt::warn!("Default data rate {} unset: {}", data, e);
+                }
+            }
+        }
+    }
+
+    /// Check for interfaces that powered up into a heavily loaded bus.
+    ///
+    /// Called once at boot, before the host has started any interface.
+    /// The controllers listen in bus monitoring while sampling, so nothing
+    /// is acknowledged, and are returned to configuration mode after. A
+    /// loaded bus is only logged, as the interfaces stay off the bus until
+    /// the host starts them either way.
+    pub fn detect_loaded_bus(&mut self) {
+        into_monitoring(&mut self.can1);
+        into_monitoring(&mut self.can2);
+        into_monitoring(&mut self.can0);
+
+        let mut busy = [0; INTERFACES];
+        for _ in 0..LOADED_BUS_SAMPLES {
+            asm::delay(CORE_CLOCK_MHZ * 1000);
+            busy[0] += is_busy(&self.can1) as u32;
+            busy[1] += is_busy(&self.can2) as u32;
+            busy[2] += is_busy(&self.can0) as u32;
+        }
+
+        into_config(&mut self.can1);
+        into_config(&mut self.can2);
+        into_config(&mut self.can0);
+
+        for (interface, busy) in busy.into_iter().enumerate() {
+            if busy >= LOADED_BUS_THRESHOLD {
+                defmt::warn!(
+                    "Loaded bus detected on interface {} at boot",
+                    interface
+                );
+                event::record(event::Kind::LoadedBus, interface as u8, 0);
+            }
+        }
+    }
+
+    /// Send a frame on each interface in internal loopback and log whether
+    /// it was sent.
+    ///
+    /// Called once at boot, before the host has started any interface.
+    /// Internal loopback doesn't drive the bus, so this is safe on a live
+    /// bus but only covers the controllers and message RAM. The self-test
+    /// request covers the transceivers too.
+    pub fn power_on_self_test(&mut self) {
+        let passed = [
+            loopback_test(&mut self.can1, 0),
+            loopback_test(&mut self.can2, 1),
+            loopback_test(&mut self.can0, 2),
+        ];
+
+        let driven = 2 + self.can0.is_some() as usize;
+        for (interface, passed) in passed.into_iter().enumerate().take(driven) {
+            match passed {
+                true => {
+                    defmt::info!("Interface {} self-test passed", interface)
+                }
+                false => {
+                    defmt::error!("Interface {} self-test failed", interface)
+                }
+            }
+        }
+    }
+
+    /// Check if an interface has been started by the host.
+    pub fn is_started(&self, interface: u8) -> bool {
+        match interface {
+            0 => self.can1.as_ref().is_some_and(Mode::is_started),
+            1 => self.can2.as_ref().is_some_and(Mode::is_started),
+            2 => self.can0.as_ref().is_some_and(Mode::is_started),
+            _ => false,
+        }
+    }
+
+    /// Listen for valid frames on every interface that isn't started.
+    ///
+    /// Returns `false` if a check is already running or every interface is
+    /// started.
+    pub fn check_presence(&mut self, window: Duration) -> bool {
+        let checking = self
+            .interfaces
+            .iter()
+            .any(|i| matches!(i.presence, Presence::Checking { .. }));
+        if checking {
+            return false;
+        }
+
+        let until = Mono::now() + window;
+        let [i1, i2, i3] = &mut self.interfaces;
+        let checked1 = i1.check_presence(&mut self.can1, until);
+        let checked2 = i2.check_presence(&mut self.can2, until);
+        let checked3 = i3.check_presence(&mut self.can0, until);
+
+        checked1 || checked2 || checked3
+    }
+
+    /// Finish a running bus presence check once its window has passed.
+    pub fn poll_presence(&mut self) {
+        let now = Mono::now();
+        let [i1, i2, i3] = &mut self.interfaces;
+        i1.poll_presence(&mut self.can1, now);
+        i2.poll_presence(&mut self.can2, now);
+        i3.poll_presence(&mut self.can0, now);
+    }
+
+    /// Start the external loopback self-test on a stopped interface.
+    ///
+    /// Returns `false` if the interface doesn't exist, is started, or is
+    /// already being tested or checked for bus presence.
+    pub fn self_test(&mut self, interface: u8, window: Duration) -> bool {
+        let until = Mono::now() + window;
+        let [i1, i2, i3] = &mut self.interfaces;
+        let running =
+            |i: &Interface| matches!(i.self_test, SelfTest::Running { .. });
+        match interface {
+            0 if !running(i1) => i1.start_self_test(&mut self.can1, until),
+            1 if !running(i2) => i2.start_self_test(&mut self.can2, until),
+            2 if !running(i3) => i3.start_self_test(&mut self.can0, until),
+            _ => false,
+        }
+    }
+
+    /// Finish running self-tests once their frame is sent or their window
+    /// has passed.
+    pub fn poll_self_test(&mut self) {
+        let now = Mono::now();
+        let [i1, i2, i3] = &mut self.interfaces;
+        i1.poll_self_test(&mut self.can1, now, 0);
+        i2.poll_self_test(&mut self.can2, now, 1);
+        i3.poll_self_test(&mut self.can0, now, 2);
+    }
+
+    /// Take every interface off the bus and discard queued frames, ahead of
+    /// a software reset.
+    pub fn quiesce(&mut self) {
+        let [i1, i2, i3] = &mut self.interfaces;
+        i1.stop(&mut self.can1);
+        i2.stop(&mut self.can2);
+        i3.stop(&mut self.can0);
+        for i in &mut self.interfaces {
+            i.went_down(StopReason::Reset);
+        }
+    }
+
+    /// Put the transceiver of an interface in or out of standby.
+    ///
+    /// A started interface is taken off the bus first rather than left
+    /// transmitting into a transceiver that no longer drives the bus.
+    pub fn set_standby(
+        &mut self,
+        interface: u8,
+        standby: bool,
+    ) -> Result<(), Error> {
+        if self.transceivers.standby(interface).is_none() {
+            return Err(Error::NoStandbyPin(interface));
+        }
+
+        if standby && self.is_started(interface) {
+            let [i1, i2, i3] = &mut self.interfaces;
+            match interface {
+                0 => i1.stop(&mut self.can1),
+                1 => i2.stop(&mut self.can2),
+                _ => i3.stop(&mut self.can0),
+            }
+            self.interfaces[interface as usize].went_down(StopReason::Standby);
+            event::record(event::Kind::Stop, interface, 0);
+        }
+
+        self.transceivers.set_standby(interface, standby)
+    }
+
+    /// Check if no interface is started.
+    pub fn is_idle(&self) -> bool {
+        (0..INTERFACES as u8).all(|interface| !self.is_started(interface))
+    }
+
+    /// Check if an interface is in loopback mode.
+    pub fn is_loopback(&self, interface: u8) -> bool {
+        match interface {
+            0 => matches!(self.can1, Some(Mode::Loopback(_))),
+            1 => matches!(self.can2, Some(Mode::Loopback(_))),
+            2 => matches!(self.can0, Some(Mode::Loopback(_))),
+            _ => false,
+        }
+    }
+
+    /// Set the nominal bit timing of an interface.
+    fn set_nominal_timing(
+        &mut self,
+        interface: u8,
+        timing: &DeviceBitTiming,
+    ) -> Result<(), Error> {
+        let btr = nominal_timing(timing)?;
+
+        match interface {
+            0 => reconfigure(&mut self.can1, |c| c.set_nominal_bit_timing(btr)),
+            1 => reconfigure(&mut self.can2, |c| c.set_nominal_bit_timing(btr)),
+            2 if self.can0.is_some() => {
+                reconfigure(&mut self.can0, |c| c.set_nominal_bit_timing(btr))
+            }
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        event::record(event::Kind::Reconfigure, interface, 0);
+
+        Ok(())
+    }
+
+    /// Set the data bit timing of an interface.
+    fn set_data_timing(
+        &mut self,
+        interface: u8,
+        timing: &DeviceBitTiming,
+    ) -> Result<(), Error> {
+        let tdc = self
+            .interfaces
+            .get(interface as usize)
+            .is_some_and(|i| i.tdc.is_some());
+        let btr = data_timing(timing, tdc)?;
+
+        match interface {
+            0 => reconfigure(&mut self.can1, |c| c.set_data_bit_timing(btr)),
+            1 => reconfigure(&mut self.can2, |c| c.set_data_bit_timing(btr)),
+            2 if self.can0.is_some() => {
+                reconfigure(&mut self.can0, |c| c.set_data_bit_timing(btr))
+            }
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        event::record(event::Kind::Reconfigure, interface, 1);
+
+        Ok(())
+    }
+
+    /// Move the nominal or data sample point (per mille) of an interface,
+    /// keeping the programmed prescaler and bit rate.
+    ///
+    /// Returns the sample point achieved after rounding to whole quanta.
+    pub fn set_sample_point(
+        &mut self,
+        interface: u8,
+        data: bool,
+        sample_point: u32,
+    ) -> Result<u32, Error> {
+        let programmed = self
+            .programmed_timing(interface)
+            .ok_or(Error::Interface(interface))?;
+
+        let programmed = if data {
+            let timing = with_sample_point(
+                &programmed.data_bit_timing(),
+                &TIMING_DATA,
+                sample_point,
+            )?;
+            self.set_data_timing(interface, &timing)?;
+            self.programmed_timing(interface)
+                .map(|t| t.data(self.clock))
+        } else {
+            let timing = with_sample_point(
+                &programmed.nominal_bit_timing(),
+                &TIMING_NOMINAL,
+                sample_point,
+            )?;
+            self.set_nominal_timing(interface, &timing)?;
+            self.programmed_timing(interface)
+                .map(|t| t.nominal(self.clock))
+        };
+
+        programmed
+            .map(|(_, sample_point)| sample_point)
+            .ok_or(Error::Interface(interface))
+    }
+
+    /// Set the nominal and data bit timing of interfaces 0 and 1 together.
+    ///
+    /// Both controllers are put in configuration mode before either is
+    /// changed and returned to their previous modes back to back, so a
+    /// synchronised pair of buses goes live with as little skew as
+    /// possible. `timing` holds `[nominal, data]` for each interface.
+    ///
+    /// Nothing is changed unless the timing for both interfaces is valid.
+    /// The result for each interface is returned either way.
+    pub fn set_timing_both(
+        &mut self,
+        timing: &[[DeviceBitTiming; 2]; 2],
+    ) -> [Result<(), Error>; 2] {
+        let btr = |index: usize| {
+            let tdc = self.interfaces[index].tdc.is_some();
+            let [nominal, data] = &timing[index];
+            Ok::<_, Error>((nominal_timing(nominal)?, data_timing(data, tdc)?))
+        };
+        let btr = [btr(0), btr(1)];
+
+        let [Ok((nominal1, data1)), Ok((nominal2, data2))] = btr else {
+            return btr.map(|b| b.map(|_| ()));
+        };
+
+        reconfigure(&mut self.can1, |c1| {
+            reconfigure(&mut self.can2, |c2| {
+                c1.set_nominal_bit_timing(nominal1);
+                c1.set_data_bit_timing(data1);
+                c2.set_nominal_bit_timing(nominal2);
+                c2.set_data_bit_timing(data2);
+            });
+        });
+
+        for interface in 0..2 {
+            event::record(event::Kind::Reconfigure, interface, 0);
+            event::record(event::Kind::Reconfigure, interface, 1);
+        }
+
+        [Ok(()), Ok(())]
+    }
+
+    /// Enable transmitter delay compensation with the given parameters, or
+    /// disable it.
+    ///
+    /// The fdcan crate only sets the enable bit so the registers are written
+    /// directly in configuration mode.
+    pub fn set_tdc(
+        &mut self,
+        interface: u8,
+        tdc: Option<Tdc>,
+    ) -> Result<(), Error> {
+        let tdcr = match tdc {
+            Some(tdc) => tdc.tdcr()?,
+            None => 0,
+        };
+        let enable = match tdc {
+            Some(_) => DBTP_TDC,
+            None => 0,
+        };
+
+        // SAFETY: the controller is in configuration mode inside
+        // `reconfigure` so the registers are writable, and the fdcan crate
+        // doesn't hold any state for them.
+        match interface {
+            0 => reconfigure(&mut self.can1, |_| {
+                let regs = unsafe { &*FDCAN2::ptr() };
+                regs.tdcr.write(|w| unsafe { w.bits(tdcr) });
+                regs.dbtp.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !DBTP_TDC) | enable)
+                });
+            }),
+            1 => reconfigure(&mut self.can2, |_| {
+                let regs = unsafe { &*FDCAN3::ptr() };
+                regs.tdcr.write(|w| unsafe { w.bits(tdcr) });
+                regs.dbtp.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !DBTP_TDC) | enable)
+                });
+            }),
+            2 if self.can0.is_some() => reconfigure(&mut self.can0, |_| {
+                let regs = unsafe { &*FDCAN1::ptr() };
+                regs.tdcr.write(|w| unsafe { w.bits(tdcr) });
+                regs.dbtp.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !DBTP_TDC) | enable)
+                });
+            }),
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        self.interfaces[interface as usize].tdc = tdc;
+
+        Ok(())
+    }
+
+    /// Enable or disable protocol exception handling.
+    ///
+    /// When enabled, the controller treats an FD frame format bit it doesn't
+    /// expect as a protocol exception and waits for the bus to go idle
+    /// rather than signalling an error. Only the given interface is taken
+    /// through configuration mode.
+    pub fn set_protocol_exception(
+        &mut self,
+        interface: u8,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        match interface {
+            0 => reconfigure(&mut self.can1, |c| {
+                c.set_protocol_exception_handling(enabled)
+            }),
+            1 => reconfigure(&mut self.can2, |c| {
+                c.set_protocol_exception_handling(enabled)
+            }),
+            2 if self.can0.is_some() => reconfigure(&mut self.can0, |c| {
+                c.set_protocol_exception_handling(enabled)
+            }),
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        defmt::info!(
+            "Interface {} protocol exception handling {}",
+            interface,
+            enabled
+        );
+
+        Ok(())
+    }
+
+    /// Select how the transmit buffers of an interface are sent.
+    ///
+    /// In FIFO mode frames go on the bus strictly in the order they were
+    /// queued, which sequential tooling relies on. In queue mode the
+    /// buffered frame with the highest priority identifier goes first, so
+    /// urgent frames overtake bulk traffic. Only the given interface is
+    /// taken through configuration mode.
+    pub fn set_tx_buffer_mode(
+        &mut self,
+        interface: u8,
+        mode: TxBufferMode,
+    ) -> Result<(), Error> {
+        match interface {
+            0 => reconfigure(&mut self.can1, |c| c.set_tx_buffer_mode(mode)),
+            1 => reconfigure(&mut self.can2, |c| c.set_tx_buffer_mode(mode)),
+            2 if self.can0.is_some() => {
+                reconfigure(&mut self.can0, |c| c.set_tx_buffer_mode(mode))
+            }
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        defmt::info!(
+            "Interface {} TX buffer mode {}",
+            interface,
+            matches!(mode, TxBufferMode::Priority)
+        );
+
+        Ok(())
+    }
+
+    /// Change the automatic retransmission setting of an interface.
+    ///
+    /// A started interface is taken through configuration mode to apply it
+    /// straight away, otherwise it's applied when the interface is started.
+    pub fn set_retransmit(
+        &mut self,
+        interface: u8,
+        retransmit: Retransmit,
+    ) -> Result<(), Error> {
+        let i = self
+            .interfaces
+            .get_mut(interface as usize)
+            .ok_or(Error::Interface(interface))?;
+        i.retransmit = retransmit;
+
+        if let Some(features) = i.features {
+            let enabled = retransmit.enabled(features);
+            match interface {
+                0 => reconfigure(&mut self.can1, |c| {
+                    c.set_automatic_retransmit(enabled)
+                }),
+                1 => reconfigure(&mut self.can2, |c| {
+                    c.set_automatic_retransmit(enabled)
+                }),
+                _ => reconfigure(&mut self.can0, |c| {
+                    c.set_automatic_retransmit(enabled)
+                }),
+            }
+        }
+
+        defmt::info!("Interface {} retransmit {}", interface, retransmit);
+
+        Ok(())
+    }
+
+    /// Check if the controller of an interface retransmits frames that fail.
+    pub fn retransmit_enabled(&self, interface: u8) -> Option<bool> {
+        // SAFETY: read-only access to a register with no side effects.
+        let cccr = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.cccr.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.cccr.read().bits(),
+            2 if self.can0.is_some() => {
+                unsafe { &*FDCAN1::ptr() }.cccr.read().bits()
+            }
+            _ => return None,
+        };
+
+        Some(cccr & CCCR_DAR == 0)
+    }
+
+    /// Check if the controller of an interface has FD operation and bit
+    /// rate switching enabled, as `(fd, brs)`.
+    pub fn fd_enabled(&self, interface: u8) -> Option<(bool, bool)> {
+        // SAFETY: read-only access to a register with no side effects.
+        let cccr = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.cccr.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.cccr.read().bits(),
+            2 if self.can0.is_some() => {
+                unsafe { &*FDCAN1::ptr() }.cccr.read().bits()
+            }
+            _ => return None,
+        };
+
+        Some((cccr & CCCR_FDOE != 0, cccr & CCCR_BRSE != 0))
+    }
+
+    /// Hardware transmit buffers of an interface with a pending request,
+    /// one bit per buffer, from `FDCAN_TXBRP`.
+    ///
+    /// A bit clears once its frame has been sent, or given up on in one
+    /// shot mode.
+    pub fn tx_pending(&self, interface: u8) -> Option<u32> {
+        // SAFETY: read-only access to a register with no side effects.
+        let txbrp = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.txbrp.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.txbrp.read().bits(),
+            2 if self.can0.is_some() => {
+                unsafe { &*FDCAN1::ptr() }.txbrp.read().bits()
+            }
+            _ => return None,
+        };
+
+        Some(txbrp & 0x7)
+    }
+
+    /// Transmit buffer mode of an interface.
+    pub fn tx_buffer_mode(&self, interface: u8) -> Option<TxBufferMode> {
+        // SAFETY: read-only access to a register with no side effects.
+        let txbc = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.txbc.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.txbc.read().bits(),
+            2 if self.can0.is_some() => {
+                unsafe { &*FDCAN1::ptr() }.txbc.read().bits()
+            }
+            _ => return None,
+        };
+
+        Some(match txbc & TXBC_TFQM {
+            0 => TxBufferMode::Fifo,
+            _ => TxBufferMode::Priority,
+        })
+    }
+
+    /// Check if protocol exception handling is enabled on an interface.
+    pub fn protocol_exception(&self, interface: u8) -> Option<bool> {
+        // SAFETY: read-only access to a register with no side effects.
+        let cccr = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.cccr.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.cccr.read().bits(),
+            2 if self.can0.is_some() => {
+                unsafe { &*FDCAN1::ptr() }.cccr.read().bits()
+            }
+            _ => return None,
+        };
+
+        Some(cccr & CCCR_PXHD == 0)
+    }
+
+    /// Controller state and error counters of an interface.
+    ///
+    /// Read live from the controller on every call rather than from the last
+    /// error sample, so the state always agrees with the counters.
+    pub fn device_state(&self, interface: u8) -> Result<DeviceState, Error> {
+        let counters = self.error_counters(interface)?;
+
+        // The receive error counter is only 7 bits, with a separate flag for
+        // reaching the error passive level. Report at least that level so
+        // hosts deriving the state from the counters agree with it.
+        let rx_errors = match counters.receive_err {
+            ReceiveErrorOverflow::Normal(count) => count,
+            ReceiveErrorOverflow::Overflow(count) => {
+                count.max(ErrorState::PASSIVE_LEVEL)
+            }
+        };
+
+        // The protocol status can't be read here as that clears the last
+        // error code the error samples rely on. The controller sets INIT when
+        // it goes bus-off, which is otherwise only seen while stopped.
+        // SAFETY: read-only access to a register with no side effects.
+        let cccr = match interface {
+            0 => unsafe { &*FDCAN2::ptr() }.cccr.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.cccr.read().bits(),
+            _ => unsafe { &*FDCAN1::ptr() }.cccr.read().bits(),
+        };
+        let bus_off = cccr & CCCR_INIT != 0;
+
+        // gs_usb has no restricted state. Report it as error passive, the
+        // closest match, as the interface can't transmit. The restricted
+        // diagnostic tells the two apart.
+        let restricted = self.interfaces[interface as usize].restricted;
+
+        let state = match ErrorState::read(&counters, bus_off) {
+            _ if !self.is_started(interface) => CanState::Stopped,
+            ErrorState::Active | ErrorState::Warning if restricted => {
+                CanState::Passive
+            }
+            state => state.into(),
+        };
+
+        Ok(DeviceState {
+            state,
+            tx_errors: counters.transmit_err as u32,
+            rx_errors: rx_errors as u32,
+        })
+    }
+
+    /// Error counters of an interface.
+    fn error_counters(&self, interface: u8) -> Result<ErrorCounters, Error> {
+        let counters = match interface {
+            0 => self.can1.as_ref().map(Mode::error_counters),
+            1 => self.can2.as_ref().map(Mode::error_counters),
+            2 => self.can0.as_ref().map(Mode::error_counters),
+            _ => None,
+        };
+
+        counters.ok_or(Error::Interface(interface))
+    }
+
+    /// Queue a frame from the host for transmission, tagged so it's echoed
+    /// once sent.
+    ///
+    /// Returns `Ok(false)` if the frame was discarded as the interface is
+    /// faulted.
+    fn queue(
+        &mut self,
+        interface: u8,
+        frame: &usbd_gscan::host::Frame,
+    ) -> Result<bool, Error> {
+        let channels = self.channels;
+        let i = self
+            .interfaces
+            .get_mut(interface as usize)
+            .filter(|_| interface < channels)
+            .ok_or(Error::Interface(interface))?;
+
+        let Some(id) = id_to_fdcan(frame.id()) else {
+            let raw = match frame.id() {
+                Id::Standard(id) => id.as_raw() as u32,
+                Id::Extended(id) => id.as_raw() | ID_EXTENDED,
+            };
+            i.counters.reject_id(false, raw);
+            return Err(Error::Id);
+        };
+
+        let fd = frame.flags.intersects(FrameFlag::FD);
+        if FrameKind::classify(fd, frame.is_remote_frame())
+            == FrameKind::Invalid
+        {
+            i.counters.fd_dropped += 1;
+            return Err(Error::RemoteFd);
+        }
+
+        if i.faulted {
+            return Ok(false);
+        }
+
+        let mut tx = TxFrame::new(id, frame.flags, frame.data());
+        if i.refuse_listen_only() {
+            return Err(Error::ListenOnly(interface));
+        }
+        if i.refuse_fd(&tx) {
+            return Err(Error::FdDisabled(interface));
+        }
+        i.echoes.tag(&mut tx, frame);
+        i.latency.stamp(&tx);
+        i.push_tx(tx, interface);
+
+        Ok(true)
+    }
+
+    /// Record bit timing from the host that was rejected.
+    ///
+    /// gs_usb has no way to fail the request, so the host only finds out
+    /// from the rejected timing diagnostic.
+    fn timing_rejected(
+        &mut self,
+        interface: u8,
+        timing: &DeviceBitTiming,
+        data: bool,
+    ) {
+        let rejected = RejectedTiming::new(self.clock, timing, data);
+        if rejected.requested != 0 {
+            defmt::error!(
+                "Rate {} not achievable, nearest {}",
+                rejected.requested,
+                rejected.nearest
+            );
+        }
+
+        if let Some(i) = self.interfaces.get_mut(interface as usize) {
+            i.counters.timing_rejected += 1;
+            i.rejected_timing = Some(rejected);
+        }
+    }
+
+    /// CAN peripheral clock.
+    pub fn clock(&self) -> Hertz {
+        self.clock
+    }
+
+    /// Fastest nominal or data bit rate the timing limits allow, in bit/s.
+    pub fn max_rate(&self, data: bool) -> u32 {
+        let limits = match data {
+            true => &TIMING_DATA,
+            false => &TIMING_NOMINAL,
+        };
+        nearest_rate(self.clock.to_Hz(), u32::MAX, limits)
+    }
+
+    /// Fastest data bit rate with at least [`MIN_PRACTICAL_QUANTA`] time
+    /// quanta per bit, in bit/s.
+    pub fn practical_data_rate(&self) -> u32 {
+        self.clock.to_Hz() / (TIMING_DATA.brp_min * MIN_PRACTICAL_QUANTA)
+    }
+
+    /// Read back the bit timing registers of an interface.
+    ///
+    /// Useful for checking the values the host asked for were programmed
+    /// without truncation.
+    pub fn programmed_timing(&self, interface: u8) -> Option<ProgrammedTiming> {
+        // SAFETY: read-only access to registers with no side effects.
+        let (nbtp, dbtp) = match interface {
+            0 => {
+                let regs = unsafe { &*FDCAN2::ptr() };
+                (regs.nbtp.read().bits(), regs.dbtp.read().bits())
+            }
+            1 => {
+                let regs = unsafe { &*FDCAN3::ptr() };
+                (regs.nbtp.read().bits(), regs.dbtp.read().bits())
+            }
+            2 if self.can0.is_some() => {
+                let regs = unsafe { &*FDCAN1::ptr() };
+                (regs.nbtp.read().bits(), regs.dbtp.read().bits())
+            }
+            _ => return None,
+        };
+
+        Some(ProgrammedTiming { nbtp, dbtp })
+    }
+
+    /// Current runtime settings.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            tx_gap_us: self
+                .interfaces
+                .each_ref()
+                .map(|i| i.tx.gap.to_micros() as u32),
+            padding: self.interfaces.each_ref().map(|i| i.tx.padding),
+            ..Default::default()
+        }
+    }
+
+    /// Apply runtime settings.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        for (i, gap) in self.interfaces.iter_mut().zip(settings.tx_gap_us) {
+            i.tx.gap = Duration::micros(gap as u64);
+        }
+        for (i, padding) in self.interfaces.iter_mut().zip(settings.padding) {
+            i.tx.padding = padding;
+        }
+    }
+
+    /// Check for interfaces that have gone bus-off and try to recover them.
+    ///
+    /// An interface that keeps going bus-off is marked as faulted and left
+    /// alone so it can't affect the other interfaces.
+    pub fn recover_bus_off(&mut self) {
+        let [i1, i2, i3] = &mut self.interfaces;
+        i1.recover(&mut self.can1, 0);
+        i2.recover(&mut self.can2, 1);
+        i3.recover(&mut self.can0, 2);
+    }
+
+    /// Check for interfaces the controller has put in restricted operation.
+    ///
+    /// Polled alongside bus-off recovery. Restricted operation is left
+    /// alone until the host clears it, as the cause may recur.
+    pub fn check_restricted(&mut self) {
+        for interface in 0..self.interfaces.len() as u8 {
+            // SAFETY: read-only access to a register with no side effects.
+            let cccr = match interface {
+                0 => unsafe { &*FDCAN2::ptr() }.cccr.read().bits(),
+                1 => unsafe { &*FDCAN3::ptr() }.cccr.read().bits(),
+                _ => unsafe { &*FDCAN1::ptr() }.cccr.read().bits(),
+            };
+            let restricted = self.is_started(interface) && cccr & CCCR_ASM != 0;
+
+            let i = &mut self.interfaces[interface as usize];
+            if restricted && !i.restricted {
+                defmt::warn!("Interface {} in restricted operation", interface);
+                event::record(event::Kind::Restricted, interface, 0);
+                i.counters.restricted += 1;
+                i.went_down(StopReason::Restricted);
+            }
+            i.restricted = restricted;
+        }
+    }
+
+    /// Take an interface out of restricted operation.
+    pub fn clear_restricted(&mut self, interface: u8) -> Result<(), Error> {
+        // SAFETY: the controller is in configuration mode inside
+        // `reconfigure`, where restricted operation can be cleared.
+        match interface {
+            0 => reconfigure(&mut self.can1, |_| {
+                let regs = unsafe { &*FDCAN2::ptr() };
+                regs.cccr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !CCCR_ASM) });
+            }),
+            1 => reconfigure(&mut self.can2, |_| {
+                let regs = unsafe { &*FDCAN3::ptr() };
+                regs.cccr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !CCCR_ASM) });
+            }),
+            2 if self.can0.is_some() => reconfigure(&mut self.can0, |_| {
+                let regs = unsafe { &*FDCAN1::ptr() };
+                regs.cccr
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !CCCR_ASM) });
+            }),
+            _ => return Err(Error::Interface(interface)),
+        }
+
+        self.interfaces[interface as usize].restricted = false;
+
+        Ok(())
+    }
+
+    /// Sample protocol errors into the session counts.
+    pub fn sample_errors(&mut self) {
+        let [i1, i2, i3] = &mut self.interfaces;
+        i1.sample_errors(&self.can1, 0);
+        i2.sample_errors(&self.can2, 1);
+        i3.sample_errors(&self.can0, 2);
+    }
+
+    /// Move queued frames into the hardware transmit buffers.
+    ///
+    /// Returns the earliest time at which a paced interface may transmit
+    /// again.
+    pub fn transmit_queued(&mut self) -> Option<Instant> {
+        let [i1, i2, i3] = &mut self.interfaces;
+
+        let next1 = match &mut self.can1 {
+            Some(Mode::Normal(can)) => i1.transmit(can, 0),
+            Some(Mode::Loopback(can)) => i1.transmit(can, 0),
+            _ => None,
+        };
+        let next2 = match &mut self.can2 {
+            Some(Mode::Normal(can)) => i2.transmit(can, 1),
+            Some(Mode::Loopback(can)) => i2.transmit(can, 1),
+            _ => None,
+        };
+        let next3 = match &mut self.can0 {
+            Some(Mode::Normal(can)) => i3.transmit(can, 2),
+            Some(Mode::Loopback(can)) => i3.transmit(can, 2),
+            _ => None,
+        };
+
+        [next1, next2, next3].into_iter().flatten().min()
+    }
+
+    /// Refill the hardware transmit buffers as transmissions complete.
+    /// Called from the FDCAN interrupts.
+    ///
+    /// The transmission completed flags are cleared first, so a frame that
+    /// completes during the refill raises the interrupt again.
+    pub fn transmit_completed(&mut self) {
+        if let Some(can) = &mut self.can1 {
+            any_mode!(can, can => can.clear_interrupt(Interrupt::TxComplete));
+        }
+        if let Some(can) = &mut self.can2 {
+            any_mode!(can, can => can.clear_interrupt(Interrupt::TxComplete));
+        }
+        if let Some(can) = &mut self.can0 {
+            any_mode!(can, can => can.clear_interrupt(Interrupt::TxComplete));
+        }
+
+        self.transmit_queued();
+    }
+
+    /// Shortest transmit gap of the started interfaces, or `None` if none
+    /// of them are paced.
+    pub fn min_tx_gap(&self) -> Option<Duration> {
+        self.interfaces
+            .iter()
+            .enumerate()
+            .filter(|(index, i)| {
+                self.is_started(*index as u8) && i.tx.gap.ticks() != 0
+            })
+            .map(|(_, i)| i.tx.gap)
+            .min()
+    }
+
+    /// Forward a frame received on `source` to other interfaces according
+    /// to the routing table.
+    ///
+    /// Frames are queued like frames from the host, so a stopped or faulted
+    /// destination discards them and a full queue drops and counts them.
+    pub fn route(&mut self, source: u8, frame: &usbd_gscan::host::Frame) {
+        if self.routes.is_empty() {
+            return;
+        }
+
+        let Some(id) = id_to_fdcan(frame.id()) else {
+            return;
+        };
+        let frame = TxFrame::new(id, frame.flags, frame.data());
+
+        let mut routed = false;
+        let started = [0, 1, 2].map(|interface| self.is_started(interface));
+        let interfaces = &mut self.interfaces;
+        self.routes.route(source, &frame, |destination, frame| {
+            let Some(i) = interfaces.get_mut(destination as usize) else {
+                return false;
+            };
+            if !started[destination as usize]
+                || i.faulted
+                || i.refuse_listen_only()
+            {
+                return false;
+            }
+            if !i.push_tx(frame, destination) {
+                return false;
+            }
+            routed = true;
+            true
+        });
+
+        if routed {
+            self.transmit_queued();
+        }
+    }
+
+    /// Take the oldest echo or received frame waiting for the host, trying
+    /// the interfaces in turn starting with `first`.
+    ///
+    /// Echoes go first as the host limits the frames it has in flight.
+    pub fn pop_received(
+        &mut self,
+        first: u8,
+    ) -> Option<(u8, usbd_gscan::host::Frame)> {
+        let count = self.interfaces.len() as u8;
+        (0..count).find_map(|n| {
+            let interface = (first + n) % count;
+            let i = &mut self.interfaces[interface as usize];
+            let frame = i.echoes.pop().or_else(|| i.rx.pop())?;
+            Some((interface, frame))
+        })
+    }
+
+    /// Queue a frame carrying a host marker, reported back with its
+    /// transmit time once sent. See [`crate::marker`].
+    ///
+    /// Returns `false` without queuing anything unless the interface is
+    /// started and in service.
+    pub fn transmit_marked(
+        &mut self,
+        interface: u8,
+        mut frame: TxFrame,
+        marker: u8,
+    ) -> bool {
+        let started = self.is_started(interface);
+        let Some(i) = self.interfaces.get_mut(interface as usize) else {
+            return false;
+        };
+        // the latency test's markers share the TX event FIFO.
+        if !started || i.faulted || i.latency.is_running() {
+            return false;
+        }
+        if i.refuse_listen_only() || i.refuse_fd(&frame) {
+            return false;
+        }
+
+        frame.header.marker = Some(marker);
+        i.push_tx(frame, interface);
+
+        self.transmit_queued();
+
+        true
+    }
+
+    /// Queue the same frame on interfaces 0 and 1 so they transmit as close
+    /// together as possible.
+    ///
+    /// Returns `false` without queuing anything unless both interfaces are
+    /// started and in service.
+    pub fn transmit_both(&mut self, mut frame: TxFrame) -> bool {
+        let started = self.is_started(0) && self.is_started(1);
+        let pair = &mut self.interfaces[..2];
+        let refused = pair.iter().any(|i| i.faulted || i.refuse_listen_only());
+        if !started || refused {
+            return false;
+        }
+        if pair.iter_mut().any(|i| i.refuse_fd(&frame)) {
+            return false;
+        }
+
+        frame.sync = true;
+        for (index, i) in pair.iter_mut().enumerate() {
+            i.tx.sync_cycles = None;
+            i.push_tx(frame, index as u8);
+        }
+
+        self.transmit_queued();
+
+        true
+    }
+
+    /// Difference between the times the last frames queued with
+    /// [`Self::transmit_both`] were handed to the hardware, in core cycles.
+    ///
+    /// `None` until both frames have been handed over.
+    pub fn transmit_skew(&self) -> Option<u32> {
+        let [i1, i2, _] = &self.interfaces;
+        let a = i1.tx.sync_cycles?;
+        let b = i2.tx.sync_cycles?;
+
+        // the cycle counter wraps, take the shorter distance.
+        Some(a.wrapping_sub(b).min(b.wrapping_sub(a)))
+    }
+}
+
+impl Device for UsbCanDevice {
+    fn config(&self) -> DeviceConfig {
+        DeviceConfig::new(self.channels)
+    }
+
+    fn bit_timing(&self) -> DeviceBitTimingConst {
+        DeviceBitTimingConst {
+            features: supported_features(),
+            fclk_can: self.clock.to_Hz(),
+            timing: TIMING_NOMINAL,
+        }
+    }
+
+    fn bit_timing_ext(&self) -> DeviceBitTimingConstExtended {
+        DeviceBitTimingConstExtended {
+            features: supported_features(),
+            fclk_can: self.clock.to_Hz(),
+            timing_nominal: TIMING_NOMINAL,
+            timing_data: TIMING_DATA,
+        }
+    }
+
+    fn configure_bit_timing(&mut self, interface: u8, timing: DeviceBitTiming) {
+        if let Err(e) = self.set_nominal_timing(interface, &timing) {
+            defmt::error!("Nominal bit timing rejected: {}", e);
+            self.timing_rejected(interface, &timing, false);
+        }
+    }
+
+    fn configure_bit_timing_data(
+        &mut self,
+        interface: u8,
+        timing: DeviceBitTiming,
+    ) {
+        if let Err(e) = self.set_data_timing(interface, &timing) {
+            defmt::error!("Data bit timing rejected: {}", e);
+            self.timing_rejected(interface, &timing, true);
+        }
+    }
+
+    /// Take the interface off the bus (`ip link set canX down`).
+    ///
+    /// See [`Interface::stop`] for the resulting controller state.
+    fn reset(&mut self, interface: u8) {
+        let [i1, i2, i3] = &mut self.interfaces;
+        match interface {
+            0 => i1.stop(&mut self.can1),
+            1 => i2.stop(&mut self.can2),
+            2 if self.can0.is_some() => i3.stop(&mut self.can0),
+            _ => {
+                defmt::error!("Interface {} not in use", interface);
+                return;
+            }
+        }
+
+        self.interfaces[interface as usize].went_down(StopReason::Host);
+        event::record(event::Kind::Stop, interface, 0);
+    }
+
+    /// Bring the interface onto the bus (`ip link set canX up`).
+    ///
+    /// See [`Interface::start`] for the resulting controller state.
+    fn start(&mut self, interface: u8, features: Feature) {
+        // gs_usb can't fail the request, so hold the start back instead.
+        if Mono::now() < self.ready_at {
+            if let Some(i) = self.interfaces.get_mut(interface as usize) {
+                defmt::info!("Interface {} start deferred", interface);
+                i.deferred_start = Some(features);
+                return;
+            }
+        }
+
+        // a self-test frame still pending would be sent once started.
+        let testing = self
+            .interfaces
+            .get(interface as usize)
+            .is_some_and(|i| matches!(i.self_test, SelfTest::Running { .. }));
+        if testing {
+            cancel_tx(interface);
+        }
+
+        let [i1, i2, i3] = &mut self.interfaces;
+        match interface {
+            0 => i1.start(&mut self.can1, features),
+            1 => i2.start(&mut self.can2, features),
+            2 if self.can0.is_some() => i3.start(&mut self.can0, features),
+            _ => {
+                defmt::error!("Interface {} not in use", interface);
+                return;
+            }
+        }
+
+        let unsupported = features & !supported_features();
+        if !unsupported.is_empty() {
+            defmt::warn!(
+                "Interface {} started with unsupported features {=u32:#x}",
+                interface,
+                unsupported.bits()
+            );
+        }
+
+        event::record(event::Kind::Start, interface, 0);
+    }
+
+    fn state(&self, interface: u8) -> usbd_gscan::host::DeviceState {
+        defmt::info!("Interface number: {}", interface);
+
+        match self.device_state(interface) {
+            Ok(state) => state,
+            Err(e) => {
+                defmt::error!("State unavailable: {}", e);
+                DeviceState {
+                    state: CanState::Stopped,
+                    tx_errors: 0,
+                    rx_errors: 0,
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, interface: u8, frame: &usbd_gscan::host::Frame) {
+        let queued = self.queue(interface, frame).unwrap_or_else(|e| {
+            defmt::warn!("Dropping frame: {}", e);
+            false
+        });
+
+        if queued {
+            self.transmit_queued();
+        } else if let Some(i) = self.interfaces.get_mut(interface as usize) {
+            // Echoed anyway, or the host would wait for it forever.
+            i.echoes.echo(frame.clone());
+        }
+    }
+}
+
+/// Check host bit timing against the controller limits.
+///
+/// `prop_seg` and `phase_seg1` are programmed as a single segment so their
+/// sum is checked against `tseg1_max` rather than being truncated. Returns
+/// the combined segment.
+fn check_timing(
+    timing: &DeviceBitTiming,
+    limits: &CanBitTimingConst,
+) -> Result<u8, Error> {
+    let seg1 = timing
+        .prop_seg
+        .checked_add(timing.phase_seg1)
+        .ok_or(Error::BitTiming)?;
+
+    let valid = (limits.tseg1_min..=limits.tseg1_max).contains(&seg1)
+        && (limits.tseg2_min..=limits.tset2_max).contains(&timing.phase_seg2)
+        && (1..=limits.sjw_max).contains(&timing.sjw)
+        && (limits.brp_min..=limits.brp_max).contains(&timing.brp);
+
+    if !valid {
+        defmt::error!(
+            "Bit timing out of range: brp={} prop_seg={} phase_seg1={} \
+            phase_seg2={} sjw={}",
+            timing.brp,
+            timing.prop_seg,
+            timing.phase_seg1,
+            timing.phase_seg2,
+            timing.sjw,
+        );
+        return Err(Error::BitTiming);
+    }
+
+    u8::try_from(seg1).map_err(|_| Error::BitTiming)
+}
+
+/// Bit timing for a bit rate with the sample point (per mille) as close as
+/// the quanta allow, using the smallest prescaler that fits the limits.
+fn timing_for_rate(
+    clock: u32,
+    rate: u32,
+    limits: &CanBitTimingConst,
+    sample_point: u32,
+) -> Result<DeviceBitTiming, Error> {
+    let max_quanta = 1 + limits.tseg1_max + limits.tset2_max;
+
+    for brp in limits.brp_min..=limits.brp_max {
+        let Some(divisor) = rate.checked_mul(brp).filter(|d| *d != 0) else {
+            break;
+        };
+        if clock % divisor != 0 {
+            continue;
+        }
+
+        let quanta = clock / divisor;
+        if quanta > max_quanta {
+            continue;
+        }
+
+        let phase_seg2 = quanta - (quanta * sample_point + 500) / 1000;
+        let Some(seg1) = (quanta - 1).checked_sub(phase_seg2) else {
+            continue;
+        };
+
+        if (limits.tseg1_min..=limits.tseg1_max).contains(&seg1)
+            && (limits.tseg2_min..=limits.tset2_max).contains(&phase_seg2)
+        {
+            return Ok(DeviceBitTiming {
+                prop_seg: 0,
+                phase_seg1: seg1,
+                phase_seg2,
+                sjw: phase_seg2.min(limits.sjw_max),
+                brp,
+            });
+        }
+    }
+
+    Err(Error::BitTiming)
+}
+
+/// Bit rate asked for by host bit timing, whether or not it is in range.
+///
+/// Returns zero if the prescaler is zero or the timing overflows.
+fn requested_rate(clock: u32, timing: &DeviceBitTiming) -> u32 {
+    let quanta = [timing.prop_seg, timing.phase_seg1, timing.phase_seg2]
+        .into_iter()
+        .try_fold(1u32, u32::checked_add);
+
+    quanta
+        .and_then(|q| q.checked_mul(timing.brp))
+        .filter(|d| *d != 0)
+        .map_or(0, |divisor| clock / divisor)
+}
+
+/// Closest bit rate to `rate` within the range the limits allow.
+///
+/// Rates in between are reached by whole quanta so may be off by a few
+/// percent, but are always within the range.
+fn nearest_rate(clock: u32, rate: u32, limits: &CanBitTimingConst) -> u32 {
+    let max_quanta = 1 + limits.tseg1_max + limits.tset2_max;
+    let min_quanta = 1 + limits.tseg1_min + limits.tseg2_min;
+
+    let slowest = clock / (limits.brp_max * max_quanta);
+    let fastest = clock / (limits.brp_min * min_quanta);
+
+    rate.clamp(slowest, fastest)
+}
+
+/// Move the sample point (per mille) of bit timing as close as the quanta
+/// allow, keeping the prescaler and bit rate.
+fn with_sample_point(
+    timing: &DeviceBitTiming,
+    limits: &CanBitTimingConst,
+    sample_point: u32,
+) -> Result<DeviceBitTiming, Error> {
+    if sample_point > 1000 {
+        return Err(Error::BitTiming);
+    }
+
+    let quanta = 1 + timing.prop_seg + timing.phase_seg1 + timing.phase_seg2;
+    let phase_seg2 = quanta - (quanta * sample_point + 500) / 1000;
+    let seg1 = (quanta - 1)
+        .checked_sub(phase_seg2)
+        .ok_or(Error::BitTiming)?;
+
+    if !(limits.tseg1_min..=limits.tseg1_max).contains(&seg1)
+        || !(limits.tseg2_min..=limits.tset2_max).contains(&phase_seg2)
+    {
+        return Err(Error::BitTiming);
+    }
+
+    Ok(DeviceBitTiming {
+        prop_seg: 0,
+        phase_seg1: seg1,
+        phase_seg2,
+        sjw: timing.sjw.min(phase_seg2),
+        brp: timing.brp,
+    })
+}
+
+/// Convert host nominal bit timing, rejecting values out of range.
+///
+/// Values that don't fit the register fields are rejected rather than
+/// truncated, so a malformed request never reaches the controller.
+fn nominal_timing(timing: &DeviceBitTiming) -> Result<NominalBitTiming, Error> {
+    let seg1 = check_timing(timing, &TIMING_NOMINAL)?;
+    let non_zero =
+        |value: u32| u8::try_from(value).ok().and_then(NonZeroU8::new);
+
+    Ok(NominalBitTiming {
+        prescaler: u16::try_from(timing.brp)
+            .ok()
+            .and_then(NonZeroU16::new)
+            .ok_or(Error::BitTiming)?,
+        seg1: non_zero(seg1 as u32).ok_or(Error::BitTiming)?,
+        seg2: non_zero(timing.phase_seg2).ok_or(Error::BitTiming)?,
+        sync_jump_width: non_zero(timing.sjw).ok_or(Error::BitTiming)?,
+    })
+}
+
+/// Convert host data bit timing, rejecting values out of range.
+fn data_timing(
+    timing: &DeviceBitTiming,
+    tdc: bool,
+) -> Result<DataBitTiming, Error> {
+    let seg1 = check_timing(timing, &TIMING_DATA)?;
+    let non_zero =
+        |value: u32| u8::try_from(value).ok().and_then(NonZeroU8::new);
+
+    Ok(DataBitTiming {
+        transceiver_delay_compensation: tdc,
+        prescaler: non_zero(timing.brp).ok_or(Error::BitTiming)?,
+        seg1: non_zero(seg1 as u32).ok_or(Error::BitTiming)?,
+        seg2: non_zero(timing.phase_seg2).ok_or(Error::BitTiming)?,
+        sync_jump_width: non_zero(timing.sjw).ok_or(Error::BitTiming)?,
+    })
+}
+
+/// Check if the controller is receiving or still waiting for the bus to go
+/// idle so it can synchronise.
+fn is_busy<I: fdcan::Instance>(can: &Option<Mode<I>>) -> bool {
+    can.as_ref().is_some_and(|mode| {
+        matches!(
+            mode.protocol_status().activity,
+            Activity::Receiver | Activity::Synchronizing
+        )
+    })
+}
+
+/// gs_usb features implemented by the firmware, advertised to the host.
+///
+/// Others the host starts an interface with are ignored.
+pub fn supported_features() -> Feature {
+    Feature::FD
+        | Feature::BT_CONST_EXT
+        | Feature::ONE_SHOT
+        | Feature::LOOP_BACK
+        | Feature::LISTEN_ONLY
+        | Feature::GET_STATE
+        | Feature::HW_TIMESTAMP
+}
+
+/// Cancel pending requests in every hardware transmit buffer of an
+/// interface.
+fn cancel_tx(interface: u8) {
+    // SAFETY: `FDCAN_TXBCR` only acts on the bits written, and only this
+    // interface's buffers are cancelled.
+    match interface {
+        0 => unsafe { &*FDCAN2::ptr() }
+            .txbcr
+            .write(|w| unsafe { w.bits(TXBCR_ALL) }),
+        1 => unsafe { &*FDCAN3::ptr() }
+            .txbcr
+            .write(|w| unsafe { w.bits(TXBCR_ALL) }),
+        2 => unsafe { &*FDCAN1::ptr() }
+            .txbcr
+            .write(|w| unsafe { w.bits(TXBCR_ALL) }),
+        _ => {}
+    }
+}
+
+/// Move the controller to bus monitoring.
+fn into_monitoring<I: fdcan::Instance>(can: &mut Option<Mode<I>>) {
+    if let Some(mode) = can.take() {
+        let config = mode.into_config();
+        can.replace(Mode::Monitoring(config.into_bus_monitoring()));
+    }
+}
+
+/// Frame sent by the self-tests, with the lowest priority identifier so any
+/// other node wins arbitration.
+fn self_test_frame() -> TxFrame {
+    TxFrame::new(
+        fdcan::id::Id::Standard(StandardId::MAX),
+        FrameFlag::empty(),
+        &SELF_TEST_DATA,
+    )
+}
+
+/// Send the self-test frame in internal loopback, waiting up to 10 ms for it
+/// to be sent, then return the controller to configuration mode.
+fn loopback_test<I: fdcan::Instance>(
+    can: &mut Option<Mode<I>>,
+    interface: u8,
+) -> bool {
+    let Some(mode) = can.take() else {
+        return false;
+    };
+
+    let mut can_loopback = mode.into_config().into_internal_loopback();
+    can_loopback.clear_interrupt(Interrupt::TxComplete);
+
+    let frame = self_test_frame();
+    let mut sent = false;
+    if can_loopback.transmit(frame.header, frame.data()).is_ok() {
+        for _ in 0..POST_POLLS {
+            asm::delay(CORE_CLOCK_MHZ * 100);
+            if can_loopback.has_interrupt(Interrupt::TxComplete) {
+                sent = true;
+                break;
+            }
+        }
+    }
+
+    if !sent {
+        cancel_tx(interface);
+    }
+
+    // drop the frame read back so it isn't forwarded once started.
+    let mut data = [0; 64];
+    while can_loopback.receive0(&mut data).is_ok() {}
+    while can_loopback.receive1(&mut data).is_ok() {}
+    can_loopback.clear_interrupt(Interrupt::RxFifo0NewMsg);
+    can_loopback.clear_interrupt(Interrupt::RxFifo1NewMsg);
+
+    can.replace(Mode::Config(can_loopback.into_config_mode()));
+    sent
+}
+
+/// Move the controller to configuration mode, off the bus.
+fn into_config<I: fdcan::Instance>(can: &mut Option<Mode<I>>) {
+    if let Some(mode) = can.take() {
+        can.replace(Mode::Config(mode.into_config()));
+    }
+}
+
+/// Check if an interface is bus-off.
+fn is_bus_off<I: fdcan::Instance, M>(can: &FdCan<I, M>) -> bool {
+    can.protocol_status().bus_off_status == BusOffStatus::BusOffState
+}
+
+/// Convert fdcan id type to embedded-hal id type.
+///
+/// Returns `None` if the raw value is out of range for an 11-bit standard or
+/// 29-bit extended identifier.
+pub fn id_to_embedded(id: fdcan::id::Id) -> Option<embedded_can::Id> {
+    match id {
+        fdcan::id::Id::Extended(id) => {
+            embedded_can::ExtendedId::new(id.as_raw()).map(Id::Extended)
+        }
+        fdcan::id::Id::Standard(id) => {
+            embedded_can::StandardId::new(id.as_raw()).map(Id::Standard)
+        }
+    }
+}
+
+/// Convert embedded-hal id type to fdcan id type.
+///
+/// Returns `None` if the raw value is out of range for an 11-bit standard or
+/// 29-bit extended identifier.
+pub fn id_to_fdcan(id: embedded_can::Id) -> Option<fdcan::id::Id> {
+    match id {
+        Id::Extended(id) => {
+            fdcan::id::ExtendedId::new(id.as_raw()).map(fdcan::id::Id::Extended)
+        }
+        Id::Standard(id) => {
+            fdcan::id::StandardId::new(id.as_raw()).map(fdcan::id::Id::Standard)
+        }
     }
 }
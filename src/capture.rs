@@ -0,0 +1,116 @@
+//! Received frame capture.
+//!
+//! A flight recorder for intermittent bus events that are hard to catch
+//! live over USB. While enabled by the host, the last [`CAPTURE_LEN`] frames
+//! received on either interface are kept in a ring in RAM, and can be read
+//! back at any time, including after capture is paused or the interfaces
+//! are stopped.
+//!
+//! Each record is 20 bytes, little-endian: `[time_us: u32, id: u32,
+//! interface: u8, len: u8, flags: u8, reserved: u8, data: [u8; 8]]`.
+//! `time_us` is microseconds since boot and wraps after ~71 minutes. Bit 31
+//! of `id` marks an extended identifier and bit 30 a remote frame. `flags`
+//! are gs_usb frame flags. Only the first 8 data bytes of FD frames are
+//! kept, `len` is still the full length.
+
+use crate::{
+    can::ID_EXTENDED,
+    hal::cortex_m::interrupt::{self, Mutex},
+    Mono,
+};
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use embedded_can::{Frame as _, Id};
+use heapless::HistoryBuffer;
+use rtic_monotonics::Monotonic;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Number of frames kept. Each costs [`RECORD_SIZE`] bytes of RAM, 1280
+/// bytes in all.
+pub const CAPTURE_LEN: usize = 64;
+/// Record size in bytes.
+pub const RECORD_SIZE: usize = 20;
+/// Remote frame flag in a record identifier.
+const ID_REMOTE: u32 = 1 << 30;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CAPTURE: Mutex<RefCell<HistoryBuffer<Record, CAPTURE_LEN>>> =
+    Mutex::new(RefCell::new(HistoryBuffer::new()));
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Record {
+    pub time_us: u32,
+    pub id: u32,
+    pub interface: u8,
+    pub len: u8,
+    pub flags: u8,
+    pub reserved: u8,
+    pub data: [u8; 8],
+}
+
+/// Start capturing, discarding frames from an earlier capture.
+pub fn start() {
+    interrupt::free(|cs| CAPTURE.borrow(cs).borrow_mut().clear());
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop capturing, keeping the frames captured so far.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Capture a frame received on `interface`, if capturing.
+pub fn record(interface: u8, frame: &usbd_gscan::host::Frame) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut id = match frame.id() {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | ID_EXTENDED,
+    };
+    let len = match frame.is_remote_frame() {
+        true => {
+            id |= ID_REMOTE;
+            frame.dlc()
+        }
+        false => frame.data().len(),
+    };
+
+    let mut data = [0; 8];
+    let kept = frame.data().len().min(data.len());
+    data[..kept].copy_from_slice(&frame.data()[..kept]);
+
+    let record = Record {
+        time_us: Mono::now().duration_since_epoch().to_micros() as u32,
+        id,
+        interface,
+        len: len as u8,
+        flags: frame.flags.bits(),
+        reserved: 0,
+        data,
+    };
+
+    interrupt::free(|cs| CAPTURE.borrow(cs).borrow_mut().write(record));
+}
+
+/// Copy captured frames, oldest first, skipping the first `skip`.
+///
+/// Returns the number of bytes written.
+pub fn read(skip: usize, buf: &mut [u8]) -> usize {
+    interrupt::free(|cs| {
+        let capture = CAPTURE.borrow(cs).borrow();
+        let records = capture.oldest_ordered().skip(skip);
+
+        let mut len = 0;
+        for (chunk, record) in buf.chunks_exact_mut(RECORD_SIZE).zip(records) {
+            chunk.copy_from_slice(record.as_bytes());
+            len += RECORD_SIZE;
+        }
+
+        len
+    })
+}
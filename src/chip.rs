@@ -0,0 +1,49 @@
+//! Silicon identification.
+//!
+//! Read from system memory and the debug MCU, which are read-only and
+//! readable without a debugger attached.
+
+/// Debug MCU identity code.
+const IDCODE: *const u32 = 0xE004_2000 as *const u32;
+/// Package data register.
+const PACKAGE: *const u16 = 0x1FFF_7500 as *const u16;
+/// 96-bit unique device identifier.
+const UID: *const u32 = 0x1FFF_7590 as *const u32;
+/// Flash size data register, in KiB.
+const FLASHSIZE: *const u16 = 0x1FFF_75E0 as *const u16;
+
+/// Flash size in KiB.
+pub fn flash_size_kb() -> u16 {
+    // SAFETY: read-only system memory.
+    unsafe { core::ptr::read_volatile(FLASHSIZE) }
+}
+
+/// Device identifier, `0x469` for the STM32G47x.
+pub fn device_id() -> u16 {
+    // SAFETY: read-only debug MCU register.
+    (unsafe { core::ptr::read_volatile(IDCODE) } & 0xfff) as u16
+}
+
+/// Silicon revision identifier.
+pub fn revision_id() -> u16 {
+    // SAFETY: read-only debug MCU register.
+    (unsafe { core::ptr::read_volatile(IDCODE) } >> 16) as u16
+}
+
+/// Package type.
+pub fn package() -> u8 {
+    // SAFETY: read-only system memory.
+    (unsafe { core::ptr::read_volatile(PACKAGE) } & 0x1f) as u8
+}
+
+/// Unique device identifier.
+pub fn uid() -> [u32; 3] {
+    // SAFETY: read-only system memory.
+    unsafe {
+        [
+            core::ptr::read_volatile(UID),
+            core::ptr::read_volatile(UID.add(1)),
+            core::ptr::read_volatile(UID.add(2)),
+        ]
+    }
+}
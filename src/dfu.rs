@@ -1,20 +1,162 @@
 //! Device firmware upgrade.
 
-use crate::hal::stm32::FLASH;
-use core::ops::RangeInclusive;
+use crate::{
+    chip,
+    error::Error,
+    event,
+    hal::stm32::{flash::RegisterBlock, FLASH},
+    image::{self, Record},
+    Mono,
+};
+use core::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use rtic_monotonics::Monotonic;
 use usbd_dfu::*;
+use zerocopy::AsBytes;
 
 pub const KEY: [u32; 2] = [0x4567_0123, 0xCDEF_89AB];
 const OPT_KEY: [u32; 2] = [0x0819_2A3B, 0x4C5D_6E7F];
-const FLASH_MEMORY: RangeInclusive<u32> = 0x0800_0000..=0x0803_FFFF;
-const BANK2_OFFSET: u32 = 0x00040000;
+// Flash layout generated by `build.rs` from the `FLASH_SIZE_KB` environment
+// variable (default 512). `DfuMemory::MEM_INFO_STRING` is an associated
+// constant so the memory map can't be built at runtime. Instead the flash
+// size register is checked against the build on startup.
+include!(concat!(env!("OUT_DIR"), "/flash.rs"));
+
+pub const FLASH_BASE: u32 = 0x0800_0000;
+pub const PAGE_SIZE: u32 = 2048;
+const FLASH_MEMORY: RangeInclusive<u32> =
+    FLASH_BASE..=FLASH_BASE + BANK_SIZE - 1;
+pub const BANK2_OFFSET: u32 = BANK_SIZE;
 
 /// Bank erase selection.
 const CR_BKER: u32 = 1 << 11;
+/// End of operation interrupt enable.
+const CR_EOPIE: u32 = 1 << 24;
+/// End of operation flag, cleared by writing 1.
+const SR_EOP: u32 = 1 << 0;
 /// Boot from bank 2 enabled bit.
 const OPTR_BFB2: u32 = 1 << 20;
 /// Dual bank mode enabled bit.
 const OPTR_DBANK: u32 = 1 << 22;
+/// User option bytes as stored in flash, loaded into `OPTR` at option byte
+/// loading.
+const OPTION_BYTES: *const u32 = 0x1FFF_7800 as *const u32;
+/// `FLASH_SR` error flags.
+const SR_ERRORS: u32 = 0xc3fa;
+/// Attempts at programming the option bytes before giving up.
+const OPTION_ATTEMPTS: u32 = 2;
+/// Longest time to program a double word, from the datasheet (90.8 µs).
+pub const DOUBLE_WORD_PROGRAM_US: u32 = 91;
+/// Longest time to erase a page, from the datasheet (24.47 ms).
+pub const PAGE_ERASE_US: u32 = 24_470;
+/// Multiple of the datasheet time after which the flash controller is
+/// given up on.
+pub const BUSY_MARGIN: u32 = 4;
+
+/// A page erase was started and hasn't been finished.
+static ERASING: AtomicBool = AtomicBool::new(false);
+/// Time the pending page erase was started, in microseconds since boot.
+static ERASE_STARTED: AtomicU32 = AtomicU32::new(0);
+
+/// Observed page erase times.
+pub static ERASE_TIME: Observed = Observed::new();
+/// Observed times to program one transfer.
+pub static PROGRAM_TIME: Observed = Observed::new();
+
+/// Shortest and longest of a measured duration, for checking the timing the
+/// host is told to allow against real silicon.
+pub struct Observed {
+    min: AtomicU32,
+    max: AtomicU32,
+    count: AtomicU32,
+}
+
+impl Observed {
+    const fn new() -> Self {
+        Self {
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+            count: AtomicU32::new(0),
+        }
+    }
+
+    fn record(&self, us: u32) {
+        self.min.fetch_min(us, Ordering::Relaxed);
+        self.max.fetch_max(us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `[min_us, max_us, count]`, all zero until something is measured.
+    pub fn read(&self) -> [u32; 3] {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return [0; 3];
+        }
+
+        [
+            self.min.load(Ordering::Relaxed),
+            self.max.load(Ordering::Relaxed),
+            count,
+        ]
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.min.store(u32::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Microseconds since boot, at the monotonic's 100 µs resolution.
+fn now_us() -> u32 {
+    Mono::now().duration_since_epoch().to_micros() as u32
+}
+
+/// Wait for the flash controller to finish an operation, giving up after
+/// `limit_us`.
+///
+/// DFU requests are handled in the USB interrupt, so a controller that never
+/// finishes would otherwise hold it, and every lock it takes, until the
+/// watchdog resets the device part way through an update. Settings and OTP
+/// writes wait the same way.
+///
+/// The monotonic only advances with interrupts enabled, so in `init` this
+/// waits for as long as the controller takes.
+pub fn wait_idle(f: &RegisterBlock, limit_us: u32) -> Result<(), Error> {
+    let started = now_us();
+    while f.sr.read().bsy().bit_is_set() {
+        if now_us().wrapping_sub(started) > limit_us {
+            defmt::error!("Flash busy for over {} us", limit_us);
+            return Err(Error::FlashBusy);
+        }
+    }
+
+    Ok(())
+}
+
+/// Record the time a page erase took. Called from the flash interrupt at
+/// the end of the erase.
+///
+/// The erase runs in the background and the host only comes back after
+/// `ERASE_TIME_MS`, so this is the only way to see how long it really took.
+pub fn erase_complete() {
+    // SAFETY: the end of operation flag is write 1 to clear so writing
+    // only it doesn't disturb an operation in progress.
+    let flash = unsafe { &*FLASH::ptr() };
+    flash.sr.write(|w| unsafe { w.bits(SR_EOP) });
+
+    let started = ERASE_STARTED.load(Ordering::Relaxed);
+    ERASE_TIME.record(now_us().wrapping_sub(started));
+}
+
+/// Check if a page erase started by a firmware update is still pending.
+///
+/// Other flash operations must wait until it's finished.
+pub fn erase_pending() -> bool {
+    ERASING.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 #[repr(u8)]
@@ -23,10 +165,42 @@ pub enum Bank {
     Bank2 = 1,
 }
 
+impl Bank {
+    /// The other bank.
+    pub fn other(self) -> Self {
+        match self {
+            Bank::Bank1 => Bank::Bank2,
+            Bank::Bank2 => Bank::Bank1,
+        }
+    }
+
+    /// Page erase bank selection (`FLASH_CR.BKER`).
+    ///
+    /// This selects a physical bank, regardless of which bank is mapped at
+    /// the start of flash, so it must be derived from the active bank.
+    pub fn erase_selection(self) -> u32 {
+        match self {
+            Bank::Bank1 => 0,
+            Bank::Bank2 => CR_BKER,
+        }
+    }
+}
+
+/// Get the bank the firmware is running from.
+pub fn active_bank(flash: &RegisterBlock) -> Bank {
+    let bank = (flash.optr.read().bits() & OPTR_BFB2) != 0;
+    match bank {
+        false => Bank::Bank1,
+        true => Bank::Bank2,
+    }
+}
+
 pub struct DfuFlash {
     /// Write buffer. Size of flash page.
     buffer: [u8; 2048],
     flash: FLASH,
+    /// Bytes from the start of the bank covered by this download.
+    image_len: u32,
 }
 
 impl DfuFlash {
@@ -34,37 +208,49 @@ impl DfuFlash {
         let mut this = Self {
             buffer: [0; 2048],
             flash,
+            image_len: 0,
         };
 
-        this.enable_dual_bank();
+        if let Err(e) = this.enable_dual_bank() {
+            defmt::error!("Failed to enable dual bank mode: {}", e);
+        }
 
         let active = this.active_bank();
         defmt::info!("Active flash bank: {}", active);
 
+        let flash_size_kb = chip::flash_size_kb();
+        if flash_size_kb != FLASH_SIZE_KB {
+            defmt::error!(
+                "Flash size {}K doesn't match firmware built for {}K",
+                flash_size_kb,
+                FLASH_SIZE_KB
+            );
+        }
+
         this
     }
 
-    fn unlock<F, T>(&mut self, f: F) -> T
+    fn unlock<F, T>(&mut self, f: F) -> Result<T, Error>
     where
         F: FnOnce(&mut FLASH, &mut [u8]) -> T,
     {
         self.flash.keyr.write(|w| unsafe { w.bits(KEY[0]) });
         self.flash.keyr.write(|w| unsafe { w.bits(KEY[1]) });
 
-        // Flash should unlock on first try. If not we are in an unrecoverable
-        // state.
+        // Flash should unlock on first try. If not, leave it alone rather
+        // than write to locked flash.
         if self.flash.cr.read().lock().bit() {
-            panic!("Flash is still locked");
+            return Err(Error::FlashLocked);
         }
 
         let result = f(&mut self.flash, &mut self.buffer);
 
         self.flash.cr.modify(|_, w| w.lock().set_bit());
 
-        result
+        Ok(result)
     }
 
-    fn opt_unlock<F, T>(&mut self, f: F) -> T
+    fn opt_unlock<F, T>(&mut self, f: F) -> Result<T, Error>
     where
         F: FnOnce(&mut FLASH) -> T,
     {
@@ -72,36 +258,151 @@ impl DfuFlash {
             flash.optkeyr.write(|w| unsafe { w.bits(OPT_KEY[0]) });
             flash.optkeyr.write(|w| unsafe { w.bits(OPT_KEY[1]) });
 
-            // Flash options should unlock on first try. If not we are in an
-            // unrecoverable state.
+            // Flash options should unlock on first try.
             if flash.cr.read().optlock().bit() {
-                panic!("Flash opt is still locked");
+                return Err(Error::FlashLocked);
             }
 
             let result = f(flash);
 
             flash.cr.modify(|_, w| w.optlock().set_bit());
 
-            result
-        })
+            Ok(result)
+        })?
     }
 
-    /// Enable dual bank flash mode.
-    pub fn enable_dual_bank(&mut self) {
-        self.opt_unlock(|f| {
-            f.optr
-                .modify(|r, w| unsafe { w.bits(r.bits() | OPTR_DBANK) });
+    /// Start erasing a page without waiting for it to finish.
+    ///
+    /// An erase takes around 20 ms, during which the USB interrupt would
+    /// otherwise block the CAN interrupts and let their RX FIFOs overrun.
+    /// The flash controller erases in the background while the device keeps
+    /// running from the other bank. Flash is left unlocked until
+    /// [`Self::finish_erase`] is called before the next flash operation,
+    /// which the host delays by `ERASE_TIME_MS`.
+    fn start_erase(&mut self, bank: Bank, sector: u8) -> Result<(), Error> {
+        let f = &mut self.flash;
+
+        f.keyr.write(|w| unsafe { w.bits(KEY[0]) });
+        f.keyr.write(|w| unsafe { w.bits(KEY[1]) });
+
+        if f.cr.read().lock().bit() {
+            return Err(Error::FlashLocked);
+        }
 
-            f.cr.modify(|_, w| w.optstrt().set_bit());
+        // clear errors and any existing operations
+        f.sr.write(|w| unsafe { w.bits(SR_ERRORS) });
+        f.cr.modify(|_, w| unsafe { w.bits(0) });
 
-            // wait while busy
-            while f.sr.read().bsy().bit_is_set() {}
+        f.cr.modify(|_, w| unsafe {
+            w.bits(bank.erase_selection() | CR_EOPIE)
+                .pnb()
+                .bits(sector)
+                .per()
+                .set_bit()
         });
+
+        ERASING.store(true, Ordering::Relaxed);
+        ERASE_STARTED.store(now_us(), Ordering::Relaxed);
+        f.cr.modify(|_, w| w.strt().set_bit());
+
+        Ok(())
+    }
+
+    /// Wait for a page erase started by [`Self::start_erase`] and lock
+    /// flash again.
+    fn finish_erase(&mut self) -> Result<(), Error> {
+        // Settings are written from a lower priority task, which may have
+        // been interrupted part way through.
+        if crate::settings::writing() {
+            return Err(Error::FlashBusy);
+        }
+
+        if !ERASING.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let f = &mut self.flash;
+
+        // Left pending on timeout so the next operation waits again.
+        wait_idle(f, PAGE_ERASE_US * BUSY_MARGIN)?;
+
+        let errors = f.sr.read().bits() & SR_ERRORS;
+
+        // remove page erase operation bit and the interrupt timing it
+        f.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_EOPIE) });
+        f.cr.modify(|_, w| w.per().clear_bit());
+        f.cr.modify(|_, w| w.lock().set_bit());
+        ERASING.store(false, Ordering::Relaxed);
+
+        if errors != 0 {
+            defmt::error!("Page erase failed: sr={=u32:#x}", errors);
+            return Err(Error::Erase);
+        }
+
+        Ok(())
+    }
+
+    /// Set and clear bits of the user option bytes.
+    ///
+    /// The stored option bytes are read back after programming, as `OPTR`
+    /// only shows what was written until the option bytes are next loaded.
+    /// Programming is retried once on a mismatch. If it still fails `OPTR`
+    /// is restored so the option bytes that are loaded on the next reset
+    /// are unchanged.
+    fn program_options(&mut self, set: u32, clear: u32) -> Result<(), Error> {
+        let mask = set | clear;
+
+        self.opt_unlock(|f| {
+            let original = f.optr.read().bits();
+            let target = (original | set) & !clear;
+
+            // SAFETY: read-only system memory.
+            let stored = unsafe { core::ptr::read_volatile(OPTION_BYTES) };
+            if (stored ^ target) & mask == 0 {
+                return Ok(());
+            }
+
+            for attempt in 1..=OPTION_ATTEMPTS {
+                // clear errors left by earlier operations.
+                f.sr.write(|w| unsafe { w.bits(SR_ERRORS) });
+
+                f.optr.write(|w| unsafe { w.bits(target) });
+                f.cr.modify(|_, w| w.optstrt().set_bit());
+
+                // Programming erases the option byte page first.
+                wait_idle(f, PAGE_ERASE_US * BUSY_MARGIN)?;
+
+                let errors = f.sr.read().bits() & SR_ERRORS;
+                // SAFETY: read-only system memory.
+                let stored = unsafe { core::ptr::read_volatile(OPTION_BYTES) };
+                if errors == 0 && (stored ^ target) & mask == 0 {
+                    defmt::info!("Option bytes programmed: {=u32:#x}", target);
+                    return Ok(());
+                }
+
+                defmt::warn!(
+                    "Option bytes attempt {} failed: sr={=u32:#x} ob={=u32:#x}",
+                    attempt,
+                    errors,
+                    stored
+                );
+            }
+
+            f.optr.write(|w| unsafe { w.bits(original) });
+            defmt::error!("Option bytes not programmed");
+
+            Err(Error::OptionBytes)
+        })?
+    }
+
+    /// Enable dual bank flash mode.
+    pub fn enable_dual_bank(&mut self) -> Result<(), Error> {
+        self.program_options(OPTR_DBANK, 0)
     }
 
     fn sector_from_address(&mut self, address: u32) -> Option<u8> {
-        let base = 0x0800_0000;
-        let sector_size = 2048;
+        let base = FLASH_BASE;
+        let sector_size = PAGE_SIZE;
 
         // Ensure address is within range
         if address < base {
@@ -117,7 +418,7 @@ impl DfuFlash {
         let sector = (address - base) / sector_size;
 
         // Verify sector is within valid range
-        if sector <= 127 {
+        if sector < BANK_PAGES {
             Some(sector as u8)
         } else {
             None
@@ -126,51 +427,118 @@ impl DfuFlash {
 
     /// Get active bank number.
     fn active_bank(&self) -> Bank {
-        let bank = (self.flash.optr.read().bits() & OPTR_BFB2) != 0;
-        match bank {
-            false => Bank::Bank1,
-            true => Bank::Bank2,
-        }
+        active_bank(&self.flash)
     }
 
-    #[allow(unused)]
+    /// Get the bank firmware updates are written to.
+    ///
+    /// Read from the option bytes every time rather than cached, so a
+    /// second update without a power cycle targets the right bank after the
+    /// first one swapped them.
     fn inactive_bank(&self) -> Bank {
+        self.active_bank().other()
+    }
+
+    /// Swap flash bank boot selection.
+    ///
+    /// The device keeps running from the current bank if the option bytes
+    /// can't be programmed.
+    pub fn swap_banks(&mut self) -> Result<(), Error> {
         match self.active_bank() {
-            Bank::Bank1 => Bank::Bank2,
-            Bank::Bank2 => Bank::Bank1,
+            Bank::Bank1 => self.program_options(OPTR_BFB2, 0)?,
+            Bank::Bank2 => self.program_options(0, OPTR_BFB2)?,
         }
+
+        // launch new firmware
+        self.opt_unlock(|f| f.cr.modify(|_, w| w.obl_launch().set_bit()))
     }
 
-    /// Swap flash bank boot selection.
-    fn swap_banks(&mut self) {
-        let bank = self.active_bank();
+    /// Program the write buffer to the inactive bank at `address` as
+    /// mapped in the active bank.
+    fn write_inactive(
+        &mut self,
+        address: u32,
+        length: usize,
+    ) -> Result<(), Error> {
+        // Checked here as well as by the callers as the write is translated
+        // into the other bank, where running past its end would go past the
+        // end of flash.
+        let padded = length.next_multiple_of(8);
+        let offset = address.checked_sub(FLASH_BASE).ok_or(Error::Address)?;
+        if offset as usize + padded > BANK_SIZE as usize {
+            defmt::error!("Write to {:#x} past the bank end", address);
+            return Err(Error::Address);
+        }
 
-        self.opt_unlock(|f| {
-            match bank {
-                Bank::Bank1 => f
-                    .optr
-                    .modify(|r, w| unsafe { w.bits(r.bits() | OPTR_BFB2) }),
-                Bank::Bank2 => f
-                    .optr
-                    .modify(|r, w| unsafe { w.bits(r.bits() & !OPTR_BFB2) }),
-            };
+        let address = address + BANK2_OFFSET;
 
-            f.cr.modify(|_, w| w.optstrt().set_bit());
+        // Writes may start mid page, e.g. resuming a download, but only into
+        // double words left blank by the page erase. Programming over data
+        // would corrupt it rather than replace it.
+        let target = unsafe {
+            core::slice::from_raw_parts(address as *const u8, padded)
+        };
+        if target.iter().any(|&b| b != 0xff) {
+            defmt::error!("Write to {:#x} over programmed flash", address);
+            return Err(Error::Occupied);
+        }
 
-            while f.sr.read().bsy().bit_is_set() {}
+        let started = now_us();
 
-            // launch new firmware
-            f.cr.modify(|_, w| w.obl_launch().set_bit());
-        });
+        self.unlock(|f, buffer| {
+            let data = &mut buffer[..length];
+
+            for idx in (0..data.len()).step_by(8) {
+                let address1 = (address + idx as u32) as *mut u32;
+                let address2 = (address + 4 + idx as u32) as *mut u32;
+
+                let (word1, word2) = if idx + 8 > data.len() {
+                    // pad writes smaller than double word.
+                    let mut tmp_buffer = [0xff; 8];
+                    let remaining = data.len() - idx;
+                    tmp_buffer[..remaining].copy_from_slice(&data[idx..]);
+                    let tmp_dword = u64::from_le_bytes(tmp_buffer);
+                    (tmp_dword as u32, (tmp_dword >> 32) as u32)
+                } else {
+                    // convert 8 bytes into two 32-bit words
+                    let bytes1 = &data[idx..idx + 4];
+                    let bytes2 = &data[idx + 4..idx + 8];
+                    (
+                        u32::from_le_bytes(bytes1.try_into().unwrap()),
+                        u32::from_le_bytes(bytes2.try_into().unwrap()),
+                    )
+                };
+
+                f.cr.modify(|_, w| w.pg().set_bit());
+
+                wait_idle(f, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)?;
+
+                unsafe {
+                    core::ptr::write_volatile(address1, word1);
+                    core::ptr::write_volatile(address2, word2);
+                }
+            }
+
+            // wait for the last double word so it's included in the time
+            wait_idle(f, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)
+        })??;
+
+        PROGRAM_TIME.record(now_us().wrapping_sub(started));
+
+        Ok(())
     }
 }
 
 impl DfuMemory for DfuFlash {
-    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/128*2Kf";
+    const MEM_INFO_STRING: &'static str = MEM_INFO;
     const INITIAL_ADDRESS_POINTER: u32 = *FLASH_MEMORY.start();
-    const PROGRAM_TIME_MS: u32 = 3;
-    const ERASE_TIME_MS: u32 = 25;
-    const FULL_ERASE_TIME_MS: u32 = 25;
+    // Reported to the host as the poll timeout, so it waits for the
+    // operation to finish rather than asking again and timing out.
+    const PROGRAM_TIME_MS: u32 = (Self::TRANSFER_SIZE as u32 / 8
+        * DOUBLE_WORD_PROGRAM_US)
+        .div_ceil(1000);
+    const ERASE_TIME_MS: u32 = PAGE_ERASE_US.div_ceil(1000);
+    const FULL_ERASE_TIME_MS: u32 = Self::ERASE_TIME_MS;
     const TRANSFER_SIZE: u16 = 64;
     const MANIFESTATION_TOLERANT: bool = false;
 
@@ -179,7 +547,11 @@ impl DfuMemory for DfuFlash {
         address: u32,
         length: usize,
     ) -> Result<&[u8], DfuMemoryError> {
-        if !FLASH_MEMORY.contains(&address) {
+        // The whole read must be within flash, not just its start.
+        let Some(end) = address.checked_add(length as u32) else {
+            return Err(DfuMemoryError::Address);
+        };
+        if !FLASH_MEMORY.contains(&address) || end - 1 > *FLASH_MEMORY.end() {
             return Err(DfuMemoryError::Address);
         }
 
@@ -192,24 +564,22 @@ impl DfuMemory for DfuFlash {
             return Err(DfuMemoryError::Address);
         }
 
-        let sector = self.sector_from_address(address).unwrap();
-
-        self.unlock(|f, _| {
-            // clear any existing operations
-            f.cr.modify(|_, w| unsafe { w.bits(0) });
+        let sector = self.sector_from_address(address).ok_or(Error::Address)?;
 
-            f.cr.modify(|_, w| unsafe {
-                w.bits(CR_BKER).pnb().bits(sector).per().set_bit()
-            });
+        self.finish_erase()?;
 
-            f.cr.modify(|_, w| w.strt().set_bit());
+        let bank = self.inactive_bank();
 
-            // wait while busy
-            while f.sr.read().bsy().bit_is_set() {}
+        // A download starts by erasing the first page. Don't carry anything
+        // over from a previous session.
+        if address == *FLASH_MEMORY.start() {
+            defmt::info!("Firmware update started, writing {}", bank);
+            event::record(event::Kind::DfuStart, event::DEVICE, 0);
+            self.buffer.fill(0xff);
+            self.image_len = 0;
+        }
 
-            // remove page erase operation bit
-            f.cr.modify(|_, w| w.per().clear_bit());
-        });
+        self.start_erase(bank, sector)?;
 
         Ok(())
     }
@@ -237,50 +607,56 @@ impl DfuMemory for DfuFlash {
             return Err(DfuMemoryError::Address);
         }
 
-        // Always write to the inactive bank.
-        let address = address + BANK2_OFFSET;
-
-        self.unlock(|f, buffer| {
-            let data = &mut buffer[..length];
-
-            for idx in (0..data.len()).step_by(8) {
-                let address1 = (address + idx as u32) as *mut u32;
-                let address2 = (address + 4 + idx as u32) as *mut u32;
-
-                let (word1, word2) = if idx + 8 > data.len() {
-                    // pad writes smaller than double word.
-                    let mut tmp_buffer = [0xff; 8];
-                    let remaining = data.len() - idx;
-                    tmp_buffer[..remaining].copy_from_slice(&data[idx..]);
-                    let tmp_dword = u64::from_le_bytes(tmp_buffer);
-                    (tmp_dword as u32, (tmp_dword >> 32) as u32)
-                } else {
-                    // convert 8 bytes into two 32-bit words
-                    let bytes1 = &data[idx..idx + 4];
-                    let bytes2 = &data[idx + 4..idx + 8];
-                    (
-                        u32::from_le_bytes(bytes1.try_into().unwrap()),
-                        u32::from_le_bytes(bytes2.try_into().unwrap()),
-                    )
-                };
+        self.finish_erase()?;
 
-                f.cr.modify(|_, w| w.pg().set_bit());
+        if length > self.buffer.len() {
+            return Err(Error::PayloadSize.into());
+        }
+        if address + length as u32 - 1 > *FLASH_MEMORY.end() {
+            return Err(DfuMemoryError::Address);
+        }
 
-                // wait while busy
-                while f.sr.read().bsy().bit_is_set() {}
+        // Flash is programmed a double word at a time and each double word
+        // only once per erase. A write starting mid double word would pad
+        // over, and so clobber, the bytes before it.
+        if address % 8 != 0 {
+            defmt::error!("Write to {:#x} not double word aligned", address);
+            return Err(Error::Address.into());
+        }
 
-                unsafe {
-                    core::ptr::write_volatile(address1, word1);
-                    core::ptr::write_volatile(address2, word2);
-                }
-            }
-        });
+        let end = address + length as u32 - FLASH_BASE;
+        if end > image::RECORD_OFFSET {
+            defmt::error!("Write to {:#x} past the image", address);
+            return Err(Error::Address.into());
+        }
+        self.image_len = self.image_len.max(end);
 
-        Ok(())
+        Ok(self.write_inactive(address, length)?)
     }
 
     fn manifestation(&mut self) -> Result<(), DfuManifestationError> {
-        self.swap_banks();
+        self.finish_erase()?;
+
+        // Record the new image so a corrupt copy isn't booted. The record
+        // page isn't part of the download so it's erased here.
+        if self.image_len == 0 {
+            defmt::error!("Nothing downloaded");
+            return Err(DfuManifestationError::Unknown);
+        }
+        let record = Record::for_inactive(self.image_len);
+        self.start_erase(self.inactive_bank(), image::RECORD_PAGE as u8)?;
+        self.finish_erase()?;
+        let len = core::mem::size_of::<Record>();
+        self.buffer[..len].copy_from_slice(record.as_bytes());
+        self.write_inactive(FLASH_BASE + image::RECORD_OFFSET, len)?;
+
+        // Carry saved settings over to the new firmware. Losing them isn't a
+        // reason to abandon the update.
+        if let Err(e) = crate::settings::copy_to_inactive_bank(&self.flash) {
+            defmt::error!("Failed to copy settings: {}", e);
+        }
+
+        self.swap_banks()?;
 
         crate::hal::cortex_m::peripheral::SCB::sys_reset()
     }
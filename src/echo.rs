@@ -0,0 +1,143 @@
+//! gs_usb transmit echoes.
+//!
+//! The host expects every frame it sends over gs_usb to come back with the
+//! echo ID it was given once the frame is on the bus, to confirm the
+//! transmission and show local applications their own traffic. The gs_usb
+//! class leaves echoing to the firmware, so each frame is tagged with a
+//! marker indexing a table of the frames in flight. The controller records
+//! the marker in the TX event FIFO when the frame is sent, and the frame is
+//! then queued to go back to the host as its echo. See [`crate::marker`].
+//! A frame that fails or is cancelled gets no TX event, so it's echoed once
+//! its hardware buffer reports the failure, as is one dropped from a full
+//! transmit queue.
+//!
+//! Markers from [`MARKER_BASE`] up are used for echoes, so they never
+//! collide with markers the host gives frames sent with transmit marked.
+
+use crate::{
+    timestamp,
+    tx::{TxFrame, HARDWARE_BUFFERS, TX_QUEUE_LEN},
+};
+use heapless::Deque;
+use usbd_gscan::host::Frame;
+
+/// First marker used for echoes. Host markers must be below it.
+pub const MARKER_BASE: u8 = 0x80;
+/// Frames in flight per interface, enough for a full transmit queue and
+/// every hardware buffer. The Linux driver has at most 10.
+const SLOTS: usize = TX_QUEUE_LEN + HARDWARE_BUFFERS;
+
+pub struct Echoes {
+    /// Frames in flight, indexed by marker less [`MARKER_BASE`].
+    slots: [Option<Frame>; SLOTS],
+    /// Slot the next frame is given.
+    next: usize,
+    /// Echoes waiting to be sent to the host.
+    ready: Deque<Frame, SLOTS>,
+    /// Add the transmit time to echoes, as the host started the interface
+    /// with hardware timestamps.
+    pub timestamps: bool,
+}
+
+impl Default for Echoes {
+    fn default() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| None),
+            next: 0,
+            ready: Deque::new(),
+            timestamps: false,
+        }
+    }
+}
+
+impl Echoes {
+    /// Tag a frame from the host with a marker so it's echoed once sent.
+    ///
+    /// Slots are given out in turn, skipping those still in use. There's
+    /// one for every frame that can be queued or in the hardware, so if
+    /// none is free the frame in the next slot is long gone. It's echoed
+    /// now rather than never.
+    pub fn tag(&mut self, tx: &mut TxFrame, frame: &Frame) {
+        let index = (0..SLOTS)
+            .map(|n| (self.next + n) % SLOTS)
+            .find(|&index| self.slots[index].is_none())
+            .unwrap_or(self.next);
+        self.next = (index + 1) % SLOTS;
+
+        if let Some(stale) = self.slots[index].take() {
+            defmt::warn!("Echo {} never completed", MARKER_BASE + index as u8);
+            self.echo(stale);
+        }
+
+        self.slots[index] = Some(frame.clone());
+        tx.header.marker = Some(MARKER_BASE + index as u8);
+    }
+
+    /// Queue the echo of a sent frame, given the marker and transmit
+    /// timestamp from its TX event.
+    ///
+    /// Returns `false` if the marker isn't an echo's.
+    pub fn complete(&mut self, marker: u8, sent: u16) -> bool {
+        let Some(index) = marker.checked_sub(MARKER_BASE) else {
+            return false;
+        };
+
+        let slot = self.slots.get_mut(index as usize);
+        if let Some(mut frame) = slot.and_then(Option::take) {
+            if self.timestamps {
+                frame.set_timestamp(timestamp::extend(sent));
+            }
+            self.echo(frame);
+        }
+
+        true
+    }
+
+    /// Give up on a tagged frame, e.g. dropped from a full transmit queue,
+    /// and echo it now.
+    pub fn abandon(&mut self, marker: u8) {
+        let Some(index) = marker.checked_sub(MARKER_BASE) else {
+            return;
+        };
+
+        let slot = self.slots.get_mut(index as usize);
+        if let Some(frame) = slot.and_then(Option::take) {
+            self.echo(frame);
+        }
+    }
+
+    /// Give up on every tagged frame, e.g. when the interface faults and
+    /// its transmit queue is discarded, and echo them now.
+    pub fn abandon_all(&mut self) {
+        while let Some(frame) = self.slots.iter_mut().find_map(Option::take) {
+            self.echo(frame);
+        }
+    }
+
+    /// Queue an echo for a frame that won't be sent, so the host doesn't
+    /// wait for it.
+    pub fn echo(&mut self, frame: Frame) {
+        if self.ready.is_full() {
+            defmt::warn!("Echo queue full");
+            self.ready.pop_front();
+        }
+        // cannot fail as space was made above.
+        let _ = self.ready.push_back(frame);
+    }
+
+    /// Take the oldest echo waiting for the host.
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.ready.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    /// Forget every frame in flight and every echo not yet sent. The host
+    /// drops its own when the interface goes down.
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+        self.ready.clear();
+    }
+}
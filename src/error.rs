@@ -0,0 +1,53 @@
+//! Crate error type.
+
+use usbd_dfu::{DfuManifestationError, DfuMemoryError};
+
+/// Errors on paths the host can reach. These are logged and recovered from
+/// rather than panicking, so bad input from the host can't brick the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// Interface number not in use.
+    Interface(u8),
+    /// Bit timing outside the controller limits.
+    BitTiming,
+    /// Frame identifier out of range.
+    Id,
+    /// Address out of range or not aligned to a page.
+    Address,
+    /// Flash is still locked after writing the unlock keys.
+    FlashLocked,
+    /// Payload doesn't fit in the memory region.
+    PayloadSize,
+    /// Memory region is already programmed.
+    Occupied,
+    /// Option bytes didn't read back as programmed.
+    OptionBytes,
+    /// Flash controller reported an error erasing a page.
+    Erase,
+    /// Flash controller didn't finish an operation in time.
+    FlashBusy,
+    /// No transceiver standby pin is connected for the interface.
+    NoStandbyPin(u8),
+    /// FD frame for an interface started without FD.
+    FdDisabled(u8),
+    /// Frame for an interface started listen-only.
+    ListenOnly(u8),
+    /// Remote frame flagged as FD, which the format doesn't allow.
+    RemoteFd,
+}
+
+impl From<Error> for DfuMemoryError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Address => DfuMemoryError::Address,
+            Error::Occupied => DfuMemoryError::CheckErased,
+            _ => DfuMemoryError::Unknown,
+        }
+    }
+}
+
+impl From<Error> for DfuManifestationError {
+    fn from(_: Error) -> Self {
+        DfuManifestationError::Unknown
+    }
+}
@@ -0,0 +1,105 @@
+//! Recent event history.
+//!
+//! Significant events are kept in a small ring buffer in RAM so a snapshot
+//! of recent history can be read from a deployed unit over USB without a
+//! debug probe.
+//!
+//! Each event is 8 bytes, little-endian: `[time_ms: u32, kind: u8,
+//! interface: u8, value: u16]`. `time_ms` is milliseconds since boot and
+//! wraps after ~49 days. `interface` is [`DEVICE`] for device-wide events.
+
+use crate::{
+    hal::cortex_m::interrupt::{self, Mutex},
+    Mono,
+};
+use core::cell::RefCell;
+use heapless::HistoryBuffer;
+use rtic_monotonics::Monotonic;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Number of events kept.
+const LOG_LEN: usize = 32;
+/// Interface number used for device-wide events.
+pub const DEVICE: u8 = 0xff;
+
+static LOG: Mutex<RefCell<HistoryBuffer<Event, LOG_LEN>>> =
+    Mutex::new(RefCell::new(HistoryBuffer::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Kind {
+    /// Device booted. `value` is 1 if reset by the watchdog and 2 if by a
+    /// software reset.
+    Boot = 0,
+    /// Interface went bus-off. `value` is the consecutive failed recoveries.
+    BusOff = 1,
+    /// Interface faulted after repeated failed bus-off recoveries.
+    Fault = 2,
+    /// RX FIFO overrun. `value` is the FIFO number.
+    Overrun = 3,
+    /// Bit timing changed. `value` is 0 for nominal and 1 for data timing.
+    Reconfigure = 4,
+    /// Interface started by the host.
+    Start = 5,
+    /// Interface stopped by the host.
+    Stop = 6,
+    /// Loaded bus detected at boot.
+    LoadedBus = 7,
+    /// Firmware update started.
+    DfuStart = 8,
+    /// Controller entered restricted operation.
+    Restricted = 9,
+    /// Frames persistently not acknowledged, suggesting missing bus
+    /// termination. `value` is the consecutive error samples.
+    AckErrors = 10,
+    /// Interface entered error warning or error passive. `value` is 1 for
+    /// warning and 2 for passive.
+    ErrorState = 11,
+    /// Bus stuck dominant, suggesting CANH shorted to CANL. `value` is the
+    /// consecutive error samples.
+    StuckDominant = 12,
+}
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Event {
+    pub time_ms: u32,
+    pub kind: u8,
+    pub interface: u8,
+    pub value: u16,
+}
+
+/// Record an event.
+pub fn record(kind: Kind, interface: u8, value: u16) {
+    let event = Event {
+        time_ms: Mono::now().duration_since_epoch().to_millis() as u32,
+        kind: kind as u8,
+        interface,
+        value,
+    };
+
+    interrupt::free(|cs| LOG.borrow(cs).borrow_mut().write(event));
+}
+
+/// Copy events, oldest first, skipping the first `skip`.
+///
+/// Returns the number of bytes written.
+pub fn read(skip: usize, buf: &mut [u8]) -> usize {
+    interrupt::free(|cs| {
+        let log = LOG.borrow(cs).borrow();
+        let events = log.oldest_ordered().skip(skip);
+
+        let mut len = 0;
+        for (chunk, event) in buf.chunks_exact_mut(8).zip(events) {
+            chunk.copy_from_slice(event.as_bytes());
+            len += 8;
+        }
+
+        len
+    })
+}
+
+/// Discard all events.
+pub fn clear() {
+    interrupt::free(|cs| LOG.borrow(cs).borrow_mut().clear());
+}
@@ -0,0 +1,201 @@
+//! Recently transmitted frames.
+//!
+//! A small ring of the frames most recently handed to each controller, with
+//! whether they were acknowledged, so a user can confirm what actually went
+//! on the wire rather than what the host intended.
+//!
+//! Each record is 12 bytes, little-endian: `[time_us: u32, id: u32, len: u8,
+//! flags: u8, status: u8, buffer: u8]`. `time_us` is microseconds since boot
+//! and wraps after ~71 minutes. Bit 31 of `id` marks an extended identifier.
+//! `flags` are gs_usb frame flags, `status` a [`Status`] and `buffer` the
+//! hardware transmit buffer used.
+
+use crate::{
+    hal::stm32::{FDCAN1, FDCAN2, FDCAN3},
+    tx::TxFrame,
+    Mono,
+};
+use fdcan::{frame::FrameFormat, id::Id};
+use heapless::Deque;
+use rtic_monotonics::Monotonic;
+use usbd_gscan::host::FrameFlag;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Number of frames kept per interface.
+const HISTORY_LEN: usize = 8;
+/// Extended identifier flag in a record.
+const ID_EXTENDED: u32 = 1 << 31;
+/// Record size in bytes.
+pub const RECORD_SIZE: usize = 12;
+/// `TXFQS` transmit FIFO/queue full flag.
+const TXFQS_TFQF: u32 = 1 << 21;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Status {
+    /// Waiting in a hardware transmit buffer.
+    Pending = 0,
+    /// Transmitted and acknowledged.
+    Acknowledged = 1,
+    /// Transmission failed, e.g. not acknowledged or arbitration lost, and
+    /// wasn't retried.
+    Failed = 2,
+    /// Taken back out of the hardware buffer for a higher priority frame
+    /// before it was sent, and queued again.
+    Replaced = 3,
+}
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Record {
+    pub time_us: u32,
+    pub id: u32,
+    pub len: u8,
+    pub flags: u8,
+    pub status: u8,
+    pub buffer: u8,
+}
+
+/// Hardware transmit buffer state of a controller.
+pub struct Buffers {
+    /// Buffer the next frame is written to.
+    pub put: u8,
+    /// Every buffer has a pending request.
+    pub full: bool,
+    /// Buffers whose last transmission succeeded.
+    pub occurred: u32,
+    /// Buffers whose last transmission failed or was cancelled.
+    pub cancelled: u32,
+}
+
+impl Buffers {
+    /// Read the transmit buffer state of an interface.
+    pub fn read(interface: u8) -> Self {
+        // SAFETY: read-only access to registers with no side effects.
+        let (txfqs, occurred, cancelled) = match interface {
+            0 => {
+                let regs = unsafe { &*FDCAN2::ptr() };
+                (
+                    regs.txfqs.read().bits(),
+                    regs.txbto.read().bits(),
+                    regs.txbcf.read().bits(),
+                )
+            }
+            1 => {
+                let regs = unsafe { &*FDCAN3::ptr() };
+                (
+                    regs.txfqs.read().bits(),
+                    regs.txbto.read().bits(),
+                    regs.txbcf.read().bits(),
+                )
+            }
+            _ => {
+                let regs = unsafe { &*FDCAN1::ptr() };
+                (
+                    regs.txfqs.read().bits(),
+                    regs.txbto.read().bits(),
+                    regs.txbcf.read().bits(),
+                )
+            }
+        };
+
+        Self {
+            put: ((txfqs >> 16) & 0x3) as u8,
+            full: txfqs & TXFQS_TFQF != 0,
+            occurred,
+            cancelled,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TxHistory {
+    records: Deque<Record, HISTORY_LEN>,
+}
+
+impl TxHistory {
+    /// Record a frame handed to `buffer`.
+    ///
+    /// If the buffers were full and the frame `replaced` a pending one, the
+    /// lowest priority pending record is updated and its buffer is used
+    /// instead, as that's the frame the controller gives up.
+    pub fn record(&mut self, frame: &TxFrame, mut buffer: u8, replaced: bool) {
+        if replaced {
+            let pending = self
+                .records
+                .iter_mut()
+                .filter(|r| r.status == Status::Pending as u8)
+                .max_by_key(|r| r.id);
+            if let Some(record) = pending {
+                record.status = Status::Replaced as u8;
+                buffer = record.buffer;
+            }
+        }
+
+        let mut flags = FrameFlag::empty();
+        if frame.header.frame_format == FrameFormat::Fdcan {
+            flags |= FrameFlag::FD;
+        }
+        if frame.header.bit_rate_switching {
+            flags |= FrameFlag::BIT_RATE_SWITCH;
+        }
+
+        if self.records.is_full() {
+            self.records.pop_front();
+        }
+
+        // cannot fail as space was made above.
+        let _ = self.records.push_back(Record {
+            time_us: Mono::now().duration_since_epoch().to_micros() as u32,
+            id: encode_id(frame.header.id),
+            len: frame.header.len,
+            flags: flags.bits(),
+            status: Status::Pending as u8,
+            buffer,
+        });
+    }
+
+    /// Update pending records from the hardware transmit buffer state.
+    pub fn resolve(&mut self, buffers: &Buffers) {
+        for record in self.records.iter_mut() {
+            if record.status != Status::Pending as u8 {
+                continue;
+            }
+
+            let mask = 1 << record.buffer;
+            if buffers.occurred & mask != 0 {
+                record.status = Status::Acknowledged as u8;
+            } else if buffers.cancelled & mask != 0 {
+                record.status = Status::Failed as u8;
+            }
+        }
+    }
+
+    /// Copy records, oldest first.
+    ///
+    /// Returns the number of bytes written.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (chunk, record) in
+            buf.chunks_exact_mut(RECORD_SIZE).zip(self.records.iter())
+        {
+            chunk.copy_from_slice(record.as_bytes());
+            len += RECORD_SIZE;
+        }
+
+        len
+    }
+
+    /// Discard all records.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+/// Encode an identifier as in a record.
+fn encode_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | ID_EXTENDED,
+    }
+}
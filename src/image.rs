@@ -0,0 +1,128 @@
+//! Firmware image integrity.
+//!
+//! A firmware update records the length and CRC of the new image in the
+//! page before the settings pages, which `memory.x` keeps out of the image.
+//! On boot the running image is checked against its record before anything
+//! else relies on it. If it doesn't match, the other bank is booted if it
+//! holds an image that does, otherwise the STM32 system bootloader is
+//! entered so the device can be reflashed over USB DFU.
+//!
+//! Images programmed with a debug probe have no record and aren't checked.
+//! The check only reads flash, so it can't make a good image unbootable
+//! unless a good image has a record that doesn't match it.
+
+use crate::{
+    dfu::{self, BANK2_OFFSET, BANK_PAGES, FLASH_BASE, PAGE_SIZE},
+    hal::stm32::FLASH,
+};
+use core::ops::Range;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Page holding the image record, the last page before the settings.
+pub const RECORD_PAGE: u32 = BANK_PAGES - 3;
+/// Offset of the image record in a bank.
+pub const RECORD_OFFSET: u32 = RECORD_PAGE * PAGE_SIZE;
+/// Marks a page holding an image record.
+const MAGIC: u32 = 0x4547_414d;
+/// Checksum over an image.
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+/// Addresses a valid initial stack pointer can take. It may point just past
+/// the end of RAM as the stack grows down.
+const RAM: Range<u32> = 0x2000_0001..0x2001_8001;
+/// STM32G4 system memory, holding the ROM bootloader.
+const SYSTEM_MEMORY: u32 = 0x1fff_0000;
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Record {
+    magic: u32,
+    /// Image length in bytes from the start of the bank.
+    len: u32,
+    crc: u32,
+    reserved: u32,
+}
+
+impl Record {
+    /// Record for an image in the inactive bank.
+    pub fn for_inactive(len: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            len,
+            crc: CRC.checksum(image(FLASH_BASE + BANK2_OFFSET, len)),
+            reserved: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Verdict {
+    /// The image matches its record.
+    Valid,
+    /// There's no record, e.g. the image was programmed with a debug probe.
+    Unchecked,
+    /// The image doesn't match its record or can't be booted.
+    Corrupt,
+}
+
+/// Check the image in the bank mapped at `base`.
+pub fn verify(base: u32) -> Verdict {
+    // SAFETY: reading flash within the bank.
+    let record = unsafe {
+        core::ptr::read_volatile((base + RECORD_OFFSET) as *const Record)
+    };
+
+    if record.magic != MAGIC {
+        return Verdict::Unchecked;
+    }
+
+    if record.len < 8 || record.len > RECORD_OFFSET || !bootable(base) {
+        return Verdict::Corrupt;
+    }
+
+    match CRC.checksum(image(base, record.len)) == record.crc {
+        true => Verdict::Valid,
+        false => Verdict::Corrupt,
+    }
+}
+
+/// Check the vector table points at RAM for the stack and into the image
+/// for the reset handler.
+fn bootable(base: u32) -> bool {
+    // SAFETY: reading the first two words of the bank.
+    let (sp, reset) = unsafe {
+        (
+            core::ptr::read_volatile(base as *const u32),
+            core::ptr::read_volatile((base + 4) as *const u32),
+        )
+    };
+
+    // Images are always linked to run from the start of flash.
+    let image = FLASH_BASE..FLASH_BASE + RECORD_OFFSET;
+    RAM.contains(&sp) && image.contains(&reset) && reset & 1 == 1
+}
+
+fn image(base: u32, len: u32) -> &'static [u8] {
+    // SAFETY: callers keep `len` within the bank.
+    unsafe { core::slice::from_raw_parts(base as *const u8, len as usize) }
+}
+
+/// Get off a corrupt image: boot the other bank if it holds a valid image,
+/// otherwise enter the system bootloader.
+///
+/// Called from `init` before the watchdog is started or interrupts are
+/// enabled.
+pub fn recover(flash: FLASH) -> ! {
+    if verify(FLASH_BASE + BANK2_OFFSET) == Verdict::Valid {
+        defmt::warn!("Booting the other bank");
+        // Only returns if the option bytes couldn't be programmed.
+        if let Err(e) = dfu::DfuFlash::new(flash).swap_banks() {
+            defmt::error!("Failed to swap banks: {}", e);
+        }
+    }
+
+    defmt::warn!("Entering the system bootloader");
+    // SAFETY: nothing has been set up that the bootloader relies on being
+    // in its reset state, and it never returns.
+    unsafe { crate::hal::cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32) }
+}
@@ -0,0 +1,172 @@
+//! USB to CAN latency measurement.
+//!
+//! While a test is running, frames the host sends on the interface over
+//! gs_usb are timestamped as they arrive and tagged with a marker, so the
+//! controller records when each one went on the bus in the TX event FIFO.
+//! One frame is measured at a time; frames arriving while one is in flight
+//! are sent as usual but not measured. Both timestamps are on the time base
+//! of [`crate::timestamp`], so the measurement covers USB handling, the
+//! software transmit queue, pacing and arbitration, as a real application
+//! would see it.
+//!
+//! Run it with the interface started in loopback mode for a figure that
+//! doesn't depend on other traffic.
+
+use crate::{timestamp, tx::TxFrame, Mono};
+use rtic_monotonics::Monotonic;
+
+/// Frames measured when the host doesn't ask for a number.
+pub const DEFAULT_SAMPLES: u16 = 32;
+/// Time after which a measured frame is given up on, e.g. because it was
+/// dropped from a full transmit queue. Shorter than the 65 ms timestamp
+/// wrap so a late completion can't be mistaken for a short one.
+const TIMEOUT_MS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum State {
+    /// No test has been run since boot.
+    Idle = 0,
+    /// Waiting for frames from the host.
+    Running = 1,
+    /// All requested frames have been measured.
+    Complete = 2,
+}
+
+/// Frame in flight.
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    marker: u8,
+    /// Arrival timestamp in microseconds.
+    arrived: u16,
+    /// Arrival time on the monotonic, for the timeout.
+    at_ms: u32,
+}
+
+pub struct LatencyTest {
+    state: State,
+    /// Frames still to be measured.
+    remaining: u16,
+    pending: Option<Pending>,
+    min: u16,
+    max: u16,
+    sum: u32,
+    samples: u32,
+    /// Measured frames whose completion was never seen.
+    lost: u32,
+}
+
+impl Default for LatencyTest {
+    fn default() -> Self {
+        Self {
+            state: State::Idle,
+            remaining: 0,
+            pending: None,
+            min: u16::MAX,
+            max: 0,
+            sum: 0,
+            samples: 0,
+            lost: 0,
+        }
+    }
+}
+
+impl LatencyTest {
+    /// Start measuring the next `samples` frames, discarding earlier
+    /// results.
+    pub fn start(&mut self, samples: u16) {
+        *self = Self {
+            state: State::Running,
+            remaining: samples,
+            ..Self::default()
+        };
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    /// Forget the frame in flight, e.g. when the interface is restarted and
+    /// its transmit queue discarded.
+    pub fn abandon(&mut self) {
+        if self.pending.take().is_some() {
+            self.lost += 1;
+        }
+    }
+
+    /// Measure a frame from the host if the test is waiting for one.
+    ///
+    /// The frame is identified by the marker it was tagged with for its
+    /// echo, see [`crate::echo`].
+    pub fn stamp(&mut self, frame: &TxFrame) {
+        let Some(marker) = frame.header.marker else {
+            return;
+        };
+        if !self.is_running() {
+            return;
+        }
+
+        let now_ms = Mono::now().duration_since_epoch().to_millis() as u32;
+        if let Some(pending) = self.pending {
+            if now_ms.wrapping_sub(pending.at_ms) < TIMEOUT_MS {
+                return;
+            }
+            self.abandon();
+        }
+
+        self.pending = Some(Pending {
+            marker,
+            arrived: timestamp::now(),
+            at_ms: now_ms,
+        });
+    }
+
+    /// Offer a TX event to the test.
+    ///
+    /// Returns `true` if it completed the frame in flight, in which case it
+    /// isn't a host marked completion.
+    pub fn complete(&mut self, marker: u8, sent: u16) -> bool {
+        match self.pending {
+            Some(pending) if pending.marker == marker => {
+                self.pending = None;
+                self.record(sent.wrapping_sub(pending.arrived));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, latency_us: u16) {
+        self.min = self.min.min(latency_us);
+        self.max = self.max.max(latency_us);
+        self.sum += latency_us as u32;
+        self.samples += 1;
+
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            self.state = State::Complete;
+            defmt::info!(
+                "Latency min={}us avg={}us max={}us",
+                self.min,
+                self.sum / self.samples,
+                self.max
+            );
+        }
+    }
+
+    /// Results as `[state, samples, lost, min_us, avg_us, max_us]`, with
+    /// the times zero until a frame has been measured.
+    pub fn read(&self) -> [u32; 6] {
+        match self.samples {
+            0 => [self.state as u32, 0, self.lost, 0, 0, 0],
+            n => [
+                self.state as u32,
+                n,
+                self.lost,
+                self.min as u32,
+                self.sum / n,
+                self.max as u32,
+            ],
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Host-bound frame rate limiting.
+//!
+//! Some embedded hosts can't keep up with a busy bus forwarded at full rate.
+//! Limiting the rate drops excess frames on the device, where they're
+//! counted, rather than letting them back up in the USB endpoint.
+
+use crate::tx::Instant;
+
+/// Frames allowed in a burst, as a fraction of the per second rate.
+const BURST_DIVISOR: u32 = 10;
+/// Credit for a single frame. Credit accrues at `rate` per microsecond.
+const FRAME_CREDIT: u64 = 1_000_000;
+
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    /// Frames per second. Zero disables the limit.
+    rate: u32,
+    /// Accrued credit for forwarding frames.
+    credit: u64,
+    /// Time credit was last added.
+    last: Option<Instant>,
+    /// Frames dropped by the limit.
+    pub dropped: u32,
+}
+
+impl RateLimit {
+    /// Frames per second, zero if unlimited.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Change the limit, starting with a full burst allowance.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+        self.credit = self.max_credit();
+        self.last = None;
+    }
+
+    /// Take credit for forwarding one frame.
+    ///
+    /// Returns `false`, counting the frame as dropped, if the limit has been
+    /// reached.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+
+        let elapsed =
+            self.last.and_then(|last| now.checked_duration_since(last));
+        if let Some(elapsed) = elapsed {
+            let earned = elapsed.to_micros() * self.rate as u64;
+            self.credit = (self.credit + earned).min(self.max_credit());
+        }
+        self.last = Some(now);
+
+        if self.credit < FRAME_CREDIT {
+            self.dropped = self.dropped.wrapping_add(1);
+            return false;
+        }
+
+        self.credit -= FRAME_CREDIT;
+        true
+    }
+
+    /// Credit for the largest burst.
+    fn max_credit(&self) -> u64 {
+        (self.rate / BURST_DIVISOR).max(1) as u64 * FRAME_CREDIT
+    }
+}
@@ -3,20 +3,41 @@
 #![feature(core_io_borrowed_buf)]
 
 mod can;
+mod capture;
+mod chip;
 mod dfu;
+mod echo;
+mod error;
+mod event;
+mod history;
+mod image;
+mod latency;
+mod limit;
+mod marker;
 mod otp;
+mod pattern;
+mod report;
+mod route;
+mod rx;
+mod settings;
+mod timestamp;
+mod transceiver;
+mod tx;
+#[cfg(feature = "persistent-timestamps")]
+mod uptime;
+mod vendor;
 mod vpd;
 
 use defmt_rtt as _;
-use nb::block;
 use panic_probe as _;
 use stm32g4xx_hal as hal;
 
-use can::id_to_embedded;
+use can::{id_to_embedded, Mode};
 use embedded_can::Frame;
 use fdcan::{
-    config::{FrameTransmissionConfig, Interrupt, Interrupts},
+    config::{FrameTransmissionConfig, Interrupt, Interrupts, TimestampSource},
     frame::FrameFormat,
+    ReceiveOverrun,
 };
 use fugit::ExtU32;
 use hal::{
@@ -41,13 +62,54 @@ use usb_device::{
     device::{StringDescriptors, UsbDevice, UsbDeviceBuilder},
 };
 use usbd_dfu::DfuClass;
-use usbd_gscan::{host::FrameFlag, GsCan};
+use usbd_gscan::{
+    host::{Feature, FrameFlag},
+    GsCan,
+};
+use vendor::Vendor;
 use vpd::VitalProductData;
 
+#[cfg(all(feature = "no-watchdog", not(debug_assertions)))]
+compile_error!(
+    "The `no-watchdog` feature is only allowed in builds with debug assertions."
+);
+
 systick_monotonic!(Mono, 10_000);
-defmt::timestamp!("{=u64:us}", Mono::now().duration_since_epoch().to_micros());
 
-#[rtic::app(device = stm32g4xx_hal::stm32, peripherals = true)]
+/// Transmit poll interval while no frame is paced, in case frames are left
+/// queued without a transmission completing to send them.
+const POLL_INTERVAL: u64 = 20;
+/// Longest USB serial number, the silicon unique ID as 24 hex digits.
+const SERIAL_LEN: usize = 24;
+#[cfg(not(feature = "persistent-timestamps"))]
+defmt::timestamp!("{=u64:us}", Mono::now().duration_since_epoch().to_micros());
+#[cfg(feature = "persistent-timestamps")]
+defmt::timestamp!(
+    "{=u64:us}",
+    uptime::micros(Mono::now().duration_since_epoch().to_micros())
+);
+
+// Task priorities (higher preempts lower):
+//
+// 3. USB interrupts. The USB peripheral must be serviced promptly or the host
+//    sees NAKs and stalls the bulk endpoints, which is where frames are lost
+//    under load.
+// 2. FDCAN interrupts. Draining the RX FIFOs preempts background work so frames
+//    are moved out of the three-slot hardware FIFOs as soon as possible, but
+//    never preempts the USB interrupts that free up room for them on the host
+//    side.
+// 1. Background tasks such as feeding the watchdog. These are software tasks
+//    run from an otherwise unused interrupt.
+//
+// Note that tasks sharing `usb_dev` and `usb_can` run at the ceiling
+// priority while holding the lock so critical sections should be kept short.
+// The DFU class is polled with them, so flash page erases are left running
+// in the background rather than waited on (see `dfu::DfuFlash`).
+#[rtic::app(
+    device = stm32g4xx_hal::stm32,
+    peripherals = true,
+    dispatchers = [SAI]
+)]
 mod app {
     use super::*;
 
@@ -60,25 +122,38 @@ mod app {
 
     #[shared]
     struct Shared {
-        _vpd: vpd::VitalProductData,
+        vpd: vpd::Assembly,
         usb_dev: UsbDevice<'static, Usb>,
         usb_can: usbd_gscan::GsCan<'static, Usb, can::UsbCanDevice>,
         usb_dfu: DfuClass<Usb, dfu::DfuFlash>,
+        vendor: vendor::State<Usb>,
     }
 
     #[local]
     struct Local {
+        #[cfg(not(feature = "no-watchdog"))]
         watchdog: IndependentWatchdog,
     }
 
     #[init]
     fn init(mut cx: init::Context) -> (Shared, Local) {
+        // Check the running image before relying on any more of it, while
+        // the clocks are still as the system bootloader expects them.
+        if image::verify(dfu::FLASH_BASE) == image::Verdict::Corrupt {
+            defmt::error!("Firmware image corrupt");
+            image::recover(cx.device.FLASH);
+        }
+
+        #[cfg(feature = "persistent-timestamps")]
+        let boots = uptime::restore();
+
         defmt::info!(
-            "name={} version={} git_hash={} built_at={}",
+            "name={} version={} git_hash={} built_at={} channel={}",
             env!("CARGO_PKG_NAME"),
             env!("CARGO_PKG_VERSION"),
             env!("CRATE_GIT_HASH"),
             env!("CRATE_BUILT_AT"),
+            env!("CRATE_RELEASE_CHANNEL"),
         );
 
         let pwr = cx
@@ -120,18 +195,38 @@ mod app {
             rcc.clocks.pll_clk.r.unwrap().to_MHz(),
         );
 
-        if rcc.get_reset_reason().independent_watchdog {
+        #[cfg(feature = "persistent-timestamps")]
+        defmt::info!("boots_since_power_on={}", boots);
+
+        let reset_reason = rcc.get_reset_reason();
+        let reset_flags = reset_flags(&reset_reason);
+        defmt::info!("reset_flags={:#04x}", reset_flags);
+        let reset_cause = if reset_reason.independent_watchdog {
             defmt::info!("reset_cause=watchdog");
-        }
+            1
+        } else if reset_reason.software {
+            defmt::info!("reset_cause=software");
+            2
+        } else {
+            0
+        };
         rcc.clear_reset_reason();
 
         Mono::start(cx.core.SYST, rcc.clocks.sys_clk.to_Hz());
+        event::record(event::Kind::Boot, event::DEVICE, reset_cause);
+
+        // Cycle counter used to timestamp synchronised transmissions.
+        cx.core.DCB.enable_trace();
+        cx.core.DWT.enable_cycle_counter();
 
+        #[cfg(not(feature = "no-watchdog"))]
         let watchdog = {
             let mut wd = IndependentWatchdog::new(cx.device.IWDG);
             wd.start(1_u32.secs());
             wd
         };
+        #[cfg(feature = "no-watchdog")]
+        defmt::warn!("Watchdog disabled.");
 
         if option_env!("WRITE_VPD").is_some() {
             let raw_vpd = include_bytes!(concat!(env!("OUT_DIR"), "/vpd.bin"));
@@ -142,7 +237,36 @@ mod app {
             }
         }
 
-        let vpd = VitalProductData::from_tlvc(otp::read()).unwrap();
+        // A corrupt VPD mustn't stop the device enumerating, or it couldn't
+        // be recovered over DFU. Fall back to defaults and identify the unit
+        // by its silicon unique ID instead.
+        let mut otp = vpd::Otp;
+        let mut corrupt: &[u8] =
+            include_bytes!(concat!(env!("OUT_DIR"), "/corrupt_vpd.bin"));
+        let mut blank: &[u8] = &[0xff; 1024];
+        let source: &mut dyn vpd::Source = match (
+            option_env!("TEST_CORRUPT_VPD"),
+            option_env!("TEST_BLANK_VPD"),
+        ) {
+            (Some(_), _) => &mut corrupt,
+            (None, Some(_)) => &mut blank,
+            (None, None) => &mut otp,
+        };
+        let (vpd, degraded) = match VitalProductData::read(source) {
+            Ok(Some(vpd)) => (vpd, false),
+            // first boot before provisioning.
+            Ok(None) => {
+                defmt::warn!("VPD not provisioned, running degraded");
+                (VitalProductData::default(), true)
+            }
+            Err(e) => {
+                defmt::error!(
+                    "VPD unreadable, running degraded: {}",
+                    defmt::Debug2Format(&e)
+                );
+                (VitalProductData::default(), true)
+            }
+        };
 
         defmt::info!(
             "serial={} hardware={} sku={}",
@@ -151,6 +275,9 @@ mod app {
             vpd.sku,
         );
 
+        // Common time base for receive timestamps on every interface.
+        timestamp::start(&cx.device.TIM3);
+
         let gpioa = cx.device.GPIOA.split(&mut rcc);
         let gpiob = cx.device.GPIOB.split(&mut rcc);
 
@@ -161,13 +288,19 @@ mod app {
             let mut can = cx.device.FDCAN2.fdcan(tx, rx, &rcc);
 
             can.set_protocol_exception_handling(false);
+            can.set_timestamp_counter_source(TimestampSource::FromTIM3);
             can.set_automatic_retransmit(false);
             can.set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS);
             can.enable_interrupts(
-                Interrupts::RX_FIFO0_NEW_MSG | Interrupts::RX_FIFO1_NEW_MSG,
+                Interrupts::RX_FIFO0_NEW_MSG
+                    | Interrupts::RX_FIFO1_NEW_MSG
+                    | Interrupts::TX_COMPLETE,
             );
+            tx::enable_complete_interrupt(0);
 
-            can.into_normal()
+            // left off the bus, with its interrupt lines disabled, until
+            // the host starts the interface.
+            can
         };
 
         let fdcan3 = {
@@ -177,15 +310,45 @@ mod app {
             let mut can = cx.device.FDCAN3.fdcan(tx, rx, &rcc);
 
             can.set_protocol_exception_handling(false);
+            can.set_timestamp_counter_source(TimestampSource::FromTIM3);
             can.set_automatic_retransmit(false);
             can.set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS);
             can.enable_interrupts(
-                Interrupts::RX_FIFO0_NEW_MSG | Interrupts::RX_FIFO1_NEW_MSG,
+                Interrupts::RX_FIFO0_NEW_MSG
+                    | Interrupts::RX_FIFO1_NEW_MSG
+                    | Interrupts::TX_COMPLETE,
             );
+            tx::enable_complete_interrupt(1);
 
-            can.into_normal()
+            // left off the bus, with its interrupt lines disabled, until
+            // the host starts the interface.
+            can
         };
 
+        // Only SKUs with a third interface route FDCAN1 to a header,
+        // elsewhere its pins are left alone.
+        let fdcan1 = (vpd.sku.interface_count() > 2).then(|| {
+            let rx = gpiob.pb8.into_alternate().set_speed(Speed::VeryHigh);
+            let tx = gpiob.pb9.into_alternate().set_speed(Speed::VeryHigh);
+
+            let mut can = cx.device.FDCAN1.fdcan(tx, rx, &rcc);
+
+            can.set_protocol_exception_handling(false);
+            can.set_timestamp_counter_source(TimestampSource::FromTIM3);
+            can.set_automatic_retransmit(false);
+            can.set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS);
+            can.enable_interrupts(
+                Interrupts::RX_FIFO0_NEW_MSG
+                    | Interrupts::RX_FIFO1_NEW_MSG
+                    | Interrupts::TX_COMPLETE,
+            );
+            tx::enable_complete_interrupt(2);
+
+            // left off the bus, with its interrupt lines disabled, until
+            // the host starts the interface.
+            can
+        });
+
         let usb = {
             let dm = gpioa.pa11.into_alternate();
             let dp = gpioa.pa12.into_alternate();
@@ -200,47 +363,89 @@ mod app {
             }))
         };
 
-        let usb_can = GsCan::new(
-            usb,
-            can::UsbCanDevice::new(
-                rcc.clocks.pll_clk.q.unwrap(),
-                fdcan2,
-                fdcan3,
-            ),
+        let mut device = can::UsbCanDevice::new(
+            rcc.clocks.pll_clk.q.unwrap(),
+            fdcan2,
+            fdcan3,
+            fdcan1,
         );
+        device.set_default_timing(vpd.bit_rate.nominal(), vpd.bit_rate.data());
+        device.set_channels(vpd.sku.interface_count());
+        device.transceivers = transceiver::Transceivers::new(&vpd.pins);
+        device.set_startup_delay((vpd.startup_delay.ms() as u64).millis());
+        device.power_on_self_test();
+        device.detect_loaded_bus();
+        if let Some(settings) = settings::load() {
+            defmt::info!("Restoring saved settings");
+            device.apply_settings(&settings);
+        }
+
+        let usb_can = GsCan::new(usb, device);
         let usb_dfu = DfuClass::new(usb, dfu::DfuFlash::new(cx.device.FLASH));
+        let mut vendor = vendor::State::new(usb, vpd.sku.interface_count());
+        vendor.reset_flags = reset_flags;
 
-        static SERIAL: static_cell::StaticCell<heapless::String<9>> =
+        static SERIAL: static_cell::StaticCell<heapless::String<SERIAL_LEN>> =
             static_cell::StaticCell::new();
         let serial = SERIAL.init(heapless::String::new());
-        core::fmt::write(serial, format_args!("{}", vpd.serial)).unwrap();
+        let [uid0, uid1, uid2] = chip::uid();
+        let truncated = match (option_env!("TEST_LONG_SERIAL"), degraded) {
+            (Some(long), _) => {
+                write_truncated(serial, format_args!("{}", long))
+            }
+            (None, true) => write_truncated(
+                serial,
+                format_args!("{:08X}{:08X}{:08X}", uid2, uid1, uid0),
+            ),
+            (None, false) => {
+                write_truncated(serial, format_args!("{}", vpd.serial))
+            }
+        };
+        if truncated {
+            defmt::warn!("Serial number truncated to {}", serial.as_str());
+        }
+        let product = match degraded {
+            true => "CAN FD Adapter",
+            false => "M.2 CAN FD Adapter",
+        };
 
         let usb_dev =
             UsbDeviceBuilder::new(usb, usbd_gscan::identifier::CANDLELIGHT)
                 .strings(&[StringDescriptors::default()
                     .manufacturer("Universal Machine Intelligence")
-                    .product("M.2 CAN FD Adapter")
+                    .product(product)
                     .serial_number(serial.as_str())])
                 .unwrap()
                 .device_class(usbd_gscan::INTERFACE_CLASS)
                 .build();
 
+        #[cfg(not(feature = "no-watchdog"))]
         watchdog::spawn().unwrap();
+        transmit::spawn().unwrap();
+        bus_monitor::spawn().unwrap();
+        #[cfg(feature = "persistent-timestamps")]
+        save_uptime::spawn().unwrap();
 
         defmt::info!("Init complete.");
 
         (
             Shared {
-                _vpd: vpd,
+                // No carrier board with its own product data exists yet.
+                vpd: vpd::Assembly::new(vpd, None),
                 usb_dev,
                 usb_can,
                 usb_dfu,
+                vendor,
+            },
+            Local {
+                #[cfg(not(feature = "no-watchdog"))]
+                watchdog,
             },
-            Local { watchdog },
         )
     }
 
-    #[task(local = [watchdog])]
+    #[cfg(not(feature = "no-watchdog"))]
+    #[task(local = [watchdog], priority = 1)]
     async fn watchdog(cx: watchdog::Context) {
         loop {
             // Feed watchdog periodically.
@@ -250,98 +455,445 @@ mod app {
         }
     }
 
-    #[task(binds = USB_HP, shared = [usb_dev, usb_can, usb_dfu])]
+    #[cfg(feature = "persistent-timestamps")]
+    #[task(priority = 1)]
+    async fn save_uptime(_: save_uptime::Context) {
+        loop {
+            let now = Mono::now().duration_since_epoch().to_millis();
+            uptime::save(now as u32);
+            Mono::delay((uptime::SAVE_INTERVAL_MS as u64).millis()).await;
+        }
+    }
+
+    #[task(shared = [usb_can], priority = 1)]
+    async fn transmit(mut cx: transmit::Context) {
+        let mut was_idle = false;
+
+        loop {
+            // Frames are sent as soon as they are received from the host,
+            // and the transmission completed interrupt refills the hardware
+            // buffers as they free up. This sends frames pacing held back
+            // and catches any left queued otherwise.
+            let (next, gap, idle) = cx.shared.usb_can.lock(|usb_can| {
+                let next = usb_can.device.transmit_queued();
+                (next, usb_can.device.min_tx_gap(), usb_can.device.is_idle())
+            });
+
+            if idle != was_idle {
+                defmt::debug!("idle={}", idle);
+                was_idle = idle;
+            }
+
+            // A frame paced after this can't be due sooner than the shortest
+            // gap from now, so waking that often never holds one back.
+            let now = Mono::now();
+            let poll = gap.map_or(now + POLL_INTERVAL.millis(), |gap| {
+                (now + gap).min(now + POLL_INTERVAL.millis())
+            });
+            Mono::delay_until(next.map_or(poll, |next| next.min(poll))).await;
+        }
+    }
+
+    #[task(shared = [usb_can, vendor, vpd], priority = 1)]
+    async fn bus_monitor(mut cx: bus_monitor::Context) {
+        let shared = &mut cx.shared;
+        loop {
+            // Reloaded from the USB interrupt, which can't take the lock.
+            let reloaded = shared.vendor.lock(|v| v.vpd_reload.take());
+            if let Some(module) = reloaded {
+                shared.vpd.lock(|vpd| vpd.module = module);
+            }
+
+            // Run without the lock so the USB interrupts are only held up by
+            // the flash itself.
+            let write = shared.vendor.lock(|v| v.settings_write.take());
+            if let Some(Err(e)) = write.map(settings::Write::run) {
+                defmt::error!("Settings not written: {}", e);
+            }
+
+            (&mut shared.usb_can, &mut shared.vendor).lock(
+                |usb_can, vendor| {
+                    // Reading the protocol status clears the last error code,
+                    // so errors are sampled before the
+                    // bus-off check reads it.
+                    usb_can.device.start_deferred();
+                    usb_can.device.sample_errors();
+                    usb_can.device.recover_bus_off();
+                    usb_can.device.check_restricted();
+                    usb_can.device.poll_presence();
+                    usb_can.device.poll_self_test();
+                    vendor.report.poll(&usb_can.device);
+
+                    // Deferred from the vendor request so its status stage is
+                    // sent before the device drops off the bus.
+                    if vendor.reset_pending {
+                        hal::cortex_m::peripheral::SCB::sys_reset();
+                    }
+                },
+            );
+            Mono::delay(100_u64.millis()).await;
+        }
+    }
+
+    #[task(
+        binds = USB_HP,
+        shared = [usb_dev, usb_can, usb_dfu, vendor],
+        priority = 3
+    )]
     fn usb_hp(cx: usb_hp::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can, cx.shared.usb_dfu).lock(
-            |usb_dev, usb_can, usb_dfu| {
-                usb_dev.poll(&mut [usb_can, usb_dfu]);
-            },
-        );
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_can,
+            cx.shared.usb_dfu,
+            cx.shared.vendor,
+        )
+            .lock(|usb_dev, usb_can, usb_dfu, vendor| {
+                vendor.usb_hp_interrupts =
+                    vendor.usb_hp_interrupts.wrapping_add(1);
+                usb_dev.poll(&mut [&mut Vendor::new(usb_can, vendor), usb_dfu]);
+            });
     }
 
-    #[task(binds = USB_LP, shared = [usb_dev, usb_can, usb_dfu])]
+    #[task(
+        binds = USB_LP,
+        shared = [usb_dev, usb_can, usb_dfu, vendor],
+        priority = 3
+    )]
     fn usb_lp(cx: usb_lp::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can, cx.shared.usb_dfu).lock(
-            |usb_dev, usb_can, usb_dfu| {
-                usb_dev.poll(&mut [usb_can, usb_dfu]);
-            },
-        );
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_can,
+            cx.shared.usb_dfu,
+            cx.shared.vendor,
+        )
+            .lock(|usb_dev, usb_can, usb_dfu, vendor| {
+                vendor.usb_lp_interrupts =
+                    vendor.usb_lp_interrupts.wrapping_add(1);
+                usb_dev.poll(&mut [&mut Vendor::new(usb_can, vendor), usb_dfu]);
+            });
+    }
+
+    /// End of a page erase started by a firmware update.
+    ///
+    /// Above the USB priority so the time is taken even while a DFU request
+    /// is waiting on the erase.
+    #[task(binds = FLASH, priority = 4)]
+    fn flash(_: flash::Context) {
+        dfu::erase_complete();
+    }
+
+    /// Receive timestamp timer wrap, extending it for the gs_usb timestamp
+    /// request. Above the USB priority so a request never misses a wrap.
+    #[task(binds = TIM3, priority = 4)]
+    fn timestamp_wrap(_: timestamp_wrap::Context) {
+        timestamp::wrapped();
     }
 
-    #[task(binds = FDCAN2_INTR0, shared = [usb_dev, usb_can])]
+    #[task(binds = FDCAN2_INTR0, shared = [usb_dev, usb_can], priority = 2)]
     fn fdcan2_it0(cx: fdcan2_it0::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can).lock(|usb_dev, usb_can| {
-            if let Some(can) = &mut usb_can.device.can1 {
-                if let Some(frame) = handle_fifo(can, false) {
-                    usb_can.transmit(0, &frame, frame.flags);
-                    usb_dev.poll(&mut [usb_can]);
-                }
-            }
-        });
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
     }
 
-    #[task(binds = FDCAN2_INTR1, shared = [usb_dev, usb_can])]
+    #[task(binds = FDCAN2_INTR1, shared = [usb_dev, usb_can], priority = 2)]
     fn fdcan2_it1(cx: fdcan2_it1::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can).lock(|usb_dev, usb_can| {
-            if let Some(can) = &mut usb_can.device.can1 {
-                if let Some(frame) = handle_fifo(can, true) {
-                    usb_can.transmit(0, &frame, frame.flags);
-                    usb_dev.poll(&mut [usb_can]);
-                }
-            }
-        });
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
     }
 
-    #[task(binds = FDCAN3_INTR0, shared = [usb_dev, usb_can])]
+    #[task(binds = FDCAN3_INTR0, shared = [usb_dev, usb_can], priority = 2)]
     fn fdcan3_it0(cx: fdcan3_it0::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can).lock(|usb_dev, usb_can| {
-            if let Some(can) = &mut usb_can.device.can2 {
-                if let Some(frame) = handle_fifo(can, false) {
-                    usb_can.transmit(1, &frame, frame.flags);
-                    usb_dev.poll(&mut [usb_can]);
-                }
-            }
-        });
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
     }
 
-    #[task(binds = FDCAN3_INTR1, shared = [usb_dev, usb_can])]
+    #[task(binds = FDCAN3_INTR1, shared = [usb_dev, usb_can], priority = 2)]
     fn fdcan3_it1(cx: fdcan3_it1::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_can).lock(|usb_dev, usb_can| {
-            if let Some(can) = &mut usb_can.device.can2 {
-                if let Some(frame) = handle_fifo(can, true) {
-                    usb_can.transmit(1, &frame, frame.flags);
-                    usb_dev.poll(&mut [usb_can]);
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
+    }
+
+    #[task(binds = FDCAN1_INTR0_IT, shared = [usb_dev, usb_can], priority = 2)]
+    fn fdcan1_it0(cx: fdcan1_it0::Context) {
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
+    }
+
+    #[task(binds = FDCAN1_INTR1_IT, shared = [usb_dev, usb_can], priority = 2)]
+    fn fdcan1_it1(cx: fdcan1_it1::Context) {
+        if (cx.shared.usb_dev, cx.shared.usb_can).lock(service) {
+            // Fails if it's already running, forwarding until empty.
+            let _ = forward_rest::spawn();
+        }
+    }
+
+    /// Refill the transmit buffers and move received frames to the host,
+    /// for any of the FDCAN interrupts.
+    ///
+    /// Returns `true` if received frames are left queued.
+    fn service(
+        usb_dev: &mut UsbDevice<'static, Usb>,
+        usb_can: &mut GsCan<'static, Usb, can::UsbCanDevice>,
+    ) -> bool {
+        usb_can.device.transmit_completed();
+        forward_received(usb_dev, usb_can)
+    }
+
+    /// Move received frames to the host.
+    ///
+    /// Every receive interrupt services the FIFOs of every interface, and
+    /// they're drained into the software queues again after each frame
+    /// forwarded, so a burst on any interface waits in the queues while
+    /// USB catches up rather than overrunning the hardware FIFOs. Frames
+    /// are taken from the interfaces in turn.
+    ///
+    /// At most a queue's worth of frames is forwarded per call so a
+    /// continuous burst doesn't hold the lock for long. Returns `true` if
+    /// frames are left queued, which [`forward_rest`] forwards.
+    fn forward_received(
+        usb_dev: &mut UsbDevice<'static, Usb>,
+        usb_can: &mut GsCan<'static, Usb, can::UsbCanDevice>,
+    ) -> bool {
+        drain_fifos(&mut usb_can.device);
+
+        let count = usb_can.device.interfaces.len() as u8;
+        let mut next = 0;
+        for _ in 0..rx::RX_QUEUE_DEPTH {
+            let Some((index, frame)) = usb_can.device.pop_received(next) else {
+                return false;
+            };
+            next = (index + 1) % count;
+
+            usb_can.transmit(index, &frame, frame.flags);
+            usb_dev.poll(&mut [usb_can]);
+            drain_fifos(&mut usb_can.device);
+        }
+
+        let interfaces = &usb_can.device.interfaces;
+        interfaces
+            .iter()
+            .any(|i| !i.rx.is_empty() || !i.echoes.is_empty())
+    }
+
+    /// Forward frames left queued by a receive interrupt.
+    ///
+    /// Runs below the receive interrupts and waits a monotonic tick between
+    /// each queue's worth, so a continuous burst can't starve the other
+    /// background tasks, the watchdog among them.
+    #[task(shared = [usb_dev, usb_can], priority = 1)]
+    async fn forward_rest(mut cx: forward_rest::Context) {
+        let shared = &mut cx.shared;
+        while (&mut shared.usb_dev, &mut shared.usb_can).lock(forward_received)
+        {
+            Mono::delay(100_u64.micros()).await;
+        }
+    }
+}
+
+/// Move every frame waiting in the hardware RX FIFOs of every interface to
+/// their software queues.
+///
+/// Only the frames present when each FIFO is checked are read, so a stopped
+/// interface, whose frames are left in the FIFO, can't hold this up.
+fn drain_fifos(device: &mut can::UsbCanDevice) {
+    for index in 0..device.interfaces.len() as u8 {
+        for fifo1 in [false, true] {
+            let level = rx::fill_level(index, fifo1);
+            let i = &mut device.interfaces[index as usize];
+            let peak = &mut i.counters.peak_fifo[fifo1 as usize];
+            *peak = (*peak).max(level);
+
+            for _ in 0..level {
+                let Some(frame) = ingest(device, index, fifo1) else {
+                    continue;
+                };
+
+                let i = &mut device.interfaces[index as usize];
+                if !i.rx.push(frame) {
+                    i.counters.overrun_queue += 1;
+                    i.session.overruns += 1;
                 }
             }
-        });
+        }
+    }
+}
+
+/// Every cause of the last reset as one bitmask, in the order of the
+/// `RCC_CSR` flags: bit 0 option byte load, 1 reset pin, 2 brown-out,
+/// 3 software, 4 independent watchdog, 5 window watchdog and 6 low power.
+fn reset_flags(reason: &hal::rcc::ResetReason) -> u32 {
+    [
+        reason.option_byte,
+        reason.reset_pin,
+        reason.brown_out,
+        reason.software,
+        reason.independent_watchdog,
+        reason.window_watchdog,
+        reason.low_power,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |flags, (bit, set)| flags | ((*set as u32) << bit))
+}
+
+/// Receive a frame from an interface, forward it according to the routing
+/// table and return it if it should go to the host.
+///
+/// Routing happens before the host-bound rate limit, so frames over the limit
+/// are still forwarded to other interfaces.
+fn ingest(
+    device: &mut can::UsbCanDevice,
+    index: u8,
+    fifo1: bool,
+) -> Option<usbd_gscan::host::Frame> {
+    let [i1, i2, i3] = &mut device.interfaces;
+    let frame = match index {
+        0 => receive(&mut device.can1, 0, i1, fifo1),
+        1 => receive(&mut device.can2, 1, i2, fifo1),
+        _ => receive(&mut device.can0, 2, i3, fifo1),
+    }?;
+
+    capture::record(index, &frame);
+    device.route(index, &frame);
+
+    let i = &mut device.interfaces[index as usize];
+    i.host_limit.allow(Mono::now()).then_some(frame)
+}
+
+/// Receive a frame from an interface if it's receiving.
+///
+/// Frames received in loopback mode are dropped if the interface suppresses
+/// them.
+fn receive<F: fdcan::Instance>(
+    can: &mut Option<Mode<F>>,
+    index: u8,
+    interface: &mut can::Interface,
+    fifo1: bool,
+) -> Option<usbd_gscan::host::Frame> {
+    match can {
+        Some(Mode::Normal(can)) => handle_fifo(can, index, fifo1, interface),
+        Some(Mode::ListenOnly(can)) => {
+            handle_fifo(can, index, fifo1, interface)
+        }
+        Some(Mode::Loopback(can)) => handle_fifo(can, index, fifo1, interface)
+            .filter(|_| !interface.suppress_loopback),
+        Some(_) => None,
+        // Only taken inside `reconfigure`, which holds the lock on the
+        // device, so this shouldn't be reachable. Count it if it is.
+        None => {
+            defmt::warn!("Interface {} received while reconfiguring", index);
+            interface.counters.rx_deferred += 1;
+            None
+        }
     }
 }
 
 /// Ingest the frame from the given FIFO queue.
-pub fn handle_fifo<F>(
-    can: &mut fdcan::FdCan<F, fdcan::NormalOperationMode>,
+pub fn handle_fifo<F, M>(
+    can: &mut fdcan::FdCan<F, M>,
+    index: u8,
     fifo1: bool,
+    interface: &mut can::Interface,
 ) -> Option<usbd_gscan::host::Frame>
 where
     F: fdcan::Instance,
+    M: fdcan::Receive,
 {
+    let counters = &mut interface.counters;
+    let session = &mut interface.session;
+    // Holds the longest payload any DLC encodes, so the driver's copy out of
+    // message RAM always fits.
     let mut data = [0; 64];
 
-    let (header, interrupt) = match fifo1 {
-        false => (
-            block!(can.receive0(&mut data)).unwrap().unwrap(),
-            Interrupt::RxFifo0NewMsg,
-        ),
-        true => (
-            block!(can.receive1(&mut data)).unwrap().unwrap(),
-            Interrupt::RxFifo1NewMsg,
-        ),
+    let (result, interrupt) = match fifo1 {
+        false => (can.receive0(&mut data), Interrupt::RxFifo0NewMsg),
+        true => (can.receive1(&mut data), Interrupt::RxFifo1NewMsg),
     };
 
     can.clear_interrupt(interrupt);
 
+    // Don't wait on an empty FIFO as that would stall the other interface
+    // and USB.
+    let result = match result {
+        Ok(result) => result,
+        Err(nb::Error::WouldBlock) => return None,
+        Err(nb::Error::Other(e)) => match e {},
+    };
+
+    let header = match result {
+        ReceiveOverrun::NoOverrun(header) => header,
+        ReceiveOverrun::Overrun(header) => {
+            match fifo1 {
+                false => counters.overrun_fifo0 += 1,
+                true => counters.overrun_fifo1 += 1,
+            }
+            session.overruns += 1;
+            defmt::warn!("RX FIFO{} overrun", fifo1 as u8);
+            event::record(event::Kind::Overrun, index, fifo1 as u16);
+            header
+        }
+    };
+    interface.rx_timestamp = timestamp::extend(header.time_stamp);
+
+    match header.filter_index {
+        Some(filter) => {
+            counters.filter_matched += 1;
+            counters.last_filter = Some(filter);
+        }
+        None => {
+            counters.filter_unmatched += 1;
+            counters.last_filter = None;
+        }
+    }
+
+    // The DLC can't encode more than 64 bytes, more than 8 in a classic
+    // frame or an FD length in between the steps, but don't trust a glitched
+    // length to index the buffer.
     let len = header.len as usize;
-    let id = id_to_embedded(header.id);
+    let valid_len = match header.frame_format {
+        FrameFormat::Fdcan => tx::fd_len(len.min(data.len())),
+        FrameFormat::Standard => len.min(8),
+    };
+    if len != valid_len {
+        defmt::warn!("Received length {} corrected to {}", len, valid_len);
+        counters.bad_length += 1;
+        counters.last_bad_length = header.len;
+    }
+    let len = valid_len;
+
+    let Some(id) = id_to_embedded(header.id) else {
+        defmt::warn!("Dropping received frame with invalid id");
+        let raw = match header.id {
+            fdcan::id::Id::Standard(id) => id.as_raw() as u32,
+            fdcan::id::Id::Extended(id) => id.as_raw() | can::ID_EXTENDED,
+        };
+        counters.reject_id(true, raw);
+        return None;
+    };
+
+    let fd = header.frame_format == FrameFormat::Fdcan;
+    if can::FrameKind::classify(fd, header.rtr) == can::FrameKind::Invalid {
+        defmt::warn!("Dropping received remote frame flagged as FD");
+        counters.fd_dropped += 1;
+        return None;
+    }
+
+    // Counted only now, as the frames dropped above have their own counters.
+    session.rx_frames += 1;
+    counters.rx_frames = counters.rx_frames.wrapping_add(1);
+    if !header.rtr {
+        counters.rx_bytes = counters.rx_bytes.wrapping_add(len as u32);
+    }
 
     let frame = if header.rtr {
         usbd_gscan::host::Frame::new_remote(id, len)
@@ -350,7 +902,7 @@ where
     };
 
     if let Some(mut frame) = frame {
-        if header.frame_format == FrameFormat::Fdcan {
+        if fd {
             frame.flags |= FrameFlag::FD;
         }
 
@@ -358,8 +910,48 @@ where
             frame.flags |= FrameFlag::BIT_RATE_SWITCH;
         }
 
+        // Only sent if the host started the interface with timestamps.
+        let features = interface.features.unwrap_or(Feature::empty());
+        if features.intersects(Feature::HW_TIMESTAMP) {
+            frame.set_timestamp(interface.rx_timestamp);
+        }
+
         Some(frame)
     } else {
         None
     }
 }
+
+/// String writer that keeps what fits and drops the rest.
+struct Truncating<'a, const N: usize> {
+    buf: &'a mut heapless::String<N>,
+    truncated: bool,
+}
+
+impl<const N: usize> core::fmt::Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.buf.push(c).is_err() {
+                self.truncated = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format into `buf`, cutting the result short if it doesn't fit.
+///
+/// Returns `true` if anything was cut.
+fn write_truncated<const N: usize>(
+    buf: &mut heapless::String<N>,
+    args: core::fmt::Arguments,
+) -> bool {
+    let mut writer = Truncating {
+        buf,
+        truncated: false,
+    };
+    // never fails as excess is dropped.
+    let _ = core::fmt::write(&mut writer, args);
+    writer.truncated
+}
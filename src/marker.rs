@@ -0,0 +1,183 @@
+//! Transmit completion markers.
+//!
+//! The host can tag a frame with an 8-bit marker. When the frame has been
+//! sent the controller stores the marker with its transmit timestamp in the
+//! TX event FIFO, which is collected here until the host reads it, so
+//! low-level tools can match requests to completions and measure latency.
+//!
+//! Each completion is 4 bytes, little-endian: `[marker: u8, reserved: u8,
+//! timestamp: u16]`. `timestamp` is on the time base shared with receive
+//! timestamps, see [`crate::timestamp`].
+
+use crate::{
+    echo::Echoes,
+    hal::stm32::{FDCAN1, FDCAN2, FDCAN3},
+    latency::LatencyTest,
+};
+use heapless::Deque;
+
+/// Message RAM of FDCAN1. Each instance has its own block after it.
+const SRAMCAN_BASE: usize = 0x4000_a400;
+/// Message RAM block size of one instance.
+const SRAMCAN_SIZE: usize = 0x350;
+/// Offset of the TX event FIFO in an instance's message RAM.
+const TX_EVENT_OFFSET: usize = 0x260;
+/// TX event FIFO element size in bytes.
+const TX_EVENT_SIZE: usize = 8;
+/// `TXEFS` event lost flag.
+const TXEFS_TEFL: u32 = 1 << 25;
+/// `IR` event lost flag, cleared by writing 1.
+const IR_TEFL: u32 = 1 << 12;
+/// Completions kept per interface until the host reads them.
+const COMPLETIONS_LEN: usize = 16;
+/// Completion size in bytes.
+pub const COMPLETION_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct Completion {
+    marker: u8,
+    timestamp: u16,
+}
+
+#[derive(Default)]
+pub struct Completions {
+    events: Deque<Completion, COMPLETIONS_LEN>,
+}
+
+impl Completions {
+    /// Move events out of the TX event FIFO of an interface.
+    ///
+    /// The FIFO only holds three events so this is called every time
+    /// queued frames are moved to the controller. The oldest completions
+    /// are dropped if the host doesn't read them. Events of frames measured
+    /// by `latency` go to it, and those of gs_usb frames to `echoes`,
+    /// instead.
+    pub fn collect(
+        &mut self,
+        interface: u8,
+        latency: &mut LatencyTest,
+        echoes: &mut Echoes,
+    ) {
+        let Some(instance) = Instance::new(interface) else {
+            return;
+        };
+
+        let status = instance.status();
+        if status & TXEFS_TEFL != 0 {
+            defmt::warn!("Interface {} TX events lost", interface);
+            instance.clear_lost();
+        }
+
+        for _ in 0..status & 0x7 {
+            let index = ((instance.status() >> 8) & 0x3) as usize;
+            let element = SRAMCAN_BASE
+                + instance.ram * SRAMCAN_SIZE
+                + TX_EVENT_OFFSET
+                + index * TX_EVENT_SIZE;
+            // Only the second word, with the marker and timestamp, is used.
+            let e1 = (element + 4) as *const u32;
+            // SAFETY: reading an element the controller has finished
+            // writing, as reported by the get index.
+            let e1 = unsafe { core::ptr::read_volatile(e1) };
+            let (marker, timestamp) = ((e1 >> 24) as u8, e1 as u16);
+            instance.acknowledge(index as u32);
+
+            // A measured frame is a gs_usb frame, so it's echoed too.
+            let measured = latency.complete(marker, timestamp);
+            if echoes.complete(marker, timestamp) || measured {
+                continue;
+            }
+
+            if self.events.is_full() {
+                self.events.pop_front();
+            }
+            // cannot fail as space was made above.
+            let _ = self.events.push_back(Completion { marker, timestamp });
+        }
+    }
+
+    /// Copy and remove completions, oldest first.
+    ///
+    /// Returns the number of bytes written.
+    pub fn take(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for chunk in buf.chunks_exact_mut(COMPLETION_SIZE) {
+            let Some(completion) = self.events.pop_front() else {
+                break;
+            };
+            chunk[0] = completion.marker;
+            chunk[1] = 0;
+            chunk[2..].copy_from_slice(&completion.timestamp.to_le_bytes());
+            len += COMPLETION_SIZE;
+        }
+
+        len
+    }
+
+    /// Discard all completions.
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// TX event FIFO registers of one controller.
+struct Instance {
+    interface: u8,
+    /// Index of the instance's message RAM block.
+    ram: usize,
+}
+
+impl Instance {
+    fn new(interface: u8) -> Option<Self> {
+        match interface {
+            0 => Some(Self { interface, ram: 1 }),
+            1 => Some(Self { interface, ram: 2 }),
+            2 => Some(Self { interface, ram: 0 }),
+            _ => None,
+        }
+    }
+
+    /// `TXEFS`.
+    fn status(&self) -> u32 {
+        // SAFETY: read-only access to a status register.
+        match self.interface {
+            0 => unsafe { &*FDCAN2::ptr() }.txefs.read().bits(),
+            1 => unsafe { &*FDCAN3::ptr() }.txefs.read().bits(),
+            _ => unsafe { &*FDCAN1::ptr() }.txefs.read().bits(),
+        }
+    }
+
+    /// Release the element at `index` back to the controller.
+    fn acknowledge(&self, index: u32) {
+        // SAFETY: the acknowledge register only affects the TX event FIFO,
+        // which nothing else reads.
+        match self.interface {
+            0 => unsafe { &*FDCAN2::ptr() }
+                .txefa
+                .write(|w| unsafe { w.bits(index) }),
+            1 => unsafe { &*FDCAN3::ptr() }
+                .txefa
+                .write(|w| unsafe { w.bits(index) }),
+            _ => unsafe { &*FDCAN1::ptr() }
+                .txefa
+                .write(|w| unsafe { w.bits(index) }),
+        }
+    }
+
+    /// Clear the event lost flag.
+    fn clear_lost(&self) {
+        // SAFETY: interrupt flags are write 1 to clear so only the event
+        // lost flag is affected.
+        match self.interface {
+            0 => unsafe { &*FDCAN2::ptr() }
+                .ir
+                .write(|w| unsafe { w.bits(IR_TEFL) }),
+            1 => unsafe { &*FDCAN3::ptr() }
+                .ir
+                .write(|w| unsafe { w.bits(IR_TEFL) }),
+            _ => unsafe { &*FDCAN1::ptr() }
+                .ir
+                .write(|w| unsafe { w.bits(IR_TEFL) }),
+        }
+    }
+}
@@ -1,7 +1,19 @@
-use crate::{dfu::KEY, hal::stm32::FLASH};
+use crate::{
+    dfu::{self, BUSY_MARGIN, DOUBLE_WORD_PROGRAM_US, KEY},
+    error::Error,
+    hal::stm32::{CRC, FLASH, RCC},
+};
 
-const OTP_LEN: usize = 1024; // 1 kilobyte
+pub const OTP_LEN: usize = 1024; // 1 kilobyte
 const OTP_ADDRESS: *const u8 = 0x1FFF7000 as *const u8;
+/// OTP is programmed a double-word at a time.
+const DOUBLE_WORD: usize = 8;
+/// Reset bit of `CRC_CR`, loading the initial value.
+const CR_RESET: u32 = 1 << 0;
+/// Input bit reversal by byte in `CRC_CR`.
+const CR_REV_IN_BYTE: u32 = 0b01 << 5;
+/// Output bit reversal bit of `CRC_CR`.
+const CR_REV_OUT: u32 = 1 << 7;
 
 /// Reads the 1 kilobyte of OTP memory.
 #[allow(unused)]
@@ -9,22 +21,64 @@ pub fn read() -> &'static [u8] {
     unsafe { core::slice::from_raw_parts(OTP_ADDRESS, OTP_LEN) }
 }
 
+/// CRC-32 of the first `len` bytes of OTP memory, computed by the CRC
+/// peripheral.
+///
+/// Configured to match the common CRC-32 (ISO-HDLC, as zlib and Python's
+/// `binascii.crc32`) so provisioning tools can compare it against the blob
+/// they wrote.
+pub fn checksum(len: usize) -> u32 {
+    // SAFETY: setting the CRC clock enable, which nothing else touches.
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.ahb1enr.modify(|_, w| w.crcen().set_bit());
+
+    // SAFETY: the CRC peripheral is only used here, from the USB interrupt.
+    let crc = unsafe { &*CRC::ptr() };
+    crc.pol.write(|w| unsafe { w.bits(0x04c1_1db7) });
+    crc.init.write(|w| unsafe { w.bits(0xffff_ffff) });
+    crc.cr
+        .write(|w| unsafe { w.bits(CR_REV_OUT | CR_REV_IN_BYTE | CR_RESET) });
+
+    for byte in &read()[..len.min(OTP_LEN)] {
+        // SAFETY: byte writes to the data register feed 8 bits at a time.
+        unsafe { core::ptr::write_volatile(crc.dr.as_ptr() as *mut u8, *byte) };
+    }
+
+    !crc.dr.read().bits()
+}
+
 /// Write data to OTP memory.
+///
+/// The final partial double-word is padded with `0xff`. Writing the same
+/// data again succeeds without programming anything so provisioning can be
+/// re-run.
 pub fn write(
     flash: &mut FLASH,
     data: &[u8],
     offset: usize,
-) -> Result<(), OtpWriteError> {
-    if data.len() + offset > OTP_LEN {
-        return Err(OtpWriteError::PayloadSize);
+) -> Result<(), Error> {
+    if offset % DOUBLE_WORD != 0 {
+        return Err(Error::Address);
+    }
+
+    // region programmed including padding.
+    let padded_len = data.len().next_multiple_of(DOUBLE_WORD);
+    if padded_len + offset > OTP_LEN {
+        return Err(Error::PayloadSize);
+    }
+
+    let otp = &read()[offset..padded_len + offset];
+    let (otp_data, otp_padding) = otp.split_at(data.len());
+
+    // check for a previous write of the same data.
+    if otp_data == data && otp_padding.iter().all(|b| *b == 0xff) {
+        defmt::info!("OTP already holds this data");
+        return Ok(());
     }
 
     // check otp is blank.
-    let otp = &read()[offset..data.len() + offset];
-    for byte in otp {
-        if *byte != 0xff {
-            return Err(OtpWriteError::Occupied);
-        }
+    if otp.iter().any(|b| *b != 0xff) {
+        return Err(Error::Occupied);
     }
 
     // unlock flash writing.
@@ -33,18 +87,27 @@ pub fn write(
 
     // check unlock worked.
     if flash.cr.read().lock().bit() {
-        panic!("Flash is still locked.");
+        return Err(Error::FlashLocked);
     }
 
     let address = OTP_ADDRESS as u32 + offset as u32;
+    let result = program(flash, address, data);
+
+    // lock flash
+    flash.cr.modify(|_, w| w.lock().set_bit());
+
+    result
+}
 
-    for idx in (0..data.len()).step_by(8) {
+/// Program data into unlocked OTP memory.
+fn program(flash: &FLASH, address: u32, data: &[u8]) -> Result<(), Error> {
+    for idx in (0..data.len()).step_by(DOUBLE_WORD) {
         let address1 = (address + idx as u32) as *mut u32;
         let address2 = (address + 4 + idx as u32) as *mut u32;
 
-        let (word1, word2) = if idx + 8 > data.len() {
+        let (word1, word2) = if idx + DOUBLE_WORD > data.len() {
             // pad writes smaller than double word.
-            let mut tmp_buffer = [0xff; 8];
+            let mut tmp_buffer = [0xff; DOUBLE_WORD];
             let remaining = data.len() - idx;
             tmp_buffer[..remaining].copy_from_slice(&data[idx..]);
             let tmp_dword = u64::from_le_bytes(tmp_buffer);
@@ -61,8 +124,7 @@ pub fn write(
 
         flash.cr.modify(|_, w| w.pg().set_bit());
 
-        // wait while busy
-        while flash.sr.read().bsy().bit_is_set() {}
+        dfu::wait_idle(flash, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)?;
 
         unsafe {
             core::ptr::write_volatile(address1, word1);
@@ -70,17 +132,6 @@ pub fn write(
         }
     }
 
-    // lock flash
-    flash.cr.modify(|_, w| w.lock().set_bit());
-
-    Ok(())
-}
-
-/// OTP memory write error.
-#[derive(Debug, defmt::Format, Clone, Copy, PartialEq, Eq)]
-pub enum OtpWriteError {
-    /// Payload will not fit in OTP.
-    PayloadSize,
-    /// Memory region is already occupied.
-    Occupied,
+    // wait for the last write to finish
+    dfu::wait_idle(flash, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)
 }
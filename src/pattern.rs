@@ -0,0 +1,91 @@
+//! Transmit test pattern generator.
+//!
+//! Repeats a host supplied sequence of frames on an interface until stopped,
+//! without any further host traffic. Used for EMC and soak testing. Frames
+//! go through the interface's transmit queue so the transmit gap sets the
+//! rate.
+
+use crate::tx::{TxFrame, TxQueue};
+use heapless::Vec;
+
+/// Maximum number of frames in a pattern.
+pub const PATTERN_LEN: usize = 16;
+/// Pattern frames kept in the transmit queue at once. Leaves room for
+/// frames from the host.
+const QUEUE_DEPTH: usize = 4;
+
+#[derive(Default)]
+pub struct Pattern {
+    frames: Vec<TxFrame, PATTERN_LEN>,
+    /// Index of the next frame to queue.
+    next: usize,
+    /// Pattern is being transmitted.
+    pub running: bool,
+    /// Frames transmitted when the pattern was started.
+    sent_at_start: u32,
+    /// Bus-off events when the pattern was started.
+    bus_off_at_start: u32,
+}
+
+impl Pattern {
+    /// Number of frames in the pattern.
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Append a frame to the pattern.
+    ///
+    /// Returns the frame if the pattern is full.
+    pub fn push(&mut self, frame: TxFrame) -> Result<(), TxFrame> {
+        self.frames.push(frame)
+    }
+
+    /// Stop and remove all frames.
+    pub fn clear(&mut self) {
+        self.stop();
+        self.frames.clear();
+    }
+
+    /// Start transmitting from the first frame.
+    ///
+    /// Returns `false` if the pattern is empty.
+    pub fn start(&mut self, tx: &TxQueue, bus_off: u32) -> bool {
+        if self.frames.is_empty() {
+            return false;
+        }
+
+        self.next = 0;
+        self.running = true;
+        self.sent_at_start = tx.sent;
+        self.bus_off_at_start = bus_off;
+
+        true
+    }
+
+    /// Stop transmitting. Frames already queued are still sent.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Frames transmitted since the pattern was started.
+    pub fn sent(&self, tx: &TxQueue) -> u32 {
+        tx.sent.wrapping_sub(self.sent_at_start)
+    }
+
+    /// Bus-off events since the pattern was started.
+    pub fn bus_off(&self, bus_off: u32) -> u32 {
+        bus_off.wrapping_sub(self.bus_off_at_start)
+    }
+
+    /// Top up the transmit queue with the next frames of the pattern.
+    pub fn refill(&mut self, tx: &mut TxQueue) {
+        if !self.running {
+            return;
+        }
+
+        while tx.len() < QUEUE_DEPTH {
+            tx.push(self.frames[self.next]);
+            self.next = (self.next + 1) % self.frames.len();
+        }
+    }
+}
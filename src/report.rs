@@ -0,0 +1,239 @@
+//! Error statistics reports.
+//!
+//! When enabled by the host, the state and error counters of every interface
+//! are pushed on a vendor interrupt endpoint whenever they change by more
+//! than a threshold, so monitoring tools don't have to poll the diagnostics.
+//!
+//! Each report is 8 bytes per interface, little-endian: `[state: u8,
+//! tx_errors: u8, rx_errors: u8, reserved: u8, errors: u32]`. `state` is 0
+//! when stopped, 1 when error active or warning and 2 when error passive,
+//! bus-off or restricted.
+//! `errors` counts protocol errors since the interface was started.
+//!
+//! The same endpoint optionally carries a heartbeat, so host software can
+//! tell a wedged adapter from a quiet bus. It's sent from the background
+//! task that also sends the reports, so it stops if the firmware locks up
+//! even while USB is still serviced. Each heartbeat is 8 bytes,
+//! little-endian: `[sequence: u32, uptime_ms: u32]`, and is told apart from
+//! a report by its length.
+
+use crate::{can::UsbCanDevice, Mono};
+use rtic_monotonics::Monotonic;
+use usb_device::class_prelude::*;
+use usbd_gscan::host::CanState;
+
+/// Report endpoint packet size.
+const PACKET_SIZE: u16 = 16;
+/// Report endpoint polling interval in milliseconds.
+const POLL_INTERVAL_MS: u8 = 10;
+/// Vendor specific interface class.
+const INTERFACE_CLASS: u8 = 0xff;
+
+/// Interface state and error counters as last reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Snapshot {
+    state: u8,
+    tx_errors: u8,
+    rx_errors: u8,
+    errors: u32,
+}
+
+impl Snapshot {
+    fn read(device: &UsbCanDevice, interface: u8) -> Self {
+        let errors = device
+            .interfaces
+            .get(interface as usize)
+            .map_or(0, |i| i.session.errors);
+
+        match device.device_state(interface) {
+            Ok(s) if device.is_started(interface) => Self {
+                state: match s.state {
+                    CanState::Passive | CanState::BusOff => 2,
+                    _ => 1,
+                },
+                tx_errors: s.tx_errors.min(u8::MAX as u32) as u8,
+                rx_errors: s.rx_errors.min(u8::MAX as u32) as u8,
+                errors,
+            },
+            _ => Self {
+                errors,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Differs from `last` enough to be worth reporting.
+    fn changed(&self, last: &Self, threshold: u32) -> bool {
+        self.state != last.state
+            || self.tx_errors.abs_diff(last.tx_errors) as u32 >= threshold
+            || self.rx_errors.abs_diff(last.rx_errors) as u32 >= threshold
+            || self.errors.wrapping_sub(last.errors) >= threshold
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0] = self.state;
+        buf[1] = self.tx_errors;
+        buf[2] = self.rx_errors;
+        buf[3] = 0;
+        buf[4..8].copy_from_slice(&self.errors.to_le_bytes());
+    }
+}
+
+pub struct ErrorReport<B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint: EndpointIn<'static, B>,
+    /// Minimum time between reports in bus monitor periods (100 ms). Zero
+    /// disables reports.
+    interval: u16,
+    /// Smallest counter change reported.
+    threshold: u16,
+    /// Bus monitor periods since the last report.
+    elapsed: u16,
+    last: [Snapshot; 2],
+    /// Time between heartbeats in bus monitor periods. Zero disables them.
+    heartbeat: u16,
+    /// Bus monitor periods since the last heartbeat.
+    heartbeat_elapsed: u16,
+    /// Heartbeats sent.
+    pub beats: u32,
+}
+
+impl<B: UsbBus> ErrorReport<B> {
+    pub fn new(alloc: &'static UsbBusAllocator<B>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            endpoint: alloc.interrupt(PACKET_SIZE, POLL_INTERVAL_MS),
+            interval: 0,
+            threshold: 0,
+            elapsed: 0,
+            last: Default::default(),
+            heartbeat: 0,
+            heartbeat_elapsed: 0,
+            beats: 0,
+        }
+    }
+
+    /// Heartbeat interval in 100 ms periods.
+    pub fn heartbeat(&self) -> u16 {
+        self.heartbeat
+    }
+
+    /// Change the heartbeat interval. An interval of zero disables them.
+    pub fn set_heartbeat(&mut self, interval: u16) {
+        self.heartbeat = interval;
+        self.heartbeat_elapsed = interval;
+    }
+
+    /// Reporting interval in 100 ms periods and counter threshold.
+    pub fn config(&self) -> (u16, u16) {
+        (self.interval, self.threshold)
+    }
+
+    /// Change the reporting interval and threshold. An interval of zero
+    /// disables reports.
+    ///
+    /// The next check always sends a report so the host starts from the
+    /// current values.
+    pub fn configure(&mut self, interval: u16, threshold: u16) {
+        self.interval = interval;
+        self.threshold = threshold;
+        self.elapsed = interval;
+        self.last = [Snapshot {
+            state: u8::MAX,
+            ..Default::default()
+        }; 2];
+    }
+
+    /// Send a heartbeat or report if one is due. Called every bus monitor
+    /// period.
+    ///
+    /// Only one packet can be pending on the endpoint, so a report due at
+    /// the same time as a heartbeat follows a period later.
+    pub fn poll(&mut self, device: &UsbCanDevice) {
+        if !self.poll_heartbeat() {
+            self.poll_report(device);
+        }
+    }
+
+    /// Send a heartbeat if one is due. Returns whether one was sent.
+    fn poll_heartbeat(&mut self) -> bool {
+        if self.heartbeat == 0 {
+            return false;
+        }
+
+        self.heartbeat_elapsed = self.heartbeat_elapsed.saturating_add(1);
+        if self.heartbeat_elapsed < self.heartbeat {
+            return false;
+        }
+
+        let uptime = Mono::now().duration_since_epoch().to_millis() as u32;
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&self.beats.to_le_bytes());
+        buf[4..].copy_from_slice(&uptime.to_le_bytes());
+
+        match self.endpoint.write(&buf) {
+            Ok(_) => {
+                self.beats = self.beats.wrapping_add(1);
+                self.heartbeat_elapsed = 0;
+                true
+            }
+            Err(UsbError::WouldBlock) => false,
+            Err(e) => {
+                defmt::error!("Heartbeat not sent: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Send a report if one is due and anything changed.
+    fn poll_report(&mut self, device: &UsbCanDevice) {
+        if self.interval == 0 {
+            return;
+        }
+
+        self.elapsed = self.elapsed.saturating_add(1);
+        if self.elapsed < self.interval {
+            return;
+        }
+
+        let threshold = self.threshold.max(1) as u32;
+        let now = [Snapshot::read(device, 0), Snapshot::read(device, 1)];
+        if !now
+            .iter()
+            .zip(&self.last)
+            .any(|(n, l)| n.changed(l, threshold))
+        {
+            return;
+        }
+
+        let mut buf = [0; PACKET_SIZE as usize];
+        for (chunk, snapshot) in buf.chunks_exact_mut(8).zip(&now) {
+            snapshot.write(chunk);
+        }
+
+        // A host that isn't reading the endpoint leaves the previous report
+        // pending. Try again next period rather than queueing.
+        match self.endpoint.write(&buf) {
+            Ok(_) => {
+                self.last = now;
+                self.elapsed = 0;
+            }
+            Err(UsbError::WouldBlock) => {}
+            Err(e) => defmt::error!("Error report not sent: {}", e),
+        }
+    }
+
+    pub fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.interface, INTERFACE_CLASS, 0, 0)?;
+        writer.endpoint(&self.endpoint)
+    }
+
+    /// Stop reports and heartbeats on USB reset.
+    pub fn reset(&mut self) {
+        self.interval = 0;
+        self.heartbeat = 0;
+    }
+}
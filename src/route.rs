@@ -0,0 +1,155 @@
+//! Gateway routing between interfaces.
+//!
+//! A small table of rules, checked for every received frame, that forwards
+//! matching frames to the other interface without involving the host,
+//! optionally under a different identifier. Received frames are still
+//! forwarded to the host as usual.
+//!
+//! Each rule is 20 bytes, little-endian: `[source: u8, destination: u8,
+//! flags: u8, reserved: u8, id: u32, mask: u32, remap: u32, hits: u32]`. A
+//! frame received on `source` matches when its identifier agrees with `id`
+//! in every bit set in `mask`. Bit 31 of `id`, `mask` and `remap` marks an
+//! extended identifier, so setting it in `mask` matches only one identifier
+//! type. When bit 0 of `flags` is set the forwarded frame uses `remap` as
+//! its identifier. `hits` counts forwarded frames and is ignored when
+//! adding a rule.
+
+use crate::tx::TxFrame;
+use fdcan::id::{ExtendedId, Id, StandardId};
+use heapless::Vec;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Maximum number of rules.
+pub const MAX_RULES: usize = 8;
+/// Rule size in bytes.
+pub const RULE_SIZE: usize = 20;
+/// Extended identifier flag in a rule.
+const ID_EXTENDED: u32 = 1 << 31;
+/// Rule flag replacing the identifier of forwarded frames.
+const FLAG_REMAP: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Rule {
+    pub source: u8,
+    pub destination: u8,
+    pub flags: u8,
+    reserved: u8,
+    pub id: u32,
+    pub mask: u32,
+    pub remap: u32,
+    pub hits: u32,
+}
+
+impl Rule {
+    /// Parse a rule from a vendor request data stage.
+    ///
+    /// Returns `None` unless it routes between two different interfaces and
+    /// any replacement identifier is in range.
+    pub fn parse(data: &[u8], interfaces: u8) -> Option<Self> {
+        let mut rule = Self::read_from(data)?;
+
+        if rule.source >= interfaces
+            || rule.destination >= interfaces
+            || rule.source == rule.destination
+        {
+            return None;
+        }
+
+        if rule.flags & FLAG_REMAP != 0 {
+            decode_id(rule.remap)?;
+        }
+
+        rule.reserved = 0;
+        rule.hits = 0;
+
+        Some(rule)
+    }
+
+    fn matches(&self, source: u8, id: u32) -> bool {
+        self.source == source && (id ^ self.id) & self.mask == 0
+    }
+}
+
+#[derive(Default)]
+pub struct RoutingTable {
+    rules: Vec<Rule, MAX_RULES>,
+}
+
+impl RoutingTable {
+    /// Append a rule.
+    ///
+    /// Returns the rule if the table is full.
+    pub fn push(&mut self, rule: Rule) -> Result<(), Rule> {
+        self.rules.push(rule)
+    }
+
+    /// Remove all rules.
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply the rules to a frame received on `source`, calling `forward`
+    /// with the destination and frame for each match.
+    ///
+    /// `forward` returns whether the frame was queued; only those count as
+    /// hits.
+    pub fn route(
+        &mut self,
+        source: u8,
+        frame: &TxFrame,
+        mut forward: impl FnMut(u8, TxFrame) -> bool,
+    ) {
+        let id = encode_id(frame.header.id);
+
+        for rule in self.rules.iter_mut().filter(|r| r.matches(source, id)) {
+            let mut routed = *frame;
+            if rule.flags & FLAG_REMAP != 0 {
+                // checked when the rule was added.
+                if let Some(remap) = decode_id(rule.remap) {
+                    routed.header.id = remap;
+                }
+            }
+
+            if forward(rule.destination, routed) {
+                rule.hits = rule.hits.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Copy rules in the order they were added.
+    ///
+    /// Returns the number of bytes written.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (chunk, rule) in
+            buf.chunks_exact_mut(RULE_SIZE).zip(self.rules.iter())
+        {
+            chunk.copy_from_slice(rule.as_bytes());
+            len += RULE_SIZE;
+        }
+
+        len
+    }
+}
+
+/// Encode an identifier as in a rule.
+fn encode_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | ID_EXTENDED,
+    }
+}
+
+/// Decode an identifier from a rule.
+fn decode_id(raw: u32) -> Option<Id> {
+    if raw & ID_EXTENDED != 0 {
+        ExtendedId::new(raw & !ID_EXTENDED).map(Id::Extended)
+    } else {
+        StandardId::new(u16::try_from(raw).ok()?).map(Id::Standard)
+    }
+}
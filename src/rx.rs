@@ -0,0 +1,67 @@
+//! Software receive queues.
+//!
+//! The controller's RX FIFOs hold three frames each, which an FD burst at
+//! 5 Mbit/s fills in a fraction of a millisecond, while forwarding a frame
+//! to the host takes a USB transfer. Receive interrupts move frames from
+//! every interface's FIFOs to these queues, and go back to the FIFOs between
+//! every frame forwarded, so a burst waits here rather than being
+//! overwritten in the controller.
+//!
+//! The depth is set at build time with the `RX_QUEUE_DEPTH` environment
+//! variable (default 16). Each slot holds a full FD frame, 80 bytes, for
+//! each interface.
+
+use crate::hal::stm32::{FDCAN1, FDCAN2, FDCAN3};
+use heapless::Deque;
+use usbd_gscan::host::Frame;
+
+// `RX_QUEUE_DEPTH`, generated by `build.rs`.
+include!(concat!(env!("OUT_DIR"), "/rx.rs"));
+
+#[derive(Default)]
+pub struct RxQueue {
+    frames: Deque<Frame, RX_QUEUE_DEPTH>,
+    /// Most frames queued at once.
+    pub peak: usize,
+}
+
+impl RxQueue {
+    /// Queue a received frame.
+    ///
+    /// Returns `false` and drops the frame if the queue is full, keeping the
+    /// frames received before it in order.
+    pub fn push(&mut self, frame: Frame) -> bool {
+        let queued = self.frames.push_back(frame).is_ok();
+        self.peak = self.peak.max(self.frames.len());
+        queued
+    }
+
+    /// Take the oldest frame.
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.frames.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Discard all queued frames.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+/// Frames waiting in a hardware RX FIFO of an interface.
+pub fn fill_level(interface: u8, fifo1: bool) -> u8 {
+    // SAFETY: read-only access to status registers with no side effects.
+    let status = match (interface, fifo1) {
+        (0, false) => unsafe { &*FDCAN2::ptr() }.rxf0s.read().bits(),
+        (0, true) => unsafe { &*FDCAN2::ptr() }.rxf1s.read().bits(),
+        (1, false) => unsafe { &*FDCAN3::ptr() }.rxf0s.read().bits(),
+        (1, true) => unsafe { &*FDCAN3::ptr() }.rxf1s.read().bits(),
+        (_, false) => unsafe { &*FDCAN1::ptr() }.rxf0s.read().bits(),
+        (_, true) => unsafe { &*FDCAN1::ptr() }.rxf1s.read().bits(),
+    };
+
+    (status & 0xf) as u8
+}
@@ -0,0 +1,342 @@
+//! Persistent runtime settings.
+//!
+//! Settings are only written to flash when the host explicitly asks for them
+//! to be saved, to avoid unnecessary flash wear. They live in the last two
+//! pages of the active bank, which are excluded from the firmware image in
+//! `memory.x` along with the image record before them, and are copied to
+//! the inactive bank before a firmware update swaps banks.
+//!
+//! Each save appends a [`Record`] with an incrementing sequence number and a
+//! CRC to the next blank slot, so a page is only erased once it's full and
+//! writes alternate between the two pages. On boot the valid record with
+//! the highest sequence number is used. A record torn by a reset mid-write
+//! fails its CRC and the previous one is used instead.
+
+use crate::{
+    can::INTERFACES,
+    dfu::{
+        self, BANK2_OFFSET, BANK_PAGES, BUSY_MARGIN, DOUBLE_WORD_PROGRAM_US,
+        FLASH_BASE, KEY, PAGE_ERASE_US, PAGE_SIZE,
+    },
+    error::Error,
+    hal::stm32::{flash::RegisterBlock, FLASH},
+    tx::DEFAULT_PADDING,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Settings layout version. Bump when the layout of [`Settings`] changes so
+/// stale settings are ignored rather than misinterpreted.
+const VERSION: u32 = 3;
+/// Flash pages holding the settings, the last two pages of the bank.
+const PAGES: [u8; 2] = [(BANK_PAGES - 2) as u8, (BANK_PAGES - 1) as u8];
+/// Size of a record in flash.
+const RECORD_SIZE: u32 = core::mem::size_of::<Record>() as u32;
+/// Records that fit in a page.
+const SLOTS: u32 = PAGE_SIZE / RECORD_SIZE;
+/// Checksum over a record's sequence number and settings.
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// A [`Write`] is in progress.
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Settings {
+    /// Layout version.
+    pub version: u32,
+    /// Minimum gap between transmitted frames in microseconds, indexed by
+    /// interface.
+    pub tx_gap_us: [u32; INTERFACES],
+    /// Byte used to pad FD payloads, indexed by interface.
+    pub padding: [u8; INTERFACES],
+    /// Unused. Keeps the layout free of implicit padding and records a
+    /// whole number of double words, the flash programming unit.
+    pub reserved: [u8; 5],
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            tx_gap_us: [0; INTERFACES],
+            padding: [DEFAULT_PADDING; INTERFACES],
+            reserved: [0; 5],
+        }
+    }
+}
+
+/// Settings as stored in flash.
+#[derive(Debug, Clone, Copy, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+struct Record {
+    /// Incremented on every save. The highest valid one is current.
+    sequence: u32,
+    /// CRC-32 of `sequence` and `settings`.
+    crc: u32,
+    settings: Settings,
+}
+
+impl Record {
+    fn new(sequence: u32, settings: Settings) -> Self {
+        let mut record = Self {
+            sequence,
+            crc: 0,
+            settings,
+        };
+        record.crc = record.checksum();
+        record
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut digest = CRC.digest();
+        digest.update(self.sequence.as_bytes());
+        digest.update(self.settings.as_bytes());
+        digest.finalize()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.sequence != u32::MAX && self.crc == self.checksum()
+    }
+}
+
+/// Location of a record slot in the settings pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    /// Index into [`PAGES`].
+    page: usize,
+    index: u32,
+}
+
+impl Slot {
+    /// Address of the slot in the active bank.
+    fn address(&self) -> u32 {
+        FLASH_BASE
+            + PAGES[self.page] as u32 * PAGE_SIZE
+            + self.index * RECORD_SIZE
+    }
+
+    /// Raw slot contents in the active bank.
+    fn stored(&self) -> &'static [u8] {
+        let len = RECORD_SIZE as usize;
+        unsafe { core::slice::from_raw_parts(self.address() as *const u8, len) }
+    }
+
+    fn read(&self) -> Option<Record> {
+        Record::read_from(self.stored()).filter(Record::is_valid)
+    }
+
+    fn is_blank(&self) -> bool {
+        self.stored().iter().all(|&b| b == 0xff)
+    }
+}
+
+/// Find the current record and where it's stored.
+fn latest() -> Option<(Slot, Record)> {
+    (0..PAGES.len())
+        .flat_map(|page| (0..SLOTS).map(move |index| Slot { page, index }))
+        .filter_map(|slot| Some((slot, slot.read()?)))
+        .max_by_key(|(_, record)| record.sequence)
+}
+
+/// Load settings from the active bank.
+///
+/// Returns `None` if no valid settings have been saved or they were saved by
+/// a firmware with a different layout.
+pub fn load() -> Option<Settings> {
+    let (_, record) = latest()?;
+    let settings = record.settings;
+
+    if settings.version != VERSION {
+        defmt::warn!("Ignoring settings version {}", settings.version);
+        return None;
+    }
+
+    Some(settings)
+}
+
+/// A save or clear requested by the host.
+///
+/// Erasing a settings page takes around 25 ms, so the vendor request only
+/// queues the write and the bus monitor task carries it out, outside the
+/// USB interrupt.
+#[derive(Debug, Clone, Copy)]
+pub enum Write {
+    /// Save these settings.
+    Save(Settings),
+    /// Erase saved settings.
+    Clear,
+}
+
+impl Write {
+    /// Carry out the write.
+    ///
+    /// Fails with [`Error::FlashBusy`] if a firmware update has a page erase
+    /// pending.
+    pub fn run(self) -> Result<(), Error> {
+        // Set first so the DFU class, which runs in the higher priority USB
+        // interrupt, can't start an operation once the check has passed.
+        WRITING.store(true, Ordering::Relaxed);
+
+        let result = if dfu::erase_pending() {
+            Err(Error::FlashBusy)
+        } else {
+            // SAFETY: the DFU class leaves flash alone while `WRITING` is
+            // set and has no operation pending, and nothing else touches it.
+            let flash = unsafe { &*FLASH::ptr() };
+            match self {
+                Self::Save(settings) => save(flash, &settings),
+                Self::Clear => clear(flash),
+            }
+        };
+
+        WRITING.store(false, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Check if a settings [`Write`] is in progress.
+///
+/// The DFU class must not touch flash until it's finished.
+pub fn writing() -> bool {
+    WRITING.load(Ordering::Relaxed)
+}
+
+/// Save settings to the active bank.
+///
+/// The record goes in the first blank slot after the current one. When its
+/// page is full the other page is erased and written from the start.
+fn save(flash: &RegisterBlock, settings: &Settings) -> Result<(), Error> {
+    let (slot, sequence) = match latest() {
+        Some((current, record)) => {
+            let next = (current.index + 1..SLOTS)
+                .map(|index| Slot {
+                    page: current.page,
+                    index,
+                })
+                .find(Slot::is_blank);
+            let next = next.unwrap_or(Slot {
+                page: 1 - current.page,
+                index: 0,
+            });
+            (next, record.sequence.wrapping_add(1))
+        }
+        None => (Slot { page: 0, index: 0 }, 0),
+    };
+
+    let record = Record::new(sequence, *settings);
+
+    unlock(flash, |flash| {
+        if slot.index == 0 {
+            erase(flash, PAGES[slot.page], false)?;
+        }
+        program(flash, slot.address(), record.as_bytes())
+    })
+}
+
+/// Erase saved settings from the active bank.
+fn clear(flash: &RegisterBlock) -> Result<(), Error> {
+    unlock(flash, |flash| {
+        for page in PAGES {
+            erase(flash, page, false)?;
+        }
+        Ok(())
+    })
+}
+
+/// Copy saved settings to the inactive bank so they survive a bank swap.
+///
+/// Only the current record is copied, keeping its sequence number.
+pub fn copy_to_inactive_bank(flash: &RegisterBlock) -> Result<(), Error> {
+    let Some((_, record)) = latest() else {
+        return Ok(());
+    };
+
+    let slot = Slot { page: 0, index: 0 };
+
+    unlock(flash, |flash| {
+        for page in PAGES {
+            erase(flash, page, true)?;
+        }
+        program(flash, slot.address() + BANK2_OFFSET, record.as_bytes())
+    })
+}
+
+fn unlock(
+    flash: &RegisterBlock,
+    f: impl FnOnce(&RegisterBlock) -> Result<(), Error>,
+) -> Result<(), Error> {
+    flash.keyr.write(|w| unsafe { w.bits(KEY[0]) });
+    flash.keyr.write(|w| unsafe { w.bits(KEY[1]) });
+
+    // Flash should unlock on first try.
+    if flash.cr.read().lock().bit() {
+        return Err(Error::FlashLocked);
+    }
+
+    let result = f(flash);
+
+    flash.cr.modify(|_, w| w.lock().set_bit());
+
+    result
+}
+
+/// Erase a settings page in the active or inactive bank.
+fn erase(flash: &RegisterBlock, page: u8, inactive: bool) -> Result<(), Error> {
+    let active = dfu::active_bank(flash);
+    let bank = if inactive { active.other() } else { active };
+
+    // clear any existing operations
+    flash.cr.modify(|_, w| unsafe { w.bits(0) });
+
+    flash.cr.modify(|_, w| unsafe {
+        w.bits(bank.erase_selection())
+            .pnb()
+            .bits(page)
+            .per()
+            .set_bit()
+    });
+
+    flash.cr.modify(|_, w| w.strt().set_bit());
+
+    dfu::wait_idle(flash, PAGE_ERASE_US * BUSY_MARGIN)?;
+
+    // remove page erase operation bit
+    flash.cr.modify(|_, w| w.per().clear_bit());
+
+    Ok(())
+}
+
+/// Program data one double word at a time, padding the last double word.
+fn program(
+    flash: &RegisterBlock,
+    address: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    for idx in (0..data.len()).step_by(8) {
+        let address1 = (address + idx as u32) as *mut u32;
+        let address2 = (address + 4 + idx as u32) as *mut u32;
+
+        let mut dword = [0xff; 8];
+        let end = data.len().min(idx + 8);
+        dword[..end - idx].copy_from_slice(&data[idx..end]);
+        let dword = u64::from_le_bytes(dword);
+
+        flash.cr.modify(|_, w| w.pg().set_bit());
+
+        dfu::wait_idle(flash, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)?;
+
+        unsafe {
+            core::ptr::write_volatile(address1, dword as u32);
+            core::ptr::write_volatile(address2, (dword >> 32) as u32);
+        }
+    }
+
+    // wait for the last write to finish
+    dfu::wait_idle(flash, DOUBLE_WORD_PROGRAM_US * BUSY_MARGIN)?;
+
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Common receive timestamp base.
+//!
+//! Each FDCAN instance has its own timestamp counter clocked in nominal bit
+//! times, so timestamps from interfaces at different bit rates, or started
+//! at different times, can't be compared. Both instances instead take their
+//! timestamps from TIM3 run at 1 MHz. Receive timestamps on either interface
+//! are then microseconds on the same 16-bit time base, wrapping together
+//! every 65.536 ms, so a host bridging two buses can order frames across
+//! interfaces.
+//!
+//! Timer wraps are counted in the TIM3 interrupt to extend the counter to
+//! 32 bits for the gs_usb timestamp request, wrapping every ~71.6 minutes.
+//! Its low 16 bits are the frame timestamps' time base so the host can
+//! place frames on its own clock.
+
+use crate::{
+    hal::stm32::{RCC, TIM3},
+    tx::CORE_CLOCK_MHZ,
+};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// Timer wraps since it was started, the high half of [`now_extended`].
+static WRAPS: AtomicU16 = AtomicU16::new(0);
+
+/// Start TIM3 counting microseconds. Must be called before either FDCAN
+/// instance leaves configuration mode.
+pub fn start(tim3: &TIM3) {
+    // SAFETY: single bit set during init, before anything else touches the
+    // peripheral clock enables.
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.apb1enr1.modify(|_, w| w.tim3en().set_bit());
+
+    // APB1 isn't divided so the timer runs from the core clock.
+    tim3.psc.write(|w| unsafe { w.bits(CORE_CLOCK_MHZ - 1) });
+    tim3.arr.write(|w| unsafe { w.bits(u16::MAX as u32) });
+    // Load the prescaler.
+    tim3.egr.write(|w| w.ug().set_bit());
+    // Loading the prescaler raised the update flag.
+    tim3.sr.write(|w| w.uif().clear_bit());
+    tim3.dier.write(|w| w.uie().set_bit());
+    tim3.cr1.modify(|_, w| w.cen().set_bit());
+}
+
+/// Current timestamp in microseconds.
+pub fn now() -> u16 {
+    // SAFETY: read-only access to the counter.
+    let tim3 = unsafe { &*TIM3::ptr() };
+    tim3.cnt.read().bits() as u16
+}
+
+/// Count a timer wrap. Called from the TIM3 interrupt.
+pub fn wrapped() {
+    // SAFETY: clearing the update flag, the only interrupt enabled.
+    let tim3 = unsafe { &*TIM3::ptr() };
+    tim3.sr.write(|w| w.uif().clear_bit());
+    WRAPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current timestamp in microseconds, extended to 32 bits.
+///
+/// A wrap the TIM3 interrupt hasn't counted yet, because it's masked or
+/// this is called at or above its priority, is taken from the update flag.
+pub fn now_extended() -> u32 {
+    // SAFETY: read-only access to the status register.
+    let tim3 = unsafe { &*TIM3::ptr() };
+
+    loop {
+        let high = WRAPS.load(Ordering::Relaxed);
+        let low = now();
+        // The flag is read after the counter, so it may be set by a wrap
+        // just after a read near the top. Only a low count is past it.
+        let pending = tim3.sr.read().uif().bit_is_set() && low < u16::MAX / 2;
+        // a wrap between the two reads would pair the old high half with
+        // the new low half.
+        if WRAPS.load(Ordering::Relaxed) == high {
+            let high = high.wrapping_add(pending as u16);
+            return (high as u32) << 16 | low as u32;
+        }
+    }
+}
+
+/// Extend a timestamp taken in the last 65.536 ms to 32 bits.
+///
+/// Older timestamps are placed a whole number of wraps too late.
+pub fn extend(timestamp: u16) -> u32 {
+    let now = now_extended();
+    let age = (now as u16).wrapping_sub(timestamp);
+    now.wrapping_sub(age as u32)
+}
@@ -0,0 +1,156 @@
+//! Transceiver standby control.
+//!
+//! Boards route the transceiver standby pins differently, if at all, so
+//! they're described by the `PINS` VPD tag rather than hard-coded, see
+//! [`crate::vpd::Pins`]. Interfaces without a standby pin are always active.
+
+use crate::{
+    error::Error,
+    hal::stm32::{GPIOA, GPIOB, GPIOC, RCC},
+    vpd::Pins,
+};
+
+/// Last port a standby pin can be on, port C.
+const MAX_PORT: u8 = 2;
+/// Pins used for USB, debug and the CAN controllers as `(port, pin)`.
+/// Never driven as standby pins.
+const RESERVED: [(u8, u8); 8] = [
+    (0, 11),
+    (0, 12),
+    (0, 13),
+    (0, 14),
+    (1, 3),
+    (1, 4),
+    (1, 5),
+    (1, 6),
+];
+
+/// Standby pin of one transceiver.
+#[derive(Debug, Clone, Copy)]
+struct Pin {
+    port: u8,
+    pin: u8,
+    /// The transceiver is in standby while the pin is low.
+    active_low: bool,
+}
+
+impl Pin {
+    /// Make the pin a push-pull output.
+    fn configure(&self) {
+        let mask = 0b11 << (self.pin * 2);
+        let output = 0b01 << (self.pin * 2);
+
+        // SAFETY: only called from `init`, after the HAL has configured
+        // its pins, and only changes the mode of this pin.
+        match self.port {
+            0 => unsafe { &*GPIOA::ptr() }
+                .moder
+                .modify(|r, w| unsafe { w.bits(r.bits() & !mask | output) }),
+            1 => unsafe { &*GPIOB::ptr() }
+                .moder
+                .modify(|r, w| unsafe { w.bits(r.bits() & !mask | output) }),
+            _ => {
+                // SAFETY: the HAL only enables the clocks of ports it's
+                // given, which doesn't include port C.
+                unsafe { &*RCC::ptr() }
+                    .ahb2enr
+                    .modify(|_, w| w.gpiocen().set_bit());
+                unsafe { &*GPIOC::ptr() }
+                    .moder
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !mask | output) })
+            }
+        }
+    }
+
+    /// Put the transceiver in or out of standby.
+    fn drive(&self, standby: bool) {
+        let bit = match standby != self.active_low {
+            true => 1 << self.pin,
+            false => 1 << (self.pin + 16),
+        };
+
+        // SAFETY: set/reset writes are atomic and only affect this pin.
+        match self.port {
+            0 => unsafe { &*GPIOA::ptr() }
+                .bsrr
+                .write(|w| unsafe { w.bits(bit) }),
+            1 => unsafe { &*GPIOB::ptr() }
+                .bsrr
+                .write(|w| unsafe { w.bits(bit) }),
+            _ => unsafe { &*GPIOC::ptr() }
+                .bsrr
+                .write(|w| unsafe { w.bits(bit) }),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Transceivers {
+    pins: [Option<Pin>; 2],
+    standby: [bool; 2],
+}
+
+impl Transceivers {
+    /// Configure the standby pins described by the VPD, leaving the
+    /// transceivers active.
+    ///
+    /// Pins on a port past C or used for something else are logged and
+    /// left alone.
+    pub fn new(pins: &Pins) -> Self {
+        let mut transceivers = Self::default();
+
+        for (interface, slot) in transceivers.pins.iter_mut().enumerate() {
+            let Some((port, pin)) = pins.standby(interface) else {
+                continue;
+            };
+
+            if port > MAX_PORT || RESERVED.contains(&(port, pin)) {
+                defmt::warn!(
+                    "Interface {} standby pin P{}{} unusable",
+                    interface,
+                    (b'A' + port) as char,
+                    pin
+                );
+                continue;
+            }
+
+            let pin = Pin {
+                port,
+                pin,
+                active_low: pins.active_low(interface),
+            };
+            // set the level before enabling the output so the transceiver
+            // doesn't glitch into standby.
+            pin.drive(false);
+            pin.configure();
+            *slot = Some(pin);
+        }
+
+        transceivers
+    }
+
+    /// Put the transceiver of an interface in or out of standby.
+    pub fn set_standby(
+        &mut self,
+        interface: u8,
+        standby: bool,
+    ) -> Result<(), Error> {
+        let index = interface as usize;
+        let Some(pin) = self.pins.get(index).copied().flatten() else {
+            return Err(Error::NoStandbyPin(interface));
+        };
+
+        pin.drive(standby);
+        self.standby[index] = standby;
+        defmt::info!("Interface {} transceiver standby {}", interface, standby);
+
+        Ok(())
+    }
+
+    /// Check if the transceiver of an interface is in standby, `None` if it
+    /// has no standby pin.
+    pub fn standby(&self, interface: u8) -> Option<bool> {
+        let index = interface as usize;
+        self.pins.get(index)?.map(|_| self.standby[index])
+    }
+}
@@ -0,0 +1,368 @@
+//! Software transmit queue.
+
+use crate::{
+    echo::Echoes,
+    hal::{
+        cortex_m::peripheral::DWT,
+        stm32::{FDCAN1, FDCAN2, FDCAN3},
+    },
+    history::{Buffers, TxHistory},
+    Mono,
+};
+use fdcan::{
+    frame::{FrameFormat, TxFrameHeader},
+    id::Id,
+    FdCan, Transmit,
+};
+use heapless::Deque;
+use rtic_monotonics::Monotonic;
+use usbd_gscan::host::FrameFlag;
+
+/// Number of frames buffered per interface.
+pub const TX_QUEUE_LEN: usize = 32;
+/// Number of hardware transmit buffers.
+pub const HARDWARE_BUFFERS: usize = 3;
+/// Hardware transmit buffers, one bit each in `TXBTIE`.
+const TX_BUFFERS: u32 = 0b111;
+
+pub type Instant = <Mono as Monotonic>::Instant;
+pub type Duration = <Mono as Monotonic>::Duration;
+
+/// Core clock frequency used to convert cycle counts to time.
+pub const CORE_CLOCK_MHZ: u32 = 160;
+/// Default byte used to pad FD payloads up to a valid length.
+pub const DEFAULT_PADDING: u8 = 0xcc;
+
+/// Frame waiting to be transmitted.
+#[derive(Debug, Clone, Copy)]
+pub struct TxFrame {
+    pub header: TxFrameHeader,
+    pub data: [u8; 64],
+    /// Record the time this frame is handed to the hardware.
+    pub sync: bool,
+}
+
+impl TxFrame {
+    /// Build a frame from a host identifier, gs_usb flags and payload.
+    ///
+    /// Payloads longer than 64 bytes are truncated.
+    pub fn new(id: Id, flags: FrameFlag, payload: &[u8]) -> Self {
+        let len = payload.len().min(64);
+
+        let header = TxFrameHeader {
+            len: len as u8,
+            frame_format: if flags.intersects(FrameFlag::FD) {
+                FrameFormat::Fdcan
+            } else {
+                FrameFormat::Standard
+            },
+            id,
+            bit_rate_switching: flags.intersects(FrameFlag::BIT_RATE_SWITCH),
+            marker: None,
+        };
+
+        let mut data = [0; 64];
+        data[..len].copy_from_slice(&payload[..len]);
+
+        Self {
+            header,
+            data,
+            sync: false,
+        }
+    }
+
+    /// Rebuild a frame the controller took back out of a transmit buffer
+    /// from its header and the data words left in message RAM.
+    fn preempted(header: TxFrameHeader, words: &[u32]) -> Self {
+        let mut data = [0; 64];
+        for (chunk, word) in data.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+
+        Self {
+            header,
+            data,
+            sync: false,
+        }
+    }
+
+    /// Check if the frame is in FD format.
+    pub fn is_fd(&self) -> bool {
+        self.header.frame_format == FrameFormat::Fdcan
+    }
+
+    /// Frame payload.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.header.len as usize]
+    }
+
+    /// Round an FD payload up to the next length a DLC can encode, filling
+    /// the extra bytes with `padding`.
+    pub fn pad(&mut self, padding: u8) {
+        if self.header.frame_format != FrameFormat::Fdcan {
+            return;
+        }
+
+        let len = self.header.len as usize;
+        let padded = fd_len(len);
+
+        self.data[len..padded].fill(padding);
+        self.header.len = padded as u8;
+    }
+}
+
+/// Shortest FD payload length a DLC can encode that holds `len` bytes.
+///
+/// Lengths over 64 give 64.
+pub fn fd_len(len: usize) -> usize {
+    match len {
+        0..=8 => len,
+        9..=12 => 12,
+        13..=16 => 16,
+        17..=20 => 20,
+        21..=24 => 24,
+        25..=32 => 32,
+        33..=48 => 48,
+        _ => 64,
+    }
+}
+
+/// Enable the transmission completed interrupt of every hardware transmit
+/// buffer of an interface.
+///
+/// `Interrupts::TX_COMPLETE` only fires for buffers enabled in `TXBTIE`,
+/// which the driver doesn't set.
+pub fn enable_complete_interrupt(interface: u8) {
+    // SAFETY: only written here, during init. The driver never touches
+    // the register.
+    match interface {
+        0 => unsafe { &*FDCAN2::ptr() }
+            .txbtie
+            .write(|w| unsafe { w.bits(TX_BUFFERS) }),
+        1 => unsafe { &*FDCAN3::ptr() }
+            .txbtie
+            .write(|w| unsafe { w.bits(TX_BUFFERS) }),
+        _ => unsafe { &*FDCAN1::ptr() }
+            .txbtie
+            .write(|w| unsafe { w.bits(TX_BUFFERS) }),
+    }
+}
+
+/// Frames waiting for a free hardware transmit buffer.
+pub struct TxQueue {
+    frames: Deque<TxFrame, TX_QUEUE_LEN>,
+    /// Minimum gap between the start of consecutive transmissions. Zero
+    /// disables pacing.
+    ///
+    /// Resolution is limited to the monotonic tick (100 µs).
+    pub gap: Duration,
+    /// Time of the last transmission.
+    last: Option<Instant>,
+    /// Core cycle count when the last sync frame was handed to the
+    /// hardware.
+    pub sync_cycles: Option<u32>,
+    /// Frames handed to the hardware.
+    pub sent: u32,
+    /// Payload bytes of the frames handed to the hardware, including FD
+    /// padding.
+    pub sent_bytes: u32,
+    /// Byte used to pad FD payloads up to a valid length.
+    pub padding: u8,
+    /// Hand the highest priority identifier to the hardware first rather
+    /// than the oldest frame. Kept across restarts.
+    pub priority: bool,
+    /// Marker of the frame in each hardware transmit buffer, until it's
+    /// sent or given up on.
+    markers: [Option<u8>; HARDWARE_BUFFERS],
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        Self {
+            frames: Deque::new(),
+            gap: Duration::from_ticks(0),
+            last: None,
+            sync_cycles: None,
+            sent: 0,
+            sent_bytes: 0,
+            padding: DEFAULT_PADDING,
+            priority: false,
+            markers: [None; HARDWARE_BUFFERS],
+        }
+    }
+}
+
+impl TxQueue {
+    /// Queue a frame for transmission, padding FD payloads.
+    ///
+    /// If the queue is full the oldest frame is dropped and returned.
+    pub fn push(&mut self, mut frame: TxFrame) -> Option<TxFrame> {
+        frame.pad(self.padding);
+
+        let dropped = if self.frames.is_full() {
+            self.frames.pop_front()
+        } else {
+            None
+        };
+
+        // cannot fail as space was made above.
+        let _ = self.frames.push_back(frame);
+
+        dropped
+    }
+
+    /// Number of queued frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Discard all queued frames.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.markers = [None; HARDWARE_BUFFERS];
+    }
+
+    /// Give up on the echoes of frames whose transmission failed, e.g. in
+    /// one shot mode, or was cancelled. They never get a TX event to
+    /// complete them.
+    fn resolve_failed(&mut self, buffers: &Buffers, echoes: &mut Echoes) {
+        for (buffer, marker) in self.markers.iter_mut().enumerate() {
+            let mask = 1 << buffer;
+            if buffers.occurred & mask != 0 {
+                *marker = None;
+            } else if buffers.cancelled & mask != 0 {
+                if let Some(marker) = marker.take() {
+                    echoes.abandon(marker);
+                }
+            }
+        }
+    }
+
+    /// Position of the next frame to hand to the hardware.
+    fn next(&self) -> Option<usize> {
+        match self.priority {
+            true => self
+                .frames
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, frame)| arbitration_key(frame.header.id))
+                .map(|(index, _)| index),
+            false => (!self.frames.is_empty()).then_some(0),
+        }
+    }
+
+    /// Remove the frame at `index`, keeping the others in order.
+    fn remove(&mut self, index: usize) {
+        for n in 0..self.frames.len() {
+            let Some(frame) = self.frames.pop_front() else {
+                return;
+            };
+            if n != index {
+                // cannot fail as a frame was just taken off.
+                let _ = self.frames.push_back(frame);
+            }
+        }
+    }
+
+    /// Move as many queued frames into the hardware transmit buffers as
+    /// pacing and buffer space allow, recording them in `history`.
+    ///
+    /// Frames handed over earlier that failed have their echoes given up
+    /// on in `echoes`.
+    ///
+    /// Returns the time at which the next frame may be sent if transmission
+    /// is being held back by pacing.
+    pub fn drain<I: fdcan::Instance, M: Transmit>(
+        &mut self,
+        can: &mut FdCan<I, M>,
+        interface: u8,
+        history: &mut TxHistory,
+        echoes: &mut Echoes,
+    ) -> Option<Instant> {
+        // Frames handed over earlier may have finished since last time.
+        let buffers = Buffers::read(interface);
+        history.resolve(&buffers);
+        self.resolve_failed(&buffers, echoes);
+
+        while let Some(index) = self.next() {
+            let now = Mono::now();
+
+            if self.gap.ticks() != 0 {
+                if let Some(last) = self.last {
+                    let next = last + self.gap;
+                    if now < next {
+                        return Some(next);
+                    }
+                }
+            }
+
+            let buffers = Buffers::read(interface);
+            history.resolve(&buffers);
+            self.resolve_failed(&buffers, echoes);
+
+            // With every buffer pending the controller would abort one of
+            // them for this frame, so wait for a buffer to free up instead.
+            if buffers.full {
+                return None;
+            }
+
+            // cannot fail as `next` found it.
+            let Some(frame) = self.frames.iter().nth(index) else {
+                return None;
+            };
+
+            let result = can.transmit_preserve(
+                frame.header,
+                frame.data(),
+                &mut |mailbox, header, data: &[u32]| {
+                    (mailbox as usize, TxFrame::preempted(header, data))
+                },
+            );
+            match result {
+                Ok(preempted) => {
+                    history.record(frame, buffers.put, preempted.is_some());
+                    // a frame that preempted another takes over its buffer.
+                    let buffer = preempted
+                        .as_ref()
+                        .map_or(buffers.put as usize, |(buffer, _)| *buffer);
+                    self.markers[buffer % HARDWARE_BUFFERS] =
+                        frame.header.marker;
+                    if frame.sync {
+                        self.sync_cycles = Some(DWT::cycle_count());
+                    }
+                    let len = frame.data().len() as u32;
+                    self.remove(index);
+                    self.last = Some(now);
+                    self.sent = self.sent.wrapping_add(1);
+                    self.sent_bytes = self.sent_bytes.wrapping_add(len);
+
+                    // Not expected as the buffers were checked above, but a
+                    // frame taken back goes out next rather than being lost.
+                    if let Some((_, frame)) = preempted {
+                        defmt::warn!("Pending frame requeued");
+                        let len = frame.data().len() as u32;
+                        self.sent = self.sent.wrapping_sub(1);
+                        self.sent_bytes = self.sent_bytes.wrapping_sub(len);
+                        // cannot fail as a frame was just removed.
+                        let _ = self.frames.push_front(frame);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => return None,
+                Err(nb::Error::Other(e)) => match e {},
+            }
+        }
+
+        None
+    }
+}
+
+/// Sort key giving an identifier's bus arbitration priority, lowest first.
+///
+/// The base identifier is compared first, and a standard frame wins over an
+/// extended frame with the same base identifier.
+fn arbitration_key(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => (id.as_raw() as u32) << 19,
+        Id::Extended(id) => (id.as_raw() << 1) | 1,
+    }
+}
@@ -0,0 +1,82 @@
+//! Uptime carried across resets for log timestamps.
+//!
+//! The uptime is saved periodically to RAM that isn't initialised at boot,
+//! so it survives resets, including watchdog resets and firmware updates,
+//! but not power loss. Each boot continues its timestamps from where the
+//! last one stopped so logs from a reboot loop can be put in order.
+
+use core::{
+    mem::MaybeUninit,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Marks the saved uptime as valid.
+const MAGIC: u32 = 0x7570_7469;
+/// How often the uptime is saved in milliseconds. Added to the saved uptime
+/// at boot so timestamps never go backwards.
+pub const SAVE_INTERVAL_MS: u32 = 100;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Saved {
+    magic: u32,
+    /// Milliseconds since power on.
+    uptime_ms: u32,
+    /// Boots since power on.
+    boots: u32,
+}
+
+#[link_section = ".uninit.UPTIME"]
+static mut SAVED: MaybeUninit<Saved> = MaybeUninit::uninit();
+
+/// Milliseconds since power on at the start of this boot.
+static ORIGIN_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Continue from the uptime saved before the last reset.
+///
+/// Must be called once at boot before [`save`]. Returns the number of boots
+/// since power on, including this one.
+pub fn restore() -> u32 {
+    // SAFETY: only accessed from init and then the single task calling
+    // `save`. Memory left uninitialised after power on is caught by the
+    // magic number.
+    let saved = unsafe { (*addr_of_mut!(SAVED)).as_mut_ptr() };
+    let last = unsafe { saved.read_volatile() };
+
+    let (origin_ms, boots) = match last.magic == MAGIC {
+        true => (
+            last.uptime_ms.wrapping_add(SAVE_INTERVAL_MS),
+            last.boots.wrapping_add(1),
+        ),
+        false => (0, 1),
+    };
+
+    ORIGIN_MS.store(origin_ms, Ordering::Relaxed);
+    unsafe {
+        saved.write_volatile(Saved {
+            magic: MAGIC,
+            uptime_ms: origin_ms,
+            boots,
+        })
+    };
+
+    boots
+}
+
+/// Save the uptime so the next boot continues from it.
+pub fn save(since_boot_ms: u32) {
+    let origin_ms = ORIGIN_MS.load(Ordering::Relaxed);
+
+    // SAFETY: see `restore`.
+    unsafe {
+        let saved = (*addr_of_mut!(SAVED)).as_mut_ptr();
+        addr_of_mut!((*saved).uptime_ms)
+            .write_volatile(origin_ms.wrapping_add(since_boot_ms));
+    }
+}
+
+/// Microseconds since power on given the microseconds since boot.
+pub fn micros(since_boot_us: u64) -> u64 {
+    ORIGIN_MS.load(Ordering::Relaxed) as u64 * 1000 + since_boot_us
+}
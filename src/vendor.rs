@@ -0,0 +1,1921 @@
+//! Vendor specific control requests.
+//!
+//! Requests are addressed to the device rather than an interface so they
+//! can't collide with the gs_usb class requests.
+
+use crate::{
+    can::{self, Presence, Retransmit, SelfTest, Tdc, UsbCanDevice},
+    capture, chip, dfu, echo,
+    error::Error,
+    event,
+    hal::stm32::FLASH,
+    latency, otp,
+    report::ErrorReport,
+    route::Rule,
+    rx,
+    settings::{self, Settings},
+    timestamp,
+    transceiver::Transceivers,
+    tx::{Duration, TxFrame, CORE_CLOCK_MHZ},
+    vpd::{self, VitalProductData},
+};
+use fdcan::{
+    config::TxBufferMode,
+    id::{ExtendedId, Id, StandardId},
+};
+use usb_device::class_prelude::*;
+use usbd_gscan::{
+    host::{DeviceBitTiming, Feature, FrameFlag},
+    GsCan,
+};
+
+/// Full-speed bulk endpoint packet size.
+const USB_PACKET_SIZE: u32 = 64;
+/// Maximum bulk packets per 1 ms full-speed frame, assuming no other
+/// traffic on the bus.
+const USB_PACKETS_PER_MS: u32 = 19;
+/// gs_usb host frame header size.
+const GS_HEADER_SIZE: u32 = 12;
+/// Header size of a frame sent in a vendor request data stage.
+const FRAME_HEADER_SIZE: usize = 8;
+/// Extended identifier flag in a vendor request frame.
+const FRAME_ID_EXTENDED: u32 = 1 << 31;
+/// USB interfaces: gs_usb, DFU and error reports, allocated in that order.
+const USB_INTERFACES: usize = 3;
+/// Default bus presence check window in milliseconds.
+const PRESENCE_WINDOW_MS: u16 = 100;
+/// Longest bus presence check window in milliseconds.
+const MAX_PRESENCE_WINDOW_MS: u16 = 5000;
+/// Time the self-test frame has to be sent in, long enough for the slowest
+/// bit rate and some arbitration losses.
+const SELF_TEST_WINDOW_MS: u64 = 100;
+/// gs_usb request reading the device timestamp counter.
+const GS_USB_BREQ_TIMESTAMP: u8 = 6;
+/// `wValue` disabling transmitter delay compensation.
+const TDC_DISABLE: u16 = 0xffff;
+/// Firmware version returned by [`Request::Version`].
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " ",
+    env!("CRATE_GIT_HASH"),
+    " ",
+    env!("CRATE_BUILT_AT"),
+    " ",
+    env!("CRATE_RELEASE_CHANNEL"),
+);
+
+/// Vendor request codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Request {
+    /// Read a diagnostic value (IN).
+    ///
+    /// `wValue` selects the [`Diagnostic`] and `wIndex` the interface.
+    Diagnostics = 0,
+    /// Reset a diagnostic value (OUT).
+    ///
+    /// `wValue` selects the [`Diagnostic`] and `wIndex` the interface.
+    ResetDiagnostics = 1,
+    /// Get (IN) or set (OUT) the minimum gap between transmitted frames.
+    ///
+    /// The gap is in microseconds; `wValue` holds the new gap when setting
+    /// and `wIndex` selects the interface. Zero disables pacing.
+    TransmitGap = 2,
+    /// Save (`wValue` = 0) the current runtime settings to flash so they
+    /// are restored on boot, or clear them and restore defaults
+    /// (`wValue` = 1) (OUT). Flash is written in the background after the
+    /// request is accepted.
+    Settings = 3,
+    /// Read back the programmed bit timing of the interface in `wIndex`
+    /// (IN).
+    ///
+    /// Returned as `[nbtp, dbtp, nominal_bit_rate, nominal_sample_point,
+    /// data_bit_rate, data_sample_point]` where the first two are the raw
+    /// register values, bit rates are in bit/s and sample points in per
+    /// mille.
+    BitTiming = 4,
+    /// Queue a frame on both interfaces at once (OUT) or read the skew
+    /// between the last such frames being handed to the hardware (IN).
+    ///
+    /// The OUT data stage holds `[id: u32, flags: u8, len: u8, reserved:
+    /// u16, data]` where bit 31 of `id` selects an extended identifier and
+    /// `flags` are gs_usb frame flags. Rejected unless both interfaces are
+    /// started.
+    ///
+    /// The skew is returned as `[skew_ns]` and the request is rejected
+    /// until both frames have been handed over.
+    TransmitBoth = 5,
+    /// Control the transmit test pattern of the interface in `wIndex`
+    /// (OUT) or read its status (IN).
+    ///
+    /// `wValue` selects the action: 0 clears the pattern, 1 appends the
+    /// frame in the data stage (same layout as [`Request::TransmitBoth`]), 2
+    /// starts and 3 stops transmission. Starting is rejected unless the
+    /// interface is started.
+    ///
+    /// Status is returned as `[running, frames, sent, bus_off]` where
+    /// `sent` and `bus_off` count since the pattern was last started.
+    Pattern = 6,
+    /// Forward (`wValue` = 0) or suppress (`wValue` = 1) frames received in
+    /// loopback mode on the interface in `wIndex` (OUT).
+    LoopbackEcho = 7,
+    /// Read recent events, oldest first, skipping the first `wValue` (IN)
+    /// or clear them (OUT).
+    ///
+    /// As many 8 byte events as fit in `wLength` are returned. See
+    /// [`crate::event`] for the format.
+    EventLog = 8,
+    /// Get (IN) or set (OUT) the byte used to pad FD payloads up to a length
+    /// a DLC can encode.
+    ///
+    /// `wValue` holds the new byte when setting and `wIndex` selects the
+    /// interface. Defaults to `0xcc`.
+    Padding = 9,
+    /// Listen for valid frames at the configured bit rate on every stopped
+    /// interface for `wValue` milliseconds (OUT) or read the result (IN).
+    ///
+    /// Interfaces are put in bus monitoring mode for the check so nothing
+    /// is transmitted, not even acknowledgements. Started interfaces are
+    /// left alone. A window of zero uses the default of 100 ms. Rejected if
+    /// a check is already running or every interface is started.
+    ///
+    /// The result is returned as one word per interface, see
+    /// [`presence_status`].
+    Presence = 10,
+    /// Get (IN) or set (OUT) the limit on frames per second forwarded to the
+    /// host from the interface in `wIndex`.
+    ///
+    /// `wValue` holds the new limit when setting. Zero, the default,
+    /// disables the limit. Frames over the limit are dropped and counted.
+    /// Returned as `[limit, dropped]`.
+    HostRateLimit = 11,
+    /// Read silicon identification (IN).
+    ///
+    /// Returned as `[flash_size_kb, device_id, revision_id, package, uid0,
+    /// uid1, uid2]` so host tooling can check the part before flashing.
+    Chip = 12,
+    /// Get (IN) or set (OUT) transmitter delay compensation of the interface
+    /// in `wIndex`.
+    ///
+    /// `wValue` holds the secondary sample point offset in the low byte and
+    /// the filter window in the high byte, both in minimum time quanta and
+    /// at most 127. `0xffff` disables compensation. Returned as `[enabled,
+    /// offset, filter]`. Compensation only works with a data prescaler of
+    /// 1 or 2.
+    TransmitterDelay = 13,
+    /// Read the firmware version (IN).
+    ///
+    /// Returned as ASCII text: the version, git hash, build time and
+    /// release channel separated by spaces.
+    Version = 14,
+    /// Get (IN) or set (OUT) error statistics reports.
+    ///
+    /// `wValue` holds the minimum time between reports in 100 ms periods,
+    /// zero to disable them, and `wIndex` the smallest error counter change
+    /// reported. Returned as `[interval_ms, threshold]`. See
+    /// [`crate::report`] for the report format.
+    ErrorReports = 15,
+    /// Set the nominal and data bit timing of both interfaces in one
+    /// operation (OUT) or read the result for each interface (IN).
+    ///
+    /// The OUT data stage holds `[prop_seg, phase_seg1, phase_seg2, sjw,
+    /// brp]` as `u32` words for the nominal then data timing of interface 0,
+    /// then the same for interface 1. Nothing is changed, and the request is
+    /// rejected, unless both are valid.
+    ///
+    /// The result is returned as one word per interface: 0 not set, 1
+    /// applied and 2 rejected.
+    BitTimingBoth = 16,
+    /// Take both interfaces off the bus and reset the device (OUT).
+    ///
+    /// The reset follows within 100 ms so the status stage reaches the host
+    /// first.
+    Reset = 17,
+    /// Get (IN) or set (OUT) the sample points of an interface, keeping its
+    /// prescaler and bit rate.
+    ///
+    /// `wValue` holds the new sample point in per mille when setting. The
+    /// low byte of `wIndex` selects the interface and the high byte the
+    /// phase, 0 for nominal and 1 for data. Returned as `[nominal, data]`
+    /// in per mille, as achieved after rounding to whole time quanta.
+    SamplePoint = 18,
+    /// Get (IN) or set (OUT) protocol exception handling of the interface
+    /// in `wIndex`.
+    ///
+    /// `wValue` is 1 to enable and 0 to disable it. Disabled by default.
+    /// Returned as `[enabled]`.
+    ProtocolException = 19,
+    /// Change the gateway routing table (OUT) or read it (IN).
+    ///
+    /// `wValue` selects the action: 0 clears the table and 1 appends the
+    /// rule in the data stage. Rules are returned in the order they were
+    /// added. See [`crate::route`] for the rule format.
+    Routing = 20,
+    /// Queue a frame on the interface in `wIndex` tagged with the marker in
+    /// the low byte of `wValue` (OUT), or read and remove the markers of
+    /// tagged frames that have been sent (IN).
+    ///
+    /// The OUT data stage holds a frame with the same layout as
+    /// [`Request::TransmitBoth`]. Rejected unless the interface is started,
+    /// and for markers from [`echo::MARKER_BASE`] up, which tag gs_usb
+    /// frames for their echoes. As many 4 byte completions as fit in `wLength`
+    /// are returned, oldest first. See [`crate::marker`] for the format.
+    TransmitMarked = 21,
+    /// Get (IN) or set (OUT) the heartbeat interval.
+    ///
+    /// `wValue` holds the time between heartbeats in 100 ms periods, zero,
+    /// the default, to disable them. Returned as `[interval_ms, sent]`. See
+    /// [`crate::report`] for the heartbeat format.
+    Heartbeat = 22,
+    /// Set the transmit buffer mode of the interface in `wIndex` (OUT).
+    ///
+    /// `wValue` is 0 to send buffered frames in the order they were queued
+    /// and 1 to send the highest priority identifier first. Read back with
+    /// [`Diagnostic::TxMode`].
+    TxMode = 23,
+    /// Read the frames of the interface in `wIndex` still waiting to be
+    /// sent (IN).
+    ///
+    /// Returned as `[buffers, queued, total]` where `buffers` has a bit set
+    /// for each hardware transmit buffer with a pending request, `queued` is
+    /// the software transmit queue depth and `total` both combined. Poll
+    /// until `total` is zero to wait for everything sent so far to reach the
+    /// bus.
+    TxPending = 24,
+    /// Get (IN) or set (OUT) transceiver standby of the interface in
+    /// `wIndex`.
+    ///
+    /// `wValue` is 1 to put the transceiver in standby and 0 to wake it. A
+    /// started interface is stopped before going to standby. Rejected if the
+    /// interface has no standby pin in the VPD. Returned as
+    /// `[fitted, standby]`.
+    Standby = 25,
+    /// Start measuring USB to CAN latency on the interface in `wIndex`
+    /// (OUT) or read the results (IN).
+    ///
+    /// `wValue` holds the number of frames to measure, zero for the default
+    /// of 32. Rejected unless the interface is started. Returned as
+    /// `[state, samples, lost, min_us, avg_us, max_us]`, see
+    /// [`crate::latency`].
+    Latency = 26,
+    /// Set automatic retransmission of the interface in `wIndex` (OUT).
+    ///
+    /// `wValue` is 0, the default, to follow the gs_usb one shot feature,
+    /// 1 to always disable it and 2 to always enable it. Kept across
+    /// restarts. Read back with [`Diagnostic::Retransmit`].
+    Retransmit = 27,
+    /// Start (OUT, `wValue` 1) or stop (OUT, `wValue` 0) capturing received
+    /// frames, or read the captured frames (IN).
+    ///
+    /// Starting discards frames from an earlier capture. Frames are
+    /// returned oldest first, as many as fit in `wLength` after skipping
+    /// `wValue`. See [`crate::capture`] for the format.
+    Capture = 28,
+    /// CRC-32 of the OTP memory (IN), so provisioning can check the VPD
+    /// was written without reading it back.
+    ///
+    /// `wValue` holds the number of bytes to check from the start of OTP,
+    /// zero for all 1024. Rejected if larger. Returned as `[crc, len]`,
+    /// with the same CRC-32 as zlib.
+    OtpCrc = 29,
+    /// Set the software transmit queue order of the interface in `wIndex`
+    /// (OUT).
+    ///
+    /// `wValue` is 0, the default, to hand frames to the controller oldest
+    /// first and 1 to hand over the highest priority identifier first. Kept
+    /// across restarts. Read back with [`Diagnostic::TxMode`].
+    TxPriority = 30,
+    /// Read the bit rate capabilities (IN), so host tools can offer only
+    /// rates the adapter can reach.
+    ///
+    /// Returned as `[clock, nominal_max, data_max, data_practical, tdc]`:
+    /// the CAN clock in Hz, the fastest nominal and data bit rates the
+    /// timing limits allow, the fastest data bit rate with at least 8 time
+    /// quanta per bit, all in bit/s, and 1 if transmitter delay
+    /// compensation is available.
+    Capabilities = 31,
+    /// Re-read the VPD from OTP and apply what can change while running
+    /// (IN).
+    ///
+    /// The transceiver standby pins and populated interface count are
+    /// updated. Strings reported in the USB descriptors, default bit rates
+    /// and the startup delay only take effect after a reset. Rejected if the
+    /// OTP is blank or can't be parsed. Returned as `[year, week, seq,
+    /// hardware, sku, nominal, data, pins, startup_delay_ms]`, where
+    /// `hardware` packs the major, minor, patch and pre-release numbers
+    /// from the high byte down and `pins` is the `PINS` tag.
+    ReloadVpd = 32,
+    /// Start an external loopback self-test of the interface in `wIndex`
+    /// (OUT) or read its result (IN).
+    ///
+    /// A frame is sent at the configured bit rate with the controller
+    /// reading it back through the transceiver, so the whole path to the
+    /// bus is tested without a second node. It does drive the bus, so run
+    /// it with the bus disconnected. The interface must be stopped and is
+    /// left as it was. Rejected if the interface is started, or already
+    /// being tested or checked for bus presence. The result is returned as
+    /// `[status]`, see [`self_test_status`].
+    SelfTest = 33,
+    /// Get (IN) or set (OUT) how long the interface in `wIndex` waits in
+    /// bus-off before recovering, like SocketCAN's `restart-ms`.
+    ///
+    /// `wValue` holds the delay in milliseconds when setting, 100 by
+    /// default, with 0 leaving the interface bus-off until the host
+    /// restarts it. Bus-off is checked every 100 ms. Kept across restarts.
+    /// Returned as `[delay_ms]`.
+    RestartDelay = 34,
+}
+
+impl TryFrom<u8> for Request {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Diagnostics as u8 => Ok(Self::Diagnostics),
+            x if x == Self::ResetDiagnostics as u8 => {
+                Ok(Self::ResetDiagnostics)
+            }
+            x if x == Self::TransmitGap as u8 => Ok(Self::TransmitGap),
+            x if x == Self::Settings as u8 => Ok(Self::Settings),
+            x if x == Self::BitTiming as u8 => Ok(Self::BitTiming),
+            x if x == Self::TransmitBoth as u8 => Ok(Self::TransmitBoth),
+            x if x == Self::Pattern as u8 => Ok(Self::Pattern),
+            x if x == Self::LoopbackEcho as u8 => Ok(Self::LoopbackEcho),
+            x if x == Self::EventLog as u8 => Ok(Self::EventLog),
+            x if x == Self::Padding as u8 => Ok(Self::Padding),
+            x if x == Self::Presence as u8 => Ok(Self::Presence),
+            x if x == Self::HostRateLimit as u8 => Ok(Self::HostRateLimit),
+            x if x == Self::Chip as u8 => Ok(Self::Chip),
+            x if x == Self::TransmitterDelay as u8 => {
+                Ok(Self::TransmitterDelay)
+            }
+            x if x == Self::Version as u8 => Ok(Self::Version),
+            x if x == Self::ErrorReports as u8 => Ok(Self::ErrorReports),
+            x if x == Self::BitTimingBoth as u8 => Ok(Self::BitTimingBoth),
+            x if x == Self::Reset as u8 => Ok(Self::Reset),
+            x if x == Self::SamplePoint as u8 => Ok(Self::SamplePoint),
+            x if x == Self::ProtocolException as u8 => {
+                Ok(Self::ProtocolException)
+            }
+            x if x == Self::Routing as u8 => Ok(Self::Routing),
+            x if x == Self::TransmitMarked as u8 => Ok(Self::TransmitMarked),
+            x if x == Self::Heartbeat as u8 => Ok(Self::Heartbeat),
+            x if x == Self::TxMode as u8 => Ok(Self::TxMode),
+            x if x == Self::TxPending as u8 => Ok(Self::TxPending),
+            x if x == Self::Standby as u8 => Ok(Self::Standby),
+            x if x == Self::Latency as u8 => Ok(Self::Latency),
+            x if x == Self::Retransmit as u8 => Ok(Self::Retransmit),
+            x if x == Self::Capture as u8 => Ok(Self::Capture),
+            x if x == Self::OtpCrc as u8 => Ok(Self::OtpCrc),
+            x if x == Self::TxPriority as u8 => Ok(Self::TxPriority),
+            x if x == Self::Capabilities as u8 => Ok(Self::Capabilities),
+            x if x == Self::ReloadVpd as u8 => Ok(Self::ReloadVpd),
+            x if x == Self::SelfTest as u8 => Ok(Self::SelfTest),
+            x if x == Self::RestartDelay as u8 => Ok(Self::RestartDelay),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Diagnostic values.
+///
+/// Unless noted otherwise values are returned as little-endian `u32` words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Diagnostic {
+    /// Overrun counts as `[fifo0, fifo1, queue, tx_queue]`, where `queue`
+    /// counts frames dropped because the software receive queue was full
+    /// and `tx_queue` the oldest frames dropped from a full transmit queue
+    /// to make room for frames from the host.
+    Overruns = 0,
+    /// USB link as `[speed, packet_size, hp_interrupts, lp_interrupts,
+    /// max_classic_fps, max_fd_fps]`. The interface is ignored.
+    ///
+    /// Speed is always 1 (full-speed, 12 Mbit/s). The maximum frame rates
+    /// are the theoretical best case for 8 byte classic and 64 byte FD
+    /// frames given the packet size; real host controllers schedule fewer
+    /// packets per frame.
+    Usb = 1,
+    /// Fault state as `[faulted, bus_off]`. `faulted` is set when bus-off
+    /// recovery has repeatedly failed and the interface has been taken out
+    /// of service until it's restarted by the host or this diagnostic is
+    /// reset.
+    Fault = 2,
+    /// Loopback as `[loopback, suppressed]`. `loopback` is set while the
+    /// interface is in loopback mode and `suppressed` when frames received
+    /// in loopback mode aren't forwarded to the host.
+    Loopback = 3,
+    /// Status of every interface as one word each, 0 if the interface isn't
+    /// populated on this SKU, 1 if it's present and 2 if it has faulted.
+    /// The interface is ignored.
+    Interfaces = 4,
+    /// Hardware filter matches as `[last_filter, matched, unmatched]`.
+    /// `last_filter` is the index of the filter that accepted the most
+    /// recent frame, or `0xffffffff` if it was accepted without matching a
+    /// filter. gs_usb frames have no room for the filter index so it's
+    /// reported here instead.
+    Filter = 5,
+    /// Features as `[started, features, unsupported]` where `features` are
+    /// the gs_usb feature flags the host started the interface with, or 0
+    /// while it's stopped, and `unsupported` those of them this firmware
+    /// doesn't implement and ignores.
+    Features = 6,
+    /// Counts since the interface was last started as `[rx_frames,
+    /// tx_frames, errors, overruns]`. `errors` counts protocol errors that
+    /// incremented the transmit or receive error counter.
+    Session = 7,
+    /// Restricted operation as `[restricted, count]`. The controller enters
+    /// restricted operation when it can't read a frame from message RAM in
+    /// time to transmit it, after which it receives but doesn't transmit.
+    /// Resetting this diagnostic takes the interface out of restricted
+    /// operation.
+    Restricted = 8,
+    /// Receive timestamps as `[rx_timestamp, now]`. `rx_timestamp` is the
+    /// hardware timestamp of the last frame received and `now` the current
+    /// time, both in microseconds on the time base shared by the
+    /// interfaces, extended to 32 bits as for the gs_usb timestamp request.
+    /// See [`crate::timestamp`].
+    Timestamp = 9,
+    /// Frames recently handed to the controller, oldest first, as 12 byte
+    /// records rather than words. As many as fit in `wLength` are
+    /// returned. See [`crate::history`] for the format.
+    TxHistory = 10,
+    /// Acknowledgement errors as `[ack_errors]`, counting error samples
+    /// (every 100 ms) where the last protocol error was a missing
+    /// acknowledgement. Usually a sign of missing bus termination.
+    AckErrors = 11,
+    /// USB configuration as `[configuration, interfaces, gs_usb_alt,
+    /// dfu_alt, report_alt]`. `configuration` is the value selected by the
+    /// host, 0 while unconfigured, and the alternate settings are those last
+    /// selected for each interface. Interfaces are numbered in the order
+    /// listed. The interface is ignored.
+    UsbConfig = 12,
+    /// Flash operation times observed during firmware updates as
+    /// `[erase_min, erase_max, erases, program_min, program_max, programs]`
+    /// where times are in microseconds at 100 µs resolution. `program` times
+    /// are for one transfer. Zero until measured. The interface is ignored.
+    FlashTiming = 13,
+    /// Why the interface last went down as `[reason, time_ms]`. `reason`
+    /// is a [`crate::can::StopReason`] and `time_ms` milliseconds since
+    /// boot. Kept across restarts so the host can find out why a link went
+    /// down.
+    StopReason = 14,
+    /// Receive interrupts that found the interface being reconfigured as
+    /// `[deferred]`. Their frames are left in the FIFO and forwarded once
+    /// reconfiguration finishes.
+    DeferredReceive = 15,
+    /// Transmit buffer mode as `[mode, priority]`. `mode` is 0 for FIFO and
+    /// 1 for priority queue, see [`Request::TxMode`], and `priority` is 1 if
+    /// the software queue hands over the highest priority identifier first,
+    /// see [`Request::TxPriority`].
+    TxMode = 16,
+    /// Frames dropped because their identifier was out of range as
+    /// `[received, from_host, last_id]`. `last_id` is the last identifier
+    /// dropped, with bit 31 set if it was extended, or zero if none has
+    /// been.
+    InvalidId = 17,
+    /// Automatic retransmission as `[setting, enabled]`, where `setting` is
+    /// as set by [`Request::Retransmit`] and `enabled` is whether the
+    /// controller currently retransmits failed frames.
+    Retransmit = 18,
+    /// Most frames seen waiting at once as `[fifo0, fifo1, queue,
+    /// queue_depth]`. The hardware FIFOs hold 3 frames each and the
+    /// software receive queue `queue_depth`.
+    RxPeak = 19,
+    /// Fault confinement state as `[state, warning, passive, bus_off]`.
+    /// `state` is 0 error active, 1 warning, 2 passive or 3 bus-off as of
+    /// the last error sample, followed by the times each was entered.
+    ErrorState = 20,
+    /// Last bit timing from the host rejected as out of range, as `[count,
+    /// data, requested, nearest]`. `data` is 1 for data bit timing,
+    /// `requested` the bit rate it asked for, zero if unknown, and
+    /// `nearest` the closest bit rate the limits allow. All zero if none
+    /// has been rejected.
+    RejectedTiming = 21,
+    /// FD status as `[fd, brs, dropped]`. `fd` and `brs` are whether the
+    /// controller currently has FD operation and bit rate switching
+    /// enabled, set when the host starts the interface with or without FD,
+    /// and `dropped` counts FD frames dropped while it was classic only and
+    /// remote frames flagged as FD.
+    Fd = 22,
+    /// Received frames with an impossible length as `[count, last_len]`.
+    /// Their length is clamped to 8 bytes for classic and 64 for FD frames,
+    /// and FD lengths in between the steps a DLC encodes are rounded up.
+    BadLength = 23,
+    /// Causes of the last reset as `[flags]`, one bit each in the order of
+    /// the `RCC_CSR` flags: bit 0 option byte load, 1 reset pin, 2
+    /// brown-out, 3 software, 4 independent watchdog, 5 window watchdog and
+    /// 6 low power. More than one can be set. The hardware flags are
+    /// cleared at boot, so this only ever covers the last reset. Resetting
+    /// the diagnostic clears it until the next boot.
+    ResetReason = 24,
+    /// Bus stuck dominant as `[stuck, count]`. `stuck` is 1 while the
+    /// started controller hasn't been able to synchronise to the bus for a
+    /// second, and `count` the times that happened.
+    StuckDominant = 25,
+    /// Traffic statistics as `[rx_frames, tx_frames, rx_bytes, tx_bytes,
+    /// overruns, tx_dropped, bus_off]`. Frames and bytes count since boot
+    /// rather than since the interface was started, and wrap. `overruns`
+    /// adds up both RX FIFOs, `tx_dropped` counts frames dropped with the
+    /// transmit queue full and `bus_off` the times the interface went
+    /// bus-off. Resetting clears only the frame and byte counts.
+    Statistics = 26,
+}
+
+impl TryFrom<u16> for Diagnostic {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Overruns as u16 => Ok(Self::Overruns),
+            x if x == Self::Usb as u16 => Ok(Self::Usb),
+            x if x == Self::Fault as u16 => Ok(Self::Fault),
+            x if x == Self::Loopback as u16 => Ok(Self::Loopback),
+            x if x == Self::Interfaces as u16 => Ok(Self::Interfaces),
+            x if x == Self::Filter as u16 => Ok(Self::Filter),
+            x if x == Self::Features as u16 => Ok(Self::Features),
+            x if x == Self::Session as u16 => Ok(Self::Session),
+            x if x == Self::Restricted as u16 => Ok(Self::Restricted),
+            x if x == Self::Timestamp as u16 => Ok(Self::Timestamp),
+            x if x == Self::TxHistory as u16 => Ok(Self::TxHistory),
+            x if x == Self::AckErrors as u16 => Ok(Self::AckErrors),
+            x if x == Self::UsbConfig as u16 => Ok(Self::UsbConfig),
+            x if x == Self::FlashTiming as u16 => Ok(Self::FlashTiming),
+            x if x == Self::StopReason as u16 => Ok(Self::StopReason),
+            x if x == Self::DeferredReceive as u16 => Ok(Self::DeferredReceive),
+            x if x == Self::TxMode as u16 => Ok(Self::TxMode),
+            x if x == Self::InvalidId as u16 => Ok(Self::InvalidId),
+            x if x == Self::Retransmit as u16 => Ok(Self::Retransmit),
+            x if x == Self::RxPeak as u16 => Ok(Self::RxPeak),
+            x if x == Self::ErrorState as u16 => Ok(Self::ErrorState),
+            x if x == Self::RejectedTiming as u16 => Ok(Self::RejectedTiming),
+            x if x == Self::Fd as u16 => Ok(Self::Fd),
+            x if x == Self::BadLength as u16 => Ok(Self::BadLength),
+            x if x == Self::ResetReason as u16 => Ok(Self::ResetReason),
+            x if x == Self::StuckDominant as u16 => Ok(Self::StuckDominant),
+            x if x == Self::Statistics as u16 => Ok(Self::Statistics),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Device state reported by vendor requests that isn't owned by a USB class.
+pub struct State<B: UsbBus> {
+    /// USB high priority interrupts serviced.
+    pub usb_hp_interrupts: u32,
+    /// USB low priority interrupts serviced.
+    pub usb_lp_interrupts: u32,
+    /// Number of interfaces populated according to the VPD SKU.
+    pub interfaces: u8,
+    /// Error statistics reports.
+    pub report: ErrorReport<B>,
+    /// Result of the last [`Request::BitTimingBoth`] for each interface.
+    pub timing_both: [u32; 2],
+    /// The host requested a reset.
+    pub reset_pending: bool,
+    /// Configuration value selected by the host, 0 while unconfigured.
+    pub configuration: u8,
+    /// Alternate setting last selected for each interface.
+    pub alt_settings: [u8; USB_INTERFACES],
+    /// Product data re-read by [`Request::ReloadVpd`], waiting to replace
+    /// the copy kept with the carrier's.
+    pub vpd_reload: Option<VitalProductData>,
+    /// Causes of the last reset, captured at boot before the flags are
+    /// cleared. See [`Diagnostic::ResetReason`].
+    pub reset_flags: u32,
+    /// Settings write waiting for the bus monitor task.
+    pub settings_write: Option<settings::Write>,
+}
+
+impl<B: UsbBus> State<B> {
+    pub fn new(alloc: &'static UsbBusAllocator<B>, interfaces: u8) -> Self {
+        Self {
+            usb_hp_interrupts: 0,
+            usb_lp_interrupts: 0,
+            interfaces,
+            report: ErrorReport::new(alloc),
+            timing_both: [0; 2],
+            reset_pending: false,
+            configuration: 0,
+            alt_settings: [0; USB_INTERFACES],
+            vpd_reload: None,
+            reset_flags: 0,
+            settings_write: None,
+        }
+    }
+}
+
+/// Wraps the gs_usb class to additionally handle vendor requests.
+///
+/// All other requests and events are forwarded to the wrapped class.
+pub struct Vendor<'a, B: UsbBus> {
+    can: &'a mut GsCan<'static, B, UsbCanDevice>,
+    state: &'a mut State<B>,
+}
+
+impl<'a, B: UsbBus> Vendor<'a, B> {
+    pub fn new(
+        can: &'a mut GsCan<'static, B, UsbCanDevice>,
+        state: &'a mut State<B>,
+    ) -> Self {
+        Self { can, state }
+    }
+
+    /// Read a diagnostic value for an interface.
+    fn diagnostic(
+        &self,
+        diagnostic: Diagnostic,
+        interface: usize,
+        xfer: ControlIn<B>,
+    ) -> usb_device::Result<()> {
+        let i = self.can.device.interfaces.get(interface);
+
+        match (diagnostic, i) {
+            (Diagnostic::Overruns, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.counters.overrun_fifo0,
+                    i.counters.overrun_fifo1,
+                    i.counters.overrun_queue,
+                    i.counters.tx_dropped,
+                ],
+            ),
+            (Diagnostic::ResetReason, _) => {
+                accept_words(xfer, &[self.state.reset_flags])
+            }
+            (Diagnostic::Usb, _) => accept_words(
+                xfer,
+                &[
+                    1,
+                    USB_PACKET_SIZE,
+                    self.state.usb_hp_interrupts,
+                    self.state.usb_lp_interrupts,
+                    max_frame_rate(8),
+                    max_frame_rate(64),
+                ],
+            ),
+            (Diagnostic::Fault, Some(i)) => {
+                accept_words(xfer, &[i.faulted as u32, i.counters.bus_off])
+            }
+            (Diagnostic::Interfaces, _) => {
+                let interfaces = &self.can.device.interfaces;
+                let mut status = [0; can::INTERFACES];
+                for (index, (word, i)) in
+                    status.iter_mut().zip(interfaces).enumerate()
+                {
+                    *word = if index >= self.state.interfaces as usize {
+                        0
+                    } else if i.faulted {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                accept_words(xfer, &status)
+            }
+            (Diagnostic::Loopback, Some(i)) => accept_words(
+                xfer,
+                &[
+                    self.can.device.is_loopback(interface as u8) as u32,
+                    i.suppress_loopback as u32,
+                ],
+            ),
+            (Diagnostic::Filter, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.counters.last_filter.map_or(u32::MAX, u32::from),
+                    i.counters.filter_matched,
+                    i.counters.filter_unmatched,
+                ],
+            ),
+            (Diagnostic::Features, Some(i)) => {
+                let features = i.features.unwrap_or(Feature::empty());
+                accept_words(
+                    xfer,
+                    &[
+                        i.features.is_some() as u32,
+                        features.bits(),
+                        (features & !can::supported_features()).bits(),
+                    ],
+                )
+            }
+            (Diagnostic::Session, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.session.rx_frames,
+                    i.session.tx_frames,
+                    i.session.errors,
+                    i.session.overruns,
+                ],
+            ),
+            (Diagnostic::Restricted, Some(i)) => accept_words(
+                xfer,
+                &[i.restricted as u32, i.counters.restricted],
+            ),
+            (Diagnostic::Timestamp, Some(i)) => {
+                accept_words(xfer, &[i.rx_timestamp, timestamp::now_extended()])
+            }
+            (Diagnostic::TxHistory, Some(i)) => {
+                xfer.accept(|buf| Ok(i.history.read(buf)))
+            }
+            (Diagnostic::AckErrors, Some(i)) => {
+                accept_words(xfer, &[i.counters.ack_errors])
+            }
+            (Diagnostic::DeferredReceive, Some(i)) => {
+                accept_words(xfer, &[i.counters.rx_deferred])
+            }
+            (Diagnostic::StopReason, Some(i)) => {
+                let (reason, time_ms) = i.last_stop;
+                accept_words(xfer, &[reason as u32, time_ms])
+            }
+            (Diagnostic::InvalidId, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.counters.invalid_id_rx,
+                    i.counters.invalid_id_tx,
+                    i.counters.last_invalid_id,
+                ],
+            ),
+            (Diagnostic::RxPeak, Some(i)) => {
+                let [fifo0, fifo1] = i.counters.peak_fifo;
+                accept_words(
+                    xfer,
+                    &[
+                        fifo0 as u32,
+                        fifo1 as u32,
+                        i.rx.peak as u32,
+                        rx::RX_QUEUE_DEPTH as u32,
+                    ],
+                )
+            }
+            (Diagnostic::ErrorState, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.error_state as u32,
+                    i.counters.error_warning,
+                    i.counters.error_passive,
+                    i.counters.bus_off,
+                ],
+            ),
+            (Diagnostic::StuckDominant, Some(i)) => accept_words(
+                xfer,
+                &[i.stuck_dominant as u32, i.counters.stuck_dominant],
+            ),
+            (Diagnostic::Statistics, Some(i)) => accept_words(
+                xfer,
+                &[
+                    i.counters.rx_frames,
+                    i.counters.tx_frames,
+                    i.counters.rx_bytes,
+                    i.counters.tx_bytes,
+                    i.counters
+                        .overrun_fifo0
+                        .wrapping_add(i.counters.overrun_fifo1),
+                    i.counters.tx_dropped,
+                    i.counters.bus_off,
+                ],
+            ),
+            (Diagnostic::RejectedTiming, Some(i)) => {
+                let rejected = i.rejected_timing.unwrap_or_default();
+                accept_words(
+                    xfer,
+                    &[
+                        i.counters.timing_rejected,
+                        rejected.data as u32,
+                        rejected.requested,
+                        rejected.nearest,
+                    ],
+                )
+            }
+            (Diagnostic::Retransmit, Some(i)) => {
+                match self.can.device.retransmit_enabled(interface as u8) {
+                    Some(enabled) => accept_words(
+                        xfer,
+                        &[i.retransmit as u32, enabled as u32],
+                    ),
+                    None => xfer.reject(),
+                }
+            }
+            (Diagnostic::BadLength, Some(i)) => accept_words(
+                xfer,
+                &[i.counters.bad_length, i.counters.last_bad_length as u32],
+            ),
+            (Diagnostic::Fd, Some(i)) => {
+                match self.can.device.fd_enabled(interface as u8) {
+                    Some((fd, brs)) => accept_words(
+                        xfer,
+                        &[fd as u32, brs as u32, i.counters.fd_dropped],
+                    ),
+                    None => xfer.reject(),
+                }
+            }
+            (Diagnostic::TxMode, Some(i)) => {
+                let priority = i.tx.priority as u32;
+                match self.can.device.tx_buffer_mode(interface as u8) {
+                    Some(TxBufferMode::Fifo) => {
+                        accept_words(xfer, &[0, priority])
+                    }
+                    Some(TxBufferMode::Priority) => {
+                        accept_words(xfer, &[1, priority])
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            (Diagnostic::FlashTiming, _) => {
+                let [erase_min, erase_max, erases] = dfu::ERASE_TIME.read();
+                let [program_min, program_max, programs] =
+                    dfu::PROGRAM_TIME.read();
+                accept_words(
+                    xfer,
+                    &[
+                        erase_min,
+                        erase_max,
+                        erases,
+                        program_min,
+                        program_max,
+                        programs,
+                    ],
+                )
+            }
+            (Diagnostic::UsbConfig, _) => {
+                let [gs_usb, dfu, report] = self.state.alt_settings;
+                accept_words(
+                    xfer,
+                    &[
+                        self.state.configuration as u32,
+                        USB_INTERFACES as u32,
+                        gs_usb as u32,
+                        dfu as u32,
+                        report as u32,
+                    ],
+                )
+            }
+            _ => xfer.reject(),
+        }
+    }
+
+    /// Track the configuration and alternate settings selected by the host.
+    ///
+    /// Standard requests are handled by the USB device and the classes
+    /// that own the interfaces, so they're only looked at here.
+    fn observe(&mut self, req: &control::Request) {
+        if req.request_type != control::RequestType::Standard {
+            return;
+        }
+
+        match (req.recipient, req.request) {
+            (
+                control::Recipient::Device,
+                control::Request::SET_CONFIGURATION,
+            ) => self.state.configuration = req.value as u8,
+            (
+                control::Recipient::Interface,
+                control::Request::SET_INTERFACE,
+            ) => {
+                let interface = req.index as usize;
+                if let Some(alt) = self.state.alt_settings.get_mut(interface) {
+                    *alt = req.value as u8;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Read back the programmed bit timing.
+    fn bit_timing(
+        &self,
+        interface: u8,
+        xfer: ControlIn<B>,
+    ) -> usb_device::Result<()> {
+        let device = &self.can.device;
+        let Some(timing) = device.programmed_timing(interface) else {
+            return xfer.reject();
+        };
+
+        let (nominal_rate, nominal_sp) = timing.nominal(device.clock());
+        let (data_rate, data_sp) = timing.data(device.clock());
+
+        accept_words(
+            xfer,
+            &[
+                timing.nbtp,
+                timing.dbtp,
+                nominal_rate,
+                nominal_sp,
+                data_rate,
+                data_sp,
+            ],
+        )
+    }
+
+    /// Re-read the VPD from OTP and apply what can change while running.
+    fn reload_vpd(&mut self, xfer: ControlIn<B>) -> usb_device::Result<()> {
+        let vpd = match VitalProductData::read(&mut vpd::Otp) {
+            Ok(Some(vpd)) => vpd,
+            Ok(None) => {
+                defmt::warn!("VPD reload: not provisioned");
+                return xfer.reject();
+            }
+            Err(e) => {
+                defmt::error!(
+                    "VPD reload: unreadable: {}",
+                    defmt::Debug2Format(&e)
+                );
+                return xfer.reject();
+            }
+        };
+
+        defmt::info!(
+            "VPD reloaded: serial={} hardware={} sku={}",
+            vpd.serial,
+            vpd.hardware,
+            vpd.sku,
+        );
+
+        self.can.device.transceivers = Transceivers::new(&vpd.pins);
+        self.state.interfaces = vpd.sku.interface_count();
+
+        let hw = &vpd.hardware;
+        let words = [
+            vpd.serial.year as u32,
+            vpd.serial.week as u32,
+            vpd.serial.seq() as u32,
+            u32::from_be_bytes([hw.major, hw.minor, hw.patch, hw.pre]),
+            vpd.sku.id() as u32,
+            vpd.bit_rate.nominal(),
+            vpd.bit_rate.data(),
+            vpd.pins.raw(),
+            vpd.startup_delay.ms(),
+        ];
+        self.state.vpd_reload = Some(vpd);
+
+        accept_words(xfer, &words)
+    }
+
+    /// Queue a frame on both interfaces.
+    fn transmit_both(&mut self, xfer: ControlOut<B>) -> usb_device::Result<()> {
+        let frame = match parse_frame(xfer.data()) {
+            Some(Ok(frame)) => frame,
+            Some(Err(raw)) => {
+                for i in &mut self.can.device.interfaces[..2] {
+                    i.counters.reject_id(false, raw);
+                }
+                return xfer.reject();
+            }
+            None => return xfer.reject(),
+        };
+
+        if !self.can.device.transmit_both(frame) {
+            return xfer.reject();
+        }
+
+        xfer.accept()
+    }
+
+    /// Queue a frame tagged with a marker on one interface.
+    fn transmit_marked(
+        &mut self,
+        marker: u8,
+        interface: u8,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        // Markers from there up tag gs_usb frames for their echoes.
+        if marker >= echo::MARKER_BASE {
+            return xfer.reject();
+        }
+
+        let device = &mut self.can.device;
+        let frame = match parse_frame(xfer.data()) {
+            Some(Ok(frame)) => frame,
+            Some(Err(raw)) => {
+                if let Some(i) = device.interfaces.get_mut(interface as usize) {
+                    i.counters.reject_id(false, raw);
+                }
+                return xfer.reject();
+            }
+            None => return xfer.reject(),
+        };
+
+        if !device.transmit_marked(interface, frame, marker) {
+            return xfer.reject();
+        }
+
+        xfer.accept()
+    }
+
+    /// Set the bit timing of both interfaces together.
+    fn bit_timing_both(
+        &mut self,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        let Some(timing) = parse_timing_both(xfer.data()) else {
+            return xfer.reject();
+        };
+
+        let results = self.can.device.set_timing_both(&timing);
+        for (interface, result) in results.iter().enumerate() {
+            self.state.timing_both[interface] = match result {
+                Ok(()) => 1,
+                Err(e) => {
+                    defmt::error!(
+                        "Interface {} timing rejected: {}",
+                        interface,
+                        e
+                    );
+                    2
+                }
+            };
+        }
+
+        match results {
+            [Ok(()), Ok(())] => xfer.accept(),
+            _ => xfer.reject(),
+        }
+    }
+
+    /// Read the skew between the last frames queued on both interfaces.
+    fn transmit_skew(&self, xfer: ControlIn<B>) -> usb_device::Result<()> {
+        match self.can.device.transmit_skew() {
+            Some(cycles) => {
+                let skew_ns = cycles as u64 * 1000 / CORE_CLOCK_MHZ as u64;
+                accept_words(xfer, &[skew_ns as u32])
+            }
+            None => xfer.reject(),
+        }
+    }
+
+    /// Change the transmit test pattern of an interface.
+    fn pattern(
+        &mut self,
+        action: u16,
+        interface: u8,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        let started = self.can.device.is_started(interface);
+        let Some(i) = self.can.device.interfaces.get_mut(interface as usize)
+        else {
+            return xfer.reject();
+        };
+
+        match action {
+            0 => i.pattern.clear(),
+            1 => {
+                let frame = match parse_frame(xfer.data()) {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(raw)) => {
+                        i.counters.reject_id(false, raw);
+                        return xfer.reject();
+                    }
+                    None => return xfer.reject(),
+                };
+                if i.pattern.push(frame).is_err() {
+                    return xfer.reject();
+                }
+            }
+            2 => {
+                if !started || !i.pattern.start(&i.tx, i.counters.bus_off) {
+                    return xfer.reject();
+                }
+                defmt::info!("Interface {} test pattern started", interface);
+            }
+            3 => {
+                i.pattern.stop();
+                defmt::info!("Interface {} test pattern stopped", interface);
+            }
+            _ => return xfer.reject(),
+        }
+
+        xfer.accept()
+    }
+
+    /// Change the gateway routing table.
+    fn routing(
+        &mut self,
+        action: u16,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        let routes = &mut self.can.device.routes;
+
+        match action {
+            0 => routes.clear(),
+            1 => {
+                let interfaces = self.state.interfaces;
+                let Some(rule) = Rule::parse(xfer.data(), interfaces) else {
+                    return xfer.reject();
+                };
+                if routes.push(rule).is_err() {
+                    return xfer.reject();
+                }
+                defmt::info!(
+                    "Routing interface {} to {}",
+                    rule.source,
+                    rule.destination
+                );
+            }
+            _ => return xfer.reject(),
+        }
+
+        xfer.accept()
+    }
+
+    /// Read the transmit test pattern status of an interface.
+    fn pattern_status(
+        &self,
+        interface: usize,
+        xfer: ControlIn<B>,
+    ) -> usb_device::Result<()> {
+        let Some(i) = self.can.device.interfaces.get(interface) else {
+            return xfer.reject();
+        };
+
+        accept_words(
+            xfer,
+            &[
+                i.pattern.running as u32,
+                i.pattern.frame_count() as u32,
+                i.pattern.sent(&i.tx),
+                i.pattern.bus_off(i.counters.bus_off),
+            ],
+        )
+    }
+
+    /// Save or clear persistent settings.
+    fn settings(
+        &mut self,
+        action: u16,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        // Page erases for firmware updates finish in the background, and
+        // only one settings write is carried out at a time.
+        if dfu::erase_pending()
+            || settings::writing()
+            || self.state.settings_write.is_some()
+        {
+            return xfer.reject();
+        }
+
+        let write = match action {
+            0 => {
+                defmt::info!("Saving settings");
+                settings::Write::Save(self.can.device.settings())
+            }
+            1 => {
+                defmt::info!("Restoring default settings");
+                self.can.device.apply_settings(&Settings::default());
+                settings::Write::Clear
+            }
+            _ => return xfer.reject(),
+        };
+
+        self.state.settings_write = Some(write);
+        xfer.accept()
+    }
+
+    /// Reset a diagnostic value for an interface.
+    fn reset_diagnostic(
+        &mut self,
+        diagnostic: Diagnostic,
+        interface: usize,
+        xfer: ControlOut<B>,
+    ) -> usb_device::Result<()> {
+        if diagnostic == Diagnostic::Restricted {
+            return match self.can.device.clear_restricted(interface as u8) {
+                Ok(()) => {
+                    let i = &mut self.can.device.interfaces[interface];
+                    i.counters.restricted = 0;
+                    xfer.accept()
+                }
+                Err(_) => xfer.reject(),
+            };
+        }
+
+        let i = self.can.device.interfaces.get_mut(interface);
+
+        match (diagnostic, i) {
+            (Diagnostic::Overruns, Some(i)) => {
+                i.counters.overrun_fifo0 = 0;
+                i.counters.overrun_fifo1 = 0;
+                i.counters.overrun_queue = 0;
+                i.counters.tx_dropped = 0;
+            }
+            (Diagnostic::ResetReason, _) => self.state.reset_flags = 0,
+            (Diagnostic::Usb, _) => {
+                self.state.usb_hp_interrupts = 0;
+                self.state.usb_lp_interrupts = 0;
+            }
+            (Diagnostic::Fault, Some(i)) => {
+                i.clear_fault();
+                i.counters.bus_off = 0;
+            }
+            (Diagnostic::Filter, Some(i)) => {
+                i.counters.filter_matched = 0;
+                i.counters.filter_unmatched = 0;
+                i.counters.last_filter = None;
+            }
+            (Diagnostic::TxHistory, Some(i)) => i.history.clear(),
+            (Diagnostic::AckErrors, Some(i)) => i.counters.ack_errors = 0,
+            (Diagnostic::DeferredReceive, Some(i)) => {
+                i.counters.rx_deferred = 0;
+            }
+            (Diagnostic::RxPeak, Some(i)) => {
+                i.counters.peak_fifo = [0; 2];
+                i.rx.peak = 0;
+            }
+            (Diagnostic::ErrorState, Some(i)) => {
+                i.counters.error_warning = 0;
+                i.counters.error_passive = 0;
+            }
+            (Diagnostic::RejectedTiming, Some(i)) => {
+                i.counters.timing_rejected = 0;
+                i.rejected_timing = None;
+            }
+            (Diagnostic::Fd, Some(i)) => i.counters.fd_dropped = 0,
+            (Diagnostic::StuckDominant, Some(i)) => {
+                i.counters.stuck_dominant = 0;
+            }
+            (Diagnostic::Statistics, Some(i)) => {
+                i.counters.rx_frames = 0;
+                i.counters.tx_frames = 0;
+                i.counters.rx_bytes = 0;
+                i.counters.tx_bytes = 0;
+            }
+            (Diagnostic::BadLength, Some(i)) => {
+                i.counters.bad_length = 0;
+                i.counters.last_bad_length = 0;
+            }
+            (Diagnostic::InvalidId, Some(i)) => {
+                i.counters.invalid_id_rx = 0;
+                i.counters.invalid_id_tx = 0;
+                i.counters.last_invalid_id = 0;
+            }
+            (Diagnostic::FlashTiming, _) => {
+                dfu::ERASE_TIME.reset();
+                dfu::PROGRAM_TIME.reset();
+            }
+            _ => return xfer.reject(),
+        }
+
+        xfer.accept()
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for Vendor<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.can.get_configuration_descriptors(writer)?;
+        self.state.report.get_configuration_descriptors(writer)
+    }
+
+    fn get_bos_descriptors(
+        &self,
+        writer: &mut BosWriter,
+    ) -> usb_device::Result<()> {
+        self.can.get_bos_descriptors(writer)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        self.can.get_string(index, lang_id)
+    }
+
+    fn reset(&mut self) {
+        self.state.report.reset();
+        self.can.device.routes.clear();
+        self.state.configuration = 0;
+        self.state.alt_settings = [0; USB_INTERFACES];
+        self.can.reset()
+    }
+
+    fn poll(&mut self) {
+        self.can.poll()
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        self.observe(&req);
+        if !is_vendor_request(&req) {
+            return self.can.control_out(xfer);
+        }
+
+        let result = match Request::try_from(req.request) {
+            Ok(Request::ResetDiagnostics) => {
+                match Diagnostic::try_from(req.value) {
+                    Ok(diagnostic) => self.reset_diagnostic(
+                        diagnostic,
+                        req.index as usize,
+                        xfer,
+                    ),
+                    Err(_) => xfer.reject(),
+                }
+            }
+            Ok(Request::TransmitGap) => {
+                match self.can.device.interfaces.get_mut(req.index as usize) {
+                    Some(i) => {
+                        i.tx.gap = Duration::micros(req.value as u64);
+                        xfer.accept()
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::Settings) => self.settings(req.value, xfer),
+            Ok(Request::LoopbackEcho) => {
+                let i = self.can.device.interfaces.get_mut(req.index as usize);
+                match (req.value, i) {
+                    (0, Some(i)) => {
+                        i.suppress_loopback = false;
+                        xfer.accept()
+                    }
+                    (1, Some(i)) => {
+                        i.suppress_loopback = true;
+                        xfer.accept()
+                    }
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::TransmitBoth) => self.transmit_both(xfer),
+            Ok(Request::Pattern) => {
+                self.pattern(req.value, req.index as u8, xfer)
+            }
+            Ok(Request::EventLog) => {
+                event::clear();
+                xfer.accept()
+            }
+            Ok(Request::Presence) => {
+                let window = match req.value {
+                    0 => PRESENCE_WINDOW_MS,
+                    ms => ms.min(MAX_PRESENCE_WINDOW_MS),
+                };
+                match self
+                    .can
+                    .device
+                    .check_presence(Duration::millis(window as u64))
+                {
+                    true => xfer.accept(),
+                    false => xfer.reject(),
+                }
+            }
+            Ok(Request::HostRateLimit) => {
+                match self.can.device.interfaces.get_mut(req.index as usize) {
+                    Some(i) => {
+                        i.host_limit.set_rate(req.value as u32);
+                        xfer.accept()
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::RestartDelay) => {
+                match self.can.device.interfaces.get_mut(req.index as usize) {
+                    Some(i) => {
+                        i.restart_ms = can::RestartMs(req.value);
+                        xfer.accept()
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::TransmitterDelay) => {
+                let [offset, filter] = req.value.to_le_bytes();
+                let tdc = match req.value {
+                    TDC_DISABLE => None,
+                    _ => Some(Tdc { offset, filter }),
+                };
+                match self.can.device.set_tdc(req.index as u8, tdc) {
+                    Ok(()) => xfer.accept(),
+                    Err(e) => {
+                        defmt::error!("Transmitter delay rejected: {}", e);
+                        xfer.reject()
+                    }
+                }
+            }
+            Ok(Request::ErrorReports) => {
+                self.state.report.configure(req.value, req.index);
+                xfer.accept()
+            }
+            Ok(Request::Heartbeat) => {
+                self.state.report.set_heartbeat(req.value);
+                xfer.accept()
+            }
+            Ok(Request::BitTimingBoth) => self.bit_timing_both(xfer),
+            Ok(Request::SamplePoint) => {
+                let [interface, phase] = req.index.to_le_bytes();
+                let result = match phase {
+                    0 | 1 => self.can.device.set_sample_point(
+                        interface,
+                        phase == 1,
+                        req.value as u32,
+                    ),
+                    _ => Err(Error::BitTiming),
+                };
+                match result {
+                    Ok(achieved) => {
+                        defmt::info!("Sample point set to {}", achieved);
+                        xfer.accept()
+                    }
+                    Err(e) => {
+                        defmt::error!("Sample point rejected: {}", e);
+                        xfer.reject()
+                    }
+                }
+            }
+            Ok(Request::ProtocolException) => {
+                let enabled = match req.value {
+                    0 => Some(false),
+                    1 => Some(true),
+                    _ => None,
+                };
+                let device = &mut self.can.device;
+                match enabled.map(|enabled| {
+                    device.set_protocol_exception(req.index as u8, enabled)
+                }) {
+                    Some(Ok(())) => xfer.accept(),
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::Routing) => self.routing(req.value, xfer),
+            Ok(Request::Retransmit) => {
+                let device = &mut self.can.device;
+                match Retransmit::try_from(req.value).map(|retransmit| {
+                    device.set_retransmit(req.index as u8, retransmit)
+                }) {
+                    Ok(Ok(())) => xfer.accept(),
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::Standby) => {
+                let standby = match req.value {
+                    0 => Some(false),
+                    1 => Some(true),
+                    _ => None,
+                };
+                let device = &mut self.can.device;
+                match standby
+                    .map(|standby| device.set_standby(req.index as u8, standby))
+                {
+                    Some(Ok(())) => xfer.accept(),
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::TxMode) => {
+                let mode = match req.value {
+                    0 => Some(TxBufferMode::Fifo),
+                    1 => Some(TxBufferMode::Priority),
+                    _ => None,
+                };
+                let device = &mut self.can.device;
+                match mode.map(|mode| {
+                    device.set_tx_buffer_mode(req.index as u8, mode)
+                }) {
+                    Some(Ok(())) => xfer.accept(),
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::SelfTest) => {
+                let window = Duration::millis(SELF_TEST_WINDOW_MS);
+                match self.can.device.self_test(req.index as u8, window) {
+                    true => xfer.accept(),
+                    false => xfer.reject(),
+                }
+            }
+            Ok(Request::TxPriority) => {
+                let i = self.can.device.interfaces.get_mut(req.index as usize);
+                match (req.value, i) {
+                    (0 | 1, Some(i)) => {
+                        i.tx.priority = req.value == 1;
+                        xfer.accept()
+                    }
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::TransmitMarked) => {
+                self.transmit_marked(req.value as u8, req.index as u8, xfer)
+            }
+            Ok(Request::Latency) => {
+                let samples = match req.value {
+                    0 => latency::DEFAULT_SAMPLES,
+                    n => n,
+                };
+                let interface = req.index as u8;
+                let started = self.can.device.is_started(interface);
+                match self.can.device.interfaces.get_mut(req.index as usize) {
+                    Some(i) if started => {
+                        i.latency.start(samples);
+                        xfer.accept()
+                    }
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::Capture) => match req.value {
+                0 => {
+                    capture::stop();
+                    xfer.accept()
+                }
+                1 => {
+                    capture::start();
+                    xfer.accept()
+                }
+                _ => xfer.reject(),
+            },
+            Ok(Request::Reset) => {
+                defmt::info!("Reset requested by host");
+                self.can.device.quiesce();
+                self.state.reset_pending = true;
+                xfer.accept()
+            }
+            Ok(Request::Padding) => {
+                let i = self.can.device.interfaces.get_mut(req.index as usize);
+                match (u8::try_from(req.value), i) {
+                    (Ok(padding), Some(i)) => {
+                        i.tx.padding = padding;
+                        xfer.accept()
+                    }
+                    _ => xfer.reject(),
+                }
+            }
+            _ => xfer.reject(),
+        };
+
+        if result.is_err() {
+            defmt::error!("Vendor request {} failed", req.request);
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if is_timestamp_request(&req) {
+            let now = timestamp::now_extended();
+            if xfer.accept_with(&now.to_le_bytes()).is_err() {
+                defmt::error!("Timestamp request failed");
+            }
+            return;
+        }
+
+        if !is_vendor_request(&req) {
+            return self.can.control_in(xfer);
+        }
+
+        let result = match Request::try_from(req.request) {
+            Ok(Request::Diagnostics) => match Diagnostic::try_from(req.value) {
+                Ok(diagnostic) => {
+                    self.diagnostic(diagnostic, req.index as usize, xfer)
+                }
+                Err(_) => xfer.reject(),
+            },
+            Ok(Request::TransmitGap) => {
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => {
+                        accept_words(xfer, &[i.tx.gap.to_micros() as u32])
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::BitTiming) => self.bit_timing(req.index as u8, xfer),
+            Ok(Request::TransmitBoth) => self.transmit_skew(xfer),
+            Ok(Request::Pattern) => {
+                self.pattern_status(req.index as usize, xfer)
+            }
+            Ok(Request::EventLog) => {
+                xfer.accept(|buf| Ok(event::read(req.value as usize, buf)))
+            }
+            Ok(Request::Capture) => {
+                xfer.accept(|buf| Ok(capture::read(req.value as usize, buf)))
+            }
+            Ok(Request::Presence) => {
+                self.can.device.poll_presence();
+                let interfaces = &self.can.device.interfaces;
+                let status =
+                    interfaces.each_ref().map(|i| presence_status(i.presence));
+                accept_words(xfer, &status)
+            }
+            Ok(Request::HostRateLimit) => {
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => accept_words(
+                        xfer,
+                        &[i.host_limit.rate(), i.host_limit.dropped],
+                    ),
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::ReloadVpd) => self.reload_vpd(xfer),
+            Ok(Request::RestartDelay) => {
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => accept_words(xfer, &[i.restart_ms.0 as u32]),
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::SelfTest) => {
+                self.can.device.poll_self_test();
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => {
+                        accept_words(xfer, &[self_test_status(i.self_test)])
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::Capabilities) => {
+                let device = &self.can.device;
+                accept_words(
+                    xfer,
+                    &[
+                        device.clock().to_Hz(),
+                        device.max_rate(false),
+                        device.max_rate(true),
+                        device.practical_data_rate(),
+                        // every FDCAN instance on this part has it.
+                        1,
+                    ],
+                )
+            }
+            Ok(Request::Chip) => {
+                let [uid0, uid1, uid2] = chip::uid();
+                accept_words(
+                    xfer,
+                    &[
+                        chip::flash_size_kb() as u32,
+                        chip::device_id() as u32,
+                        chip::revision_id() as u32,
+                        chip::package() as u32,
+                        uid0,
+                        uid1,
+                        uid2,
+                    ],
+                )
+            }
+            Ok(Request::TransmitterDelay) => {
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => {
+                        let tdc = i.tdc.unwrap_or(Tdc {
+                            offset: 0,
+                            filter: 0,
+                        });
+                        accept_words(
+                            xfer,
+                            &[
+                                i.tdc.is_some() as u32,
+                                tdc.offset as u32,
+                                tdc.filter as u32,
+                            ],
+                        )
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::Version) => xfer.accept_with_static(VERSION.as_bytes()),
+            Ok(Request::OtpCrc) => match req.value as usize {
+                0 => accept_words(
+                    xfer,
+                    &[otp::checksum(otp::OTP_LEN), otp::OTP_LEN as u32],
+                ),
+                len if len <= otp::OTP_LEN => {
+                    accept_words(xfer, &[otp::checksum(len), len as u32])
+                }
+                _ => xfer.reject(),
+            },
+            Ok(Request::ErrorReports) => {
+                let (interval, threshold) = self.state.report.config();
+                accept_words(xfer, &[interval as u32 * 100, threshold as u32])
+            }
+            Ok(Request::Heartbeat) => {
+                let report = &self.state.report;
+                accept_words(
+                    xfer,
+                    &[report.heartbeat() as u32 * 100, report.beats],
+                )
+            }
+            Ok(Request::BitTimingBoth) => {
+                accept_words(xfer, &self.state.timing_both)
+            }
+            Ok(Request::Standby) => {
+                let device = &self.can.device;
+                let standby = device
+                    .interfaces
+                    .get(req.index as usize)
+                    .map(|_| device.transceivers.standby(req.index as u8));
+                match standby {
+                    Some(Some(standby)) => {
+                        accept_words(xfer, &[1, standby as u32])
+                    }
+                    Some(None) => accept_words(xfer, &[0, 0]),
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::TxPending) => {
+                let device = &self.can.device;
+                let index = req.index as usize;
+                match (
+                    device.tx_pending(req.index as u8),
+                    device.interfaces.get(index),
+                ) {
+                    (Some(buffers), Some(i)) => {
+                        let queued = i.tx.len() as u32;
+                        accept_words(
+                            xfer,
+                            &[buffers, queued, buffers.count_ones() + queued],
+                        )
+                    }
+                    _ => xfer.reject(),
+                }
+            }
+            Ok(Request::ProtocolException) => {
+                match self.can.device.protocol_exception(req.index as u8) {
+                    Some(enabled) => accept_words(xfer, &[enabled as u32]),
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::Routing) => {
+                let routes = &self.can.device.routes;
+                xfer.accept(|buf| Ok(routes.read(buf)))
+            }
+            Ok(Request::Latency) => {
+                let index = req.index as usize;
+                match self.can.device.interfaces.get_mut(index) {
+                    Some(i) => {
+                        i.completions.collect(
+                            req.index as u8,
+                            &mut i.latency,
+                            &mut i.echoes,
+                        );
+                        accept_words(xfer, &i.latency.read())
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::TransmitMarked) => {
+                let index = req.index as usize;
+                match self.can.device.interfaces.get_mut(index) {
+                    Some(i) => {
+                        i.completions.collect(
+                            req.index as u8,
+                            &mut i.latency,
+                            &mut i.echoes,
+                        );
+                        xfer.accept(|buf| Ok(i.completions.take(buf)))
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::SamplePoint) => {
+                let device = &self.can.device;
+                match device.programmed_timing(req.index as u8) {
+                    Some(timing) => {
+                        let (_, nominal) = timing.nominal(device.clock());
+                        let (_, data) = timing.data(device.clock());
+                        accept_words(xfer, &[nominal, data])
+                    }
+                    None => xfer.reject(),
+                }
+            }
+            Ok(Request::Padding) => {
+                match self.can.device.interfaces.get(req.index as usize) {
+                    Some(i) => accept_words(xfer, &[i.tx.padding as u32]),
+                    None => xfer.reject(),
+                }
+            }
+            _ => xfer.reject(),
+        };
+
+        if result.is_err() {
+            defmt::error!("Vendor request {} failed", req.request);
+        }
+    }
+
+    fn endpoint_setup(&mut self, addr: EndpointAddress) {
+        self.can.endpoint_setup(addr)
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        self.can.endpoint_out(addr)
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        self.can.endpoint_in_complete(addr)
+    }
+
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        self.can.get_alt_setting(interface)
+    }
+
+    fn set_alt_setting(
+        &mut self,
+        interface: InterfaceNumber,
+        alternative: u8,
+    ) -> bool {
+        self.can.set_alt_setting(interface, alternative)
+    }
+}
+
+/// Check if a request is addressed to this vendor class.
+fn is_vendor_request(req: &control::Request) -> bool {
+    req.request_type == control::RequestType::Vendor
+        && req.recipient == control::Recipient::Device
+}
+
+/// The gs_usb request for the device timestamp, which the gs_usb class
+/// doesn't handle.
+///
+/// It's addressed to the gs_usb interface, always interface 0.
+fn is_timestamp_request(req: &control::Request) -> bool {
+    req.request_type == control::RequestType::Vendor
+        && req.recipient == control::Recipient::Interface
+        && req.request == GS_USB_BREQ_TIMESTAMP
+        && req.index == 0
+}
+
+/// Encode the bus presence of an interface: 0 not checked, 1 checking,
+/// 2 no valid frames seen or 3 valid frames seen.
+fn presence_status(presence: Presence) -> u32 {
+    match presence {
+        Presence::Unknown => 0,
+        Presence::Checking { .. } => 1,
+        Presence::Absent => 2,
+        Presence::Present => 3,
+    }
+}
+
+/// Encode the self-test result of an interface: 0 not run, 1 running,
+/// 2 failed or 3 passed.
+fn self_test_status(self_test: SelfTest) -> u32 {
+    match self_test {
+        SelfTest::Unknown => 0,
+        SelfTest::Running { .. } => 1,
+        SelfTest::Failed => 2,
+        SelfTest::Passed => 3,
+    }
+}
+
+/// Parse a frame from a vendor request data stage.
+///
+/// `None` if the data stage is malformed, or `Err` with the raw identifier,
+/// bit 31 set if extended, if it's out of range so it can be counted.
+fn parse_frame(data: &[u8]) -> Option<Result<TxFrame, u32>> {
+    let (header, payload) = data.split_at_checked(FRAME_HEADER_SIZE)?;
+
+    let raw = u32::from_le_bytes(header[..4].try_into().ok()?);
+    let flags = FrameFlag::from_bits_truncate(header[4]);
+    let len = header[5] as usize;
+
+    if len > 64 || payload.len() < len {
+        return None;
+    }
+
+    let id = if raw & FRAME_ID_EXTENDED != 0 {
+        ExtendedId::new(raw & !FRAME_ID_EXTENDED).map(Id::Extended)
+    } else {
+        u16::try_from(raw)
+            .ok()
+            .and_then(StandardId::new)
+            .map(Id::Standard)
+    };
+
+    Some(
+        id.map(|id| TxFrame::new(id, flags, &payload[..len]))
+            .ok_or(raw),
+    )
+}
+
+/// Parse the data stage of [`Request::BitTimingBoth`].
+fn parse_timing_both(data: &[u8]) -> Option<[[DeviceBitTiming; 2]; 2]> {
+    if data.len() != 4 * 5 * 4 {
+        return None;
+    }
+
+    let mut words = data
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()));
+    let mut timing = || DeviceBitTiming {
+        prop_seg: words.next().unwrap(),
+        phase_seg1: words.next().unwrap(),
+        phase_seg2: words.next().unwrap(),
+        sjw: words.next().unwrap(),
+        brp: words.next().unwrap(),
+    };
+
+    Some([[timing(), timing()], [timing(), timing()]])
+}
+
+/// Best case frames per second for a payload length.
+///
+/// Each gs_usb frame is sent as its own bulk transfer so a frame that
+/// doesn't fit in a single packet costs a whole extra packet.
+fn max_frame_rate(payload: u32) -> u32 {
+    let packets = (GS_HEADER_SIZE + payload).div_ceil(USB_PACKET_SIZE);
+    USB_PACKETS_PER_MS * 1000 / packets
+}
+
+/// Accept a control IN transfer with a list of little-endian words.
+fn accept_words<B: UsbBus>(
+    xfer: ControlIn<B>,
+    words: &[u32],
+) -> usb_device::Result<()> {
+    xfer.accept(|buf| {
+        let len = words.len() * 4;
+        if buf.len() < len {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        for (chunk, word) in buf.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+
+        Ok(len)
+    })
+}
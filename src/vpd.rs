@@ -1,9 +1,89 @@
 //! Vital product data.
+//!
+//! Multi-byte fields are little-endian. They're stored as `zerocopy`
+//! byte-order types and read through accessors so the layout doesn't depend
+//! on the host that packed the blob or the target parsing it.
+//!
+//! The CAN module's product data in OTP is the primary source. A carrier
+//! board the module is fitted to may describe itself with a second blob in
+//! the same format from its own [`Source`], e.g. an I2C EEPROM, so the
+//! whole [`Assembly`] can be identified.
 
 use core::{convert::Infallible, fmt::Formatter};
 use defmt::Format;
 use tlvc::{TlvcReadError, TlvcReader};
-use zerocopy::{AsBytes, FromBytes, FromZeroes};
+use zerocopy::{
+    byteorder::{LittleEndian, U16, U32},
+    AsBytes, FromBytes, FromZeroes,
+};
+
+/// Value of erased or unprogrammed memory.
+const ERASED: u8 = 0xff;
+
+/// Somewhere product data can be read from.
+pub trait Source {
+    /// Raw TLV-C product data, or `None` if the source isn't fitted.
+    fn read(&mut self) -> Option<&[u8]>;
+}
+
+/// The CAN module's one-time programmable memory.
+pub struct Otp;
+
+impl Source for Otp {
+    fn read(&mut self) -> Option<&[u8]> {
+        Some(crate::otp::read())
+    }
+}
+
+/// Product data already in memory, e.g. built into the firmware for
+/// testing.
+impl Source for &[u8] {
+    fn read(&mut self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+/// Product data of the whole assembly.
+#[derive(Debug, Format)]
+pub struct Assembly {
+    /// The CAN module's own product data, which decides how it runs.
+    pub module: VitalProductData,
+    /// Product data of the carrier board, if it has any.
+    pub carrier: Option<VitalProductData>,
+}
+
+impl Assembly {
+    /// Read the carrier's product data to go with the module's.
+    ///
+    /// Carrier data that can't be parsed is logged and left out, as it only
+    /// describes the assembly and doesn't change how the module runs.
+    pub fn new(
+        module: VitalProductData,
+        carrier: Option<&mut dyn Source>,
+    ) -> Self {
+        let carrier = match carrier.map(VitalProductData::read) {
+            Some(Ok(Some(vpd))) => {
+                defmt::info!(
+                    "carrier_serial={} carrier_hardware={} carrier_sku={}",
+                    vpd.serial,
+                    vpd.hardware,
+                    vpd.sku,
+                );
+                Some(vpd)
+            }
+            Some(Err(e)) => {
+                defmt::warn!(
+                    "Carrier VPD unreadable: {}",
+                    defmt::Debug2Format(&e)
+                );
+                None
+            }
+            _ => None,
+        };
+
+        Self { module, carrier }
+    }
+}
 
 /// Vital product data
 #[derive(Debug, Format)]
@@ -12,9 +92,41 @@ pub struct VitalProductData {
     pub serial: Serial,
     pub hardware: Version,
     pub sku: Sku,
+    pub bit_rate: BitRate,
+    pub pins: Pins,
+    pub startup_delay: StartupDelay,
+}
+
+impl Default for VitalProductData {
+    /// Product data used when none can be read, with every field at its
+    /// default and an unknown SKU.
+    fn default() -> Self {
+        Self {
+            serial: Serial::default(),
+            hardware: Version::default(),
+            sku: Sku::Unknown(0),
+            bit_rate: BitRate::default(),
+            pins: Pins::default(),
+            startup_delay: StartupDelay::default(),
+        }
+    }
 }
 
 impl VitalProductData {
+    /// Read product data from a source.
+    ///
+    /// Returns `Ok(None)` if the source isn't fitted or is blank, as OTP is
+    /// on a unit that hasn't been provisioned yet.
+    pub fn read(
+        source: &mut dyn Source,
+    ) -> Result<Option<Self>, TlvcReadError<Infallible>> {
+        source
+            .read()
+            .filter(|buf| buf.iter().any(|b| *b != ERASED))
+            .map(Self::from_tlvc)
+            .transpose()
+    }
+
     /// Read TLV-C product data.
     ///
     /// If a tag is not presen, the default value for the type is used.
@@ -22,6 +134,9 @@ impl VitalProductData {
         let mut serial = None;
         let mut version = None;
         let mut sku: Option<u8> = None;
+        let mut bit_rate = None;
+        let mut pins = None;
+        let mut startup_delay = None;
 
         let mut reader = TlvcReader::begin(buf)?;
         while let Ok(Some(chunk)) = reader.next() {
@@ -29,14 +144,26 @@ impl VitalProductData {
                 b"SER " => serial = Self::process_chunk(&chunk)?,
                 b"HW  " => version = Self::process_chunk(&chunk)?,
                 b"SKU " => sku = Self::process_chunk(&chunk)?,
+                b"RATE" => bit_rate = Self::process_chunk(&chunk)?,
+                b"PINS" => pins = Self::process_chunk(&chunk)?,
+                b"DLAY" => startup_delay = Self::process_chunk(&chunk)?,
                 _ => {} // do nothing for unknown tags
             }
         }
 
+        // Out of range serials are kept for forward compatibility but likely
+        // indicate the fields were packed incorrectly during provisioning.
+        if let Some(Err(e)) = serial.as_ref().map(Serial::validate) {
+            defmt::warn!("Serial number out of range: {}", e);
+        }
+
         Ok(Self {
             serial: serial.unwrap_or_default(),
             hardware: version.unwrap_or_default(),
             sku: Sku::from(sku.unwrap_or_default()),
+            bit_rate: bit_rate.unwrap_or_default(),
+            pins: pins.unwrap_or_default(),
+            startup_delay: startup_delay.unwrap_or_default(),
         })
     }
 
@@ -65,16 +192,12 @@ impl VitalProductData {
 pub struct Serial {
     pub year: u8,
     pub week: u8,
-    pub seq: u16,
+    seq: U16<LittleEndian>,
 }
 
 impl Default for Serial {
     fn default() -> Self {
-        Self {
-            year: 99,
-            week: 99,
-            seq: 0x9999,
-        }
+        Self::new(99, 99, 0x9999)
     }
 }
 
@@ -84,19 +207,63 @@ impl Serial {
 
     /// Creates a new [`Serial`]
     pub fn new(year: u8, week: u8, seq: u16) -> Self {
-        Self { year, week, seq }
+        Self {
+            year,
+            week,
+            seq: U16::new(seq),
+        }
+    }
+
+    /// Sequence number within the week.
+    pub fn seq(&self) -> u16 {
+        self.seq.get()
+    }
+
+    /// Creates a new [`Serial`], checking the year and week are in range.
+    pub fn try_new(year: u8, week: u8, seq: u16) -> Result<Self, SerialError> {
+        let serial = Self::new(year, week, seq);
+        serial.validate()?;
+        Ok(serial)
+    }
+
+    /// Check the year is 0–99 and the week is 1–53.
+    pub fn validate(&self) -> Result<(), SerialError> {
+        if self.year > 99 {
+            return Err(SerialError::Year(self.year));
+        }
+
+        if !(1..=53).contains(&self.week) {
+            return Err(SerialError::Week(self.week));
+        }
+
+        Ok(())
     }
 }
 
+/// Serial number field out of range.
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// Year is not 0–99.
+    Year(u8),
+    /// Week is not 1–53.
+    Week(u8),
+}
+
 impl defmt::Format for Serial {
     fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "{:02}{:02}-{:04X}", self.year, self.week, self.seq)
+        defmt::write!(
+            fmt,
+            "{:02}{:02}-{:04X}",
+            self.year,
+            self.week,
+            self.seq()
+        )
     }
 }
 
 impl core::fmt::Display for Serial {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:02}{:02}-{:04X}", self.year, self.week, self.seq)
+        write!(f, "{:02}{:02}-{:04X}", self.year, self.week, self.seq())
     }
 }
 
@@ -134,12 +301,160 @@ impl defmt::Format for Version {
     }
 }
 
+/// Default bit rates programmed at boot, in bit/s.
+///
+/// Lets a freshly plugged adapter observe a bus before the host configures
+/// it. A nominal rate of zero leaves the interfaces unconfigured and a data
+/// rate of zero leaves the data phase unconfigured.
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct BitRate {
+    nominal: U32<LittleEndian>,
+    data: U32<LittleEndian>,
+}
+
+impl Default for BitRate {
+    fn default() -> Self {
+        Self::new(500_000, 2_000_000)
+    }
+}
+
+impl BitRate {
+    /// Assert size at compile time.
+    const _SIZE: () = assert!(core::mem::size_of::<Self>() == 8);
+
+    /// Creates a new [`BitRate`].
+    pub fn new(nominal: u32, data: u32) -> Self {
+        Self {
+            nominal: U32::new(nominal),
+            data: U32::new(data),
+        }
+    }
+
+    /// Nominal bit rate.
+    pub fn nominal(&self) -> u32 {
+        self.nominal.get()
+    }
+
+    /// Data bit rate.
+    pub fn data(&self) -> u32 {
+        self.data.get()
+    }
+}
+
+impl defmt::Format for BitRate {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "BitRate {{ nominal: {}, data: {} }}",
+            self.nominal(),
+            self.data()
+        );
+    }
+}
+
+/// Time after boot before the host can start the interfaces, in
+/// milliseconds.
+///
+/// For hosts that power the adapter before the bus has settled. Starts
+/// requested earlier are held back until the delay ends. Defaults to zero.
+#[derive(Debug, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct StartupDelay {
+    ms: U32<LittleEndian>,
+}
+
+impl StartupDelay {
+    /// Assert size at compile time.
+    const _SIZE: () = assert!(core::mem::size_of::<Self>() == 4);
+
+    /// Delay in milliseconds.
+    pub fn ms(&self) -> u32 {
+        self.ms.get()
+    }
+}
+
+impl defmt::Format for StartupDelay {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "StartupDelay {{ ms: {} }}", self.ms());
+    }
+}
+
+/// Transceiver control pins.
+///
+/// Each standby pin is one byte, the port in the high nibble (0 for port A)
+/// and the pin number in the low nibble, or `0xff` if the interface's
+/// transceiver has no standby pin. Bit `n` of `flags` is set if the standby
+/// pin of interface `n` is active low. Without the tag no pins are driven,
+/// which matches every SKU built so far.
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Pins {
+    standby: [u8; 2],
+    flags: u8,
+    reserved: u8,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Self {
+            standby: [NO_PIN; 2],
+            flags: 0,
+            reserved: 0,
+        }
+    }
+}
+
+/// Pin byte of a transceiver without a standby pin.
+const NO_PIN: u8 = 0xff;
+
+impl Pins {
+    /// Assert size at compile time.
+    const _SIZE: () = assert!(core::mem::size_of::<Self>() == 4);
+
+    /// Port and pin number of an interface's standby pin.
+    pub fn standby(&self, interface: usize) -> Option<(u8, u8)> {
+        match self.standby.get(interface) {
+            None | Some(&NO_PIN) => None,
+            Some(pin) => Some((pin >> 4, pin & 0xf)),
+        }
+    }
+
+    /// The tag as stored in the VPD, little-endian.
+    pub fn raw(&self) -> u32 {
+        u32::from_le_bytes([
+            self.standby[0],
+            self.standby[1],
+            self.flags,
+            self.reserved,
+        ])
+    }
+
+    /// Check if an interface's standby pin is active low.
+    pub fn active_low(&self, interface: usize) -> bool {
+        self.flags & (1 << interface) != 0
+    }
+}
+
+impl defmt::Format for Pins {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "Pins {{ standby: {:#x}, flags: {:#x} }}",
+            self.standby,
+            self.flags
+        );
+    }
+}
+
 /// SKU identity
 #[derive(Debug, Format)]
 #[repr(u8)]
 pub enum SkuId {
     M2KeyE = 1,
     MiniPCIe = 2,
+    /// M.2 Key E with FDCAN1 routed to a header as a third interface.
+    M2KeyEHeader = 3,
 }
 
 impl TryFrom<u8> for SkuId {
@@ -149,6 +464,7 @@ impl TryFrom<u8> for SkuId {
         match value {
             x if x == Self::M2KeyE as u8 => Ok(Self::M2KeyE),
             x if x == Self::MiniPCIe as u8 => Ok(Self::MiniPCIe),
+            x if x == Self::M2KeyEHeader as u8 => Ok(Self::M2KeyEHeader),
             _ => Err(value),
         }
     }
@@ -160,6 +476,30 @@ pub enum Sku {
     Unknown(u8),
 }
 
+impl Sku {
+    /// SKU number as stored in the VPD.
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Known(SkuId::M2KeyE) => SkuId::M2KeyE as u8,
+            Self::Known(SkuId::MiniPCIe) => SkuId::MiniPCIe as u8,
+            Self::Known(SkuId::M2KeyEHeader) => SkuId::M2KeyEHeader as u8,
+            Self::Unknown(id) => *id,
+        }
+    }
+
+    /// Number of CAN interfaces wired to connectors.
+    ///
+    /// Unknown SKUs are assumed to have the two interfaces every SKU has.
+    pub fn interface_count(&self) -> u8 {
+        match self {
+            Self::Known(SkuId::M2KeyE) => 2,
+            Self::Known(SkuId::MiniPCIe) => 2,
+            Self::Known(SkuId::M2KeyEHeader) => 3,
+            Self::Unknown(_) => 2,
+        }
+    }
+}
+
 impl From<u8> for Sku {
     fn from(value: u8) -> Self {
         match SkuId::try_from(value) {
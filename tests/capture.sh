@@ -0,0 +1,55 @@
+# Only runs on Linux.
+# Requires can-utils and python3 with pyusb.
+#
+# Captures frames on adapter interface `can0` started in loopback mode, so no
+# bus is needed, then stops the interface and checks the captured frames can
+# still be read back.
+
+# Exit if anything returns an error.
+set -e
+
+sudo ip link set can0 down
+sudo ip link set can0 type can bitrate 500000 loopback on
+sudo ip link set can0 up
+
+python3 - <<'PY'
+import usb.core
+
+# Vendor OUT request to the device starting capture.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+dev.ctrl_transfer(0x40, 28, 1, 0)
+PY
+
+cangen can0 -I 123 -L 8 -D i -g 5 -n 100
+sleep 1
+sudo ip link set can0 down
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+# Vendor OUT request to the device stopping capture.
+dev.ctrl_transfer(0x40, 28, 0, 0)
+
+# Vendor IN requests to the device for the captured frames, 6 at a time.
+records = []
+while True:
+    data = bytes(dev.ctrl_transfer(0xC0, 28, len(records), 0, 120))
+    if not data:
+        break
+    records += [data[i:i + 20] for i in range(0, len(data), 20)]
+
+print(f"captured={len(records)}")
+if len(records) != 64:
+    raise SystemExit("Expected the last 64 frames")
+
+for record in records:
+    time_us, can_id, interface, length = struct.unpack("<IIBB", record[:10])
+    if can_id != 0x123 or interface != 0 or length != 8:
+        raise SystemExit(f"Unexpected frame {record.hex()}")
+PY
+
+echo "Finished..."
@@ -36,4 +36,13 @@ echo "75.0%"; ip link set can0 type can bitrate 250000 sample-point 0.750
 echo "50.0%"; ip link set can0 type can bitrate 250000 sample-point 0.500
 
 
+echo "Testing out of range segments (should be rejected)."
+
+echo "prop_seg + phase_seg1 > tseg1_max"
+if ip link set can0 type can tq 50 prop-seg 200 phase-seg1 100 phase-seg2 10 sjw 1; then
+    echo "Out of range timing accepted"
+    exit 1
+fi
+
+
 echo "Finished..."
@@ -0,0 +1,39 @@
+# Only runs on Linux.
+# Requires dfu-util. Pass the firmware binary to download as the argument.
+#
+# Downloads the firmware padded to fill the bank, minus the image record and
+# settings pages, so every page is erased and programmed. The last double
+# word isn't blank, so verifying catches it not being written. Run it with the
+# adapter behind a hub or on a slow host too, where the poll timeouts
+# reported by the device must be long enough that dfu-util doesn't give up.
+
+# Exit if anything returns an error.
+set -e
+
+FIRMWARE=$1
+PADDED=$(mktemp)
+OUT=$(mktemp)
+trap 'rm -f "$PADDED" "$OUT"' EXIT
+
+SIZE=$(stat -c %s "$FIRMWARE")
+# 256K bank less the image record and two settings pages.
+BANK=$((250 * 1024))
+
+cp "$FIRMWARE" "$PADDED"
+head -c $((BANK - SIZE - 8)) /dev/zero | tr '\0' '\377' >> "$PADDED"
+head -c 8 /dev/zero | tr '\0' '\132' >> "$PADDED"
+
+echo "Downloading $BANK bytes"
+START=$(date +%s)
+dfu-util -a 0 -s 0x08000000:leave -D "$PADDED"
+echo "Download took $(($(date +%s) - START)) s"
+
+# wait for the device to reset and enumerate again.
+sleep 3
+
+echo "Verifying download"
+rm -f "$OUT"
+dfu-util -a 0 -s 0x08000000:"$BANK" -U "$OUT"
+cmp "$PADDED" "$OUT"
+
+echo "Finished..."
@@ -0,0 +1,35 @@
+# Only runs on Linux.
+# Requires dfu-util. Pass the firmware binary to download as the argument.
+#
+# Downloads the firmware padded to one double word more than fits below the
+# image record, which must be rejected without writing into the record pages
+# or swapping banks. The adapter keeps running its current firmware.
+
+# Exit if anything returns an error.
+set -e
+
+FIRMWARE=$1
+PADDED=$(mktemp)
+trap 'rm -f "$PADDED"' EXIT
+
+SIZE=$(stat -c %s "$FIRMWARE")
+# 256K bank less the image record and two settings pages.
+BANK=$((250 * 1024))
+
+cp "$FIRMWARE" "$PADDED"
+head -c $((BANK + 8 - SIZE)) /dev/zero | tr '\0' '\377' >> "$PADDED"
+
+echo "Downloading $((BANK + 8)) bytes (should be rejected)"
+if dfu-util -a 0 -s 0x08000000:leave -D "$PADDED"; then
+    echo "Oversized image accepted"
+    exit 1
+fi
+
+# clear the error state left by the rejected download.
+dfu-util -a 0 -e || true
+sleep 3
+
+echo "Checking the adapter is still running"
+lsusb -d 1d50:606f
+
+echo "Finished..."
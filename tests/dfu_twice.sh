@@ -0,0 +1,31 @@
+# Only runs on Linux.
+# Requires dfu-util. Pass the firmware binary to download as the argument.
+#
+# Downloads the same firmware twice without a power cycle. Each download is
+# written to the inactive bank and the banks are swapped, so the second
+# download must target the other bank to the first.
+
+# Exit if anything returns an error.
+set -e
+
+FIRMWARE=$1
+OUT=$(mktemp)
+trap 'rm -f "$OUT"' EXIT
+
+SIZE=$(stat -c %s "$FIRMWARE")
+
+for n in 1 2
+do
+    echo "Download $n"
+    dfu-util -a 0 -s 0x08000000:leave -D "$FIRMWARE"
+
+    # wait for the device to reset and enumerate again.
+    sleep 3
+
+    echo "Verifying download $n"
+    rm -f "$OUT"
+    dfu-util -a 0 -s 0x08000000:"$SIZE" -U "$OUT"
+    cmp "$FIRMWARE" "$OUT"
+done
+
+echo "Finished..."
@@ -0,0 +1,41 @@
+# Only runs on Linux.
+# Requires dfu-util and an adapter in DFU mode.
+
+# Exit if anything returns an error.
+set -e
+
+OUT=$(mktemp)
+trap 'rm -f "$OUT"' EXIT
+
+echo "Testing upload bounds."
+
+echo "Last page of flash"
+rm -f "$OUT"
+dfu-util -a 0 -s 0x0803F800:2048 -U "$OUT"
+
+echo "Past the end of flash (should be rejected)"
+rm -f "$OUT"
+if dfu-util -a 0 -s 0x0803FFC0:128 -U "$OUT"; then
+    echo "Read past the end of flash accepted"
+    exit 1
+fi
+
+echo "Misaligned download (should be rejected)"
+head -c 64 /dev/zero > "$OUT"
+if dfu-util -a 0 -s 0x08000001 -D "$OUT"; then
+    echo "Misaligned download accepted"
+    exit 1
+fi
+
+echo "Download not double word aligned (should be rejected)"
+head -c 64 /dev/zero > "$OUT"
+if dfu-util -a 0 -s 0x08000004 -D "$OUT"; then
+    echo "Download not double word aligned accepted"
+    exit 1
+fi
+
+echo "Device still responds after rejected requests"
+rm -f "$OUT"
+dfu-util -a 0 -s 0x0803F800:2048 -U "$OUT"
+
+echo "Finished..."
@@ -0,0 +1,84 @@
+# Only runs on Linux.
+# Requires python3 with pyusb and nothing else connected to adapter
+# interface `can0`. Must be run as root on most systems.
+#
+# Drives the host-reachable paths that used to panic and checks the device
+# rejects or absorbs the input and keeps responding: bit timing with a zero
+# prescaler or segments too long for the registers, and frames queued on an
+# interface whose transmit buffers can't drain.
+
+# Exit if anything returns an error.
+set -e
+
+ip link set can0 down
+ip link set can0 type can bitrate 500000 dbitrate 2000000 fd on
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+# The gs_usb driver holds interface 0, which pyusb claims for requests to it.
+dev.detach_kernel_driver(0)
+try:
+    def send(request, prop_seg, phase_seg1, phase_seg2, sjw, brp):
+        # gs_usb bit timing request (1) or data bit timing request (10)
+        # for channel 0.
+        data = struct.pack("<5I", prop_seg, phase_seg1, phase_seg2, sjw, brp)
+        dev.ctrl_transfer(0x41, request, 0, 0, data)
+
+    # Reset the rejected timing diagnostic.
+    dev.ctrl_transfer(0x40, 1, 21, 0)
+
+    print("Nominal prescaler of zero")
+    send(1, 0, 13, 2, 1, 0)
+    print("Data prescaler of zero")
+    send(10, 0, 13, 2, 1, 0)
+    print("Nominal segment 1 too long for the register")
+    send(1, 0, 0x101, 2, 1, 1)
+    print("Data segment 2 too long for the register")
+    send(10, 0, 13, 0x102, 1, 1)
+
+    data = dev.ctrl_transfer(0xC0, 0, 21, 0, 16)
+    count = struct.unpack("<4I", bytes(data))[0]
+    print(f"rejected={count}")
+    if count != 4:
+        raise SystemExit("Rejections not recorded")
+finally:
+    dev.attach_kernel_driver(0)
+PY
+
+# Without another node nothing acknowledges the frames, so they stay in the
+# transmit buffers and the queue behind them fills.
+ip link set can0 up
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+# Reset the overruns diagnostic.
+dev.ctrl_transfer(0x40, 1, 0, 0)
+
+# Transmit marked requests with an 8 byte frame, well past the queue depth.
+frame = struct.pack("<IBBH", 0x123, 0, 8, 0) + bytes(8)
+for marker in range(64):
+    dev.ctrl_transfer(0x40, 21, marker, 0, frame)
+
+data = dev.ctrl_transfer(0xC0, 0, 0, 0, 16)
+dropped = struct.unpack("<4I", bytes(data))[3]
+print(f"tx_dropped={dropped}")
+if dropped == 0:
+    raise SystemExit("Full transmit queue not counted")
+
+# Vendor IN request for the version, checking the device still responds.
+dev.ctrl_transfer(0xC0, 14, 0, 0, 64)
+PY
+
+ip link set can0 down
+
+echo "Finished..."
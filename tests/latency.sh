@@ -0,0 +1,49 @@
+# Only runs on Linux.
+# Requires can-utils and python3 with pyusb.
+#
+# Measures USB to CAN latency on adapter interface `can0` started in
+# loopback mode, so no bus is needed, and prints the results. Pass the number
+# of frames to measure as the first argument, default 32.
+
+# Exit if anything returns an error.
+set -e
+
+SAMPLES=${1:-32}
+
+sudo ip link set can0 down
+sudo ip link set can0 type can bitrate 500000 dbitrate 2000000 fd on \
+    loopback on
+sudo ip link set can0 up
+
+SAMPLES="$SAMPLES" python3 - <<'PY'
+import os
+
+import usb.core
+
+# Vendor OUT request to the device starting the latency test on interface 0.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+dev.ctrl_transfer(0x40, 26, int(os.environ["SAMPLES"]), 0)
+PY
+
+# Space the frames out so each one is measured.
+cangen can0 -I 123 -L 8 -g 60 -n "$((SAMPLES * 2))"
+sleep 1
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+# Vendor IN request to the device for the latency test results.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+data = dev.ctrl_transfer(0xC0, 26, 0, 0, 24)
+state, samples, lost, low, avg, high = struct.unpack("<6I", bytes(data))
+print(f"state={state} samples={samples} lost={lost}")
+print(f"min={low}us avg={avg}us max={high}us")
+if state != 2:
+    raise SystemExit("Latency test didn't complete")
+PY
+
+sudo ip link set can0 down
+
+echo "Finished..."
@@ -0,0 +1,37 @@
+# Only runs on Linux.
+# Requires python3 with pyusb and a provisioned adapter.
+# Optionally pass the VPD file the adapter was provisioned with, defaults to
+# `vpd.ron`.
+#
+# Packs the VPD the same way provisioning does and checks the CRC of the
+# OTP matches it, catching partial or failed OTP writes.
+
+# Exit if anything returns an error.
+set -e
+
+VPD=${1:-vpd.ron}
+
+WRITE_VPD="$VPD" cargo build --release
+BLOB=$(ls -t target/*/release/build/*/out/vpd.bin | head -n 1)
+
+BLOB="$BLOB" python3 - <<'PY'
+import binascii
+import os
+import struct
+
+import usb.core
+
+with open(os.environ["BLOB"], "rb") as f:
+    blob = f.read()
+expected = binascii.crc32(blob)
+
+# Vendor IN request to the device for the CRC of the VPD's length of OTP.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+data = dev.ctrl_transfer(0xC0, 29, len(blob), 0, 8)
+crc, length = struct.unpack("<2I", bytes(data))
+print(f"len={length} crc={crc:#010x} expected={expected:#010x}")
+if crc != expected:
+    raise SystemExit("OTP doesn't match the VPD")
+PY
+
+echo "Finished..."
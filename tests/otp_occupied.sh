@@ -0,0 +1,49 @@
+# Only runs on Linux.
+# Requires probe-rs, python3 with pyusb, usbutils and an adapter provisioned
+# with `vpd.ron` (see `tests/vpd_reprovision.sh`). Optionally pass the VPD
+# file the adapter was provisioned with.
+#
+# Boots firmware that provisions different VPD over the programmed OTP. The
+# write must be refused as occupied, leaving the OTP untouched, and the
+# device must keep running and enumerate.
+
+# Exit if anything returns an error.
+set -e
+
+VPD=${1:-vpd.ron}
+OTHER=$(mktemp --suffix=.ron)
+LOG=$(mktemp)
+trap 'rm -f "$OTHER" "$LOG"' EXIT
+
+# Refuse to run on blank OTP, which the write below would program.
+sh tests/otp_crc.sh "$VPD"
+
+# Same VPD with a different SKU.
+sed 's/("SKU ", \[ \[1\] \])/("SKU ", [ [2] ])/' "$VPD" > "$OTHER"
+if cmp -s "$VPD" "$OTHER"; then
+    echo "Couldn't change the SKU in $VPD"
+    exit 1
+fi
+
+WRITE_VPD="$OTHER" cargo build --release
+
+# probe-rs keeps running to print logs so stop it once booted.
+WRITE_VPD="$OTHER" timeout 30 cargo run --release > "$LOG" 2>&1 &
+RUN=$!
+
+sleep 20
+lsusb -d 1d50:606f -v | grep -q "CAN FD Adapter"
+wait $RUN || true
+
+grep -q "Occupied" "$LOG"
+grep -q "Init complete." "$LOG"
+
+if grep -q "panicked" "$LOG"; then
+    echo "Firmware panicked"
+    exit 1
+fi
+
+# The OTP still holds the original VPD.
+sh tests/otp_crc.sh "$VPD"
+
+echo "Finished..."
@@ -0,0 +1,57 @@
+# Only runs on Linux.
+# Requires can-utils, python3 with pyusb and a third CAN interface (default
+# `can2`, pass another as the first argument) on a bus shared with adapter
+# interface `can0`, both at the same bit rate and with `can0` up.
+#
+# Moves the nominal sample point of `can0` back and forth, taking the
+# controller through configuration mode each time, while frames arrive. The
+# sender retries frames nothing acknowledged, so every frame must reach the
+# host, including the last, and none may be stranded in the controller.
+
+# Exit if anything returns an error.
+set -e
+
+INJECT=${1:-can2}
+FRAMES=${2:-2000}
+LOG=$(mktemp)
+trap 'rm -f "$LOG"' EXIT
+
+timeout 30 candump -n "$FRAMES" can0 > "$LOG" &
+DUMP=$!
+sleep 0.5
+
+cangen "$INJECT" -g 1 -I 123 -L 8 -D i -n "$FRAMES" &
+
+python3 - <<'PY'
+import time
+
+import usb.core
+
+# Vendor OUT request to the device setting the sample point of interface 0.
+REQUEST_TYPE = 0x40
+SAMPLE_POINT = 18
+DIAGNOSTICS = 0
+DEFERRED_RECEIVE = 15
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+end = time.monotonic() + 3
+n = 0
+while time.monotonic() < end:
+    dev.ctrl_transfer(REQUEST_TYPE, SAMPLE_POINT, (750, 875)[n % 2], 0)
+    n += 1
+    time.sleep(0.002)
+print(f"reconfigured {n} times")
+
+data = dev.ctrl_transfer(0xC0, DIAGNOSTICS, DEFERRED_RECEIVE, 0, 4)
+print(f"deferred={int.from_bytes(bytes(data), 'little')}")
+PY
+
+wait $DUMP
+
+RECEIVED=$(wc -l < "$LOG")
+echo "received $RECEIVED of $FRAMES"
+if [ "$RECEIVED" -ne "$FRAMES" ]; then
+    exit 1
+fi
+
+echo "Finished..."
@@ -0,0 +1,71 @@
+# Only runs on Linux.
+# Requires python3 with pyusb. Must be run as root on most systems.
+#
+# Sends a remote frame flagged as FD straight to the adapter, which the Linux
+# driver can't, and checks it's dropped, counted in the FD diagnostic and
+# still echoed so the host doesn't wait for it.
+
+# Exit if anything returns an error.
+set -e
+
+ip link set can0 down
+ip link set can0 type can bitrate 500000 dbitrate 2000000 fd on
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+import usb.util
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+# The gs_usb driver holds interface 0, which pyusb claims for requests to it.
+dev.detach_kernel_driver(0)
+try:
+    intf = dev.get_active_configuration()[(0, 0)]
+    ep_out, ep_in = (
+        usb.util.find_descriptor(
+            intf,
+            custom_match=lambda e: usb.util.endpoint_direction(
+                e.bEndpointAddress
+            )
+            == direction,
+        )
+        for direction in (usb.util.ENDPOINT_OUT, usb.util.ENDPOINT_IN)
+    )
+
+    def mode(mode, flags):
+        # gs_usb mode request for channel 0.
+        dev.ctrl_transfer(0x41, 2, 0, 0, struct.pack("<2I", mode, flags))
+
+    # Reset the FD diagnostic.
+    dev.ctrl_transfer(0x40, 1, 22, 0)
+
+    # Start in loopback with FD so no bus is needed.
+    mode(1, (1 << 1) | (1 << 8))
+    try:
+        # gs_usb frame with echo id 7, the remote flag on the id and the FD
+        # flag.
+        can_id = 0x40000000 | 0x123
+        header = struct.pack("<2I4B", 7, can_id, 8, 0, 1 << 1, 0)
+        ep_out.write(header + bytes(64))
+
+        echo = bytes(ep_in.read(ep_in.wMaxPacketSize, timeout=1000))
+        echo_id, echo_can_id = struct.unpack("<2I", echo[:8])
+        print(f"echo_id={echo_id} can_id={echo_can_id:#x}")
+        if echo_id != 7:
+            raise SystemExit("Dropped frame not echoed")
+    finally:
+        mode(0, 0)
+
+    fd, brs, dropped = struct.unpack(
+        "<3I", bytes(dev.ctrl_transfer(0xC0, 0, 22, 0, 12))
+    )
+    print(f"fd={fd} brs={brs} dropped={dropped}")
+    if dropped != 1:
+        raise SystemExit("Remote FD frame not counted")
+finally:
+    dev.attach_kernel_driver(0)
+PY
+
+echo "Finished..."
@@ -0,0 +1,40 @@
+# Only runs on Linux.
+# Requires can-utils, python3 with pyusb and a third CAN FD interface
+# (default `can2`, pass another as the first argument) on a bus shared with
+# adapter interface `can0`, all at 500 kbit/s nominal and 5 Mbit/s data.
+#
+# Sends back-to-back 64 byte FD frames and reports the overruns they caused
+# on the adapter. Repeat with firmware built with different `RX_QUEUE_DEPTH`
+# values to compare how well each absorbs the burst.
+
+# Exit if anything returns an error.
+set -e
+
+INJECT=${1:-can2}
+FRAMES=${2:-5000}
+
+python3 - <<'PY'
+import usb.core
+
+# Vendor OUT request to the device resetting the overruns diagnostic.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+dev.ctrl_transfer(0x40, 1, 0, 0)
+PY
+
+echo "Sending $FRAMES FD frames on $INJECT"
+cangen "$INJECT" -f -b -L 64 -g 0 -I 123 -D i -n "$FRAMES"
+sleep 1
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+# Vendor IN request to the device for the overruns diagnostic.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+data = dev.ctrl_transfer(0xC0, 0, 0, 0, 16)
+fifo0, fifo1, queue, _ = struct.unpack("<4I", bytes(data))
+print(f"fifo0={fifo0} fifo1={fifo1} queue={queue}")
+PY
+
+echo "Finished..."
@@ -0,0 +1,45 @@
+# Only runs on Linux.
+# Requires python3 with pyusb.
+#
+# Runs the external loopback self-test on both interfaces, which checks the
+# transceivers as well as the controllers. The self-test transmits, so
+# disconnect the adapter from any bus first.
+
+# Exit if anything returns an error.
+set -e
+
+sudo ip link set can0 down
+sudo ip link set can1 down
+sudo ip link set can0 type can bitrate 500000
+sudo ip link set can1 type can bitrate 500000
+
+python3 - <<'PY'
+import struct
+import time
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+failed = False
+for interface in range(2):
+    # Vendor OUT request to the device starting the self-test.
+    dev.ctrl_transfer(0x40, 33, 0, interface)
+
+    # Vendor IN requests to the device until the result is in.
+    while True:
+        data = dev.ctrl_transfer(0xC0, 33, 0, interface, 4)
+        (status,) = struct.unpack("<I", bytes(data))
+        if status != 1:
+            break
+        time.sleep(0.05)
+
+    result = {0: "interrupted", 2: "FAIL", 3: "pass"}.get(status, status)
+    print(f"interface {interface}: {result}")
+    failed |= status != 3
+
+if failed:
+    raise SystemExit("Self-test failed")
+PY
+
+echo "Finished..."
@@ -1,5 +1,35 @@
 set -e
 
+# Out of range ids can't be sent with cansend, so send them through the
+# transmit both vendor request and check they're rejected and counted.
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+# Reset the invalid id diagnostic.
+for interface in range(2):
+    dev.ctrl_transfer(0x40, 1, 17, interface)
+
+# Standard id above 0x7FF, then extended id above 0x1FFFFFFF.
+ids = [0x800, (1 << 31) | 0x20000000]
+for raw in ids:
+    frame = struct.pack("<IBBH", raw, 0, 1, 0) + b"\x00"
+    try:
+        dev.ctrl_transfer(0x40, 5, 0, 0, frame)
+    except usb.core.USBError:
+        pass
+    else:
+        raise SystemExit(f"Out of range id {raw:#x} accepted")
+
+for interface in range(2):
+    data = dev.ctrl_transfer(0xC0, 0, 17, interface, 12)
+    _, from_host, last = struct.unpack("<3I", bytes(data))
+    assert from_host == len(ids), f"can{interface} counted {from_host}"
+    assert last == ids[-1], f"can{interface} last id {last:#x}"
+PY
+
 while true
 do
     # standard frames
@@ -8,6 +38,11 @@ do
     # extended frames.
     cansend can0 1F334455#1122334455667788
     cansend can1 1F334455#5566778811223344
+    # boundary ids.
+    cansend can0 000#00
+    cansend can1 7FF#FF
+    cansend can0 00000000#00
+    cansend can1 1FFFFFFF#FF
     # fd frames.
     cansend can0 213##311223344
     cansend can1 231##311223344
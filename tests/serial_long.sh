@@ -0,0 +1,33 @@
+# Only runs on Linux.
+# Requires probe-rs and usbutils.
+#
+# Boots a build with a serial number longer than the USB serial buffer. The
+# device must warn, keep running and enumerate with the serial number cut to
+# its first 24 characters.
+
+# Exit if anything returns an error.
+set -e
+
+SERIAL=0123456789ABCDEF0123456789ABCDEF
+LOG=$(mktemp)
+trap 'rm -f "$LOG"' EXIT
+
+TEST_LONG_SERIAL=$SERIAL cargo build --release
+
+# probe-rs keeps running to print logs so stop it once booted.
+TEST_LONG_SERIAL=$SERIAL timeout 30 cargo run --release > "$LOG" 2>&1 &
+RUN=$!
+
+sleep 20
+lsusb -d 1d50:606f -v | grep "iSerial" | grep -q " ${SERIAL:0:24}$"
+wait $RUN || true
+
+grep -q "Serial number truncated" "$LOG"
+grep -q "Init complete." "$LOG"
+
+if grep -q "panicked" "$LOG"; then
+    echo "Long serial number not handled"
+    exit 1
+fi
+
+echo "Finished..."
@@ -0,0 +1,46 @@
+# Only runs on Linux.
+# Requires can-utils, python3 with pyusb and adapter interfaces `can0` and
+# `can1` up on the same bus.
+#
+# Sends a known number of frames from `can0` to `can1` and checks the
+# traffic statistics of both interfaces count them.
+
+# Exit if anything returns an error.
+set -e
+
+FRAMES=${1:-100}
+
+python3 - <<'PY'
+import usb.core
+
+# Vendor OUT request to the device resetting the statistics diagnostic.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+for interface in range(2):
+    dev.ctrl_transfer(0x40, 1, 26, interface)
+PY
+
+echo "Sending $FRAMES 8 byte frames on can0"
+cangen can0 -L 8 -g 1 -I 123 -D i -n "$FRAMES"
+sleep 1
+
+FRAMES=$FRAMES python3 - <<'PY'
+import os
+import struct
+
+import usb.core
+
+frames = int(os.environ["FRAMES"])
+
+# Vendor IN request to the device for the statistics diagnostic.
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+stats = []
+for interface in range(2):
+    data = dev.ctrl_transfer(0xC0, 0, 26, interface, 28)
+    stats.append(struct.unpack("<7I", bytes(data)))
+    print(f"can{interface}: {stats[-1]}")
+
+assert stats[0][1] == frames and stats[0][3] == frames * 8, "can0 TX"
+assert stats[1][0] == frames and stats[1][2] == frames * 8, "can1 RX"
+PY
+
+echo "Finished..."
@@ -0,0 +1,48 @@
+# Only runs on Linux.
+# Requires can-utils, python3 with pyusb and a third CAN interface (default
+# `can2`, pass another as the first argument) on a bus shared with both
+# adapter interfaces `can0` and `can1`, all at the same bit rate.
+#
+# Every frame injected on the shared bus is received by both interfaces at
+# the same moment, so their receive timestamps must agree to within a few
+# microseconds however the interfaces were configured.
+
+# Exit if anything returns an error.
+set -e
+
+INJECT=${1:-can2}
+
+for n in $(seq 1 20)
+do
+    cansend "$INJECT" 123#DEADBEEF
+    sleep 0.05
+
+    python3 - <<'PY'
+import struct
+import sys
+
+import usb.core
+
+# Vendor IN request to the device for the timestamp diagnostic.
+REQUEST_TYPE = 0xC0
+DIAGNOSTICS = 0
+TIMESTAMP = 9
+# Allowed difference in microseconds.
+TOLERANCE = 2
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+stamps = []
+for interface in (0, 1):
+    data = dev.ctrl_transfer(REQUEST_TYPE, DIAGNOSTICS, TIMESTAMP, interface, 8)
+    rx, _ = struct.unpack("<II", bytes(data))
+    stamps.append(rx)
+
+skew = (stamps[0] - stamps[1]) & 0xFFFF
+skew = min(skew, 0x10000 - skew)
+print(f"can0={stamps[0]} can1={stamps[1]} skew={skew}us")
+if skew > TOLERANCE:
+    sys.exit(1)
+PY
+done
+
+echo "Finished..."
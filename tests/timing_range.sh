@@ -0,0 +1,60 @@
+# Only runs on Linux.
+# Requires python3 with pyusb. Must be run as root on most systems.
+#
+# Sends bit timing outside the advertised limits straight to the adapter,
+# bypassing the checks the Linux driver makes, and checks it's rejected with
+# the previous timing kept and the rejection recorded in diagnostics.
+
+# Exit if anything returns an error.
+set -e
+
+ip link set can0 down
+ip link set can0 type can bitrate 500000 dbitrate 2000000 fd on
+
+python3 - <<'PY'
+import struct
+
+import usb.core
+
+dev = usb.core.find(idVendor=0x1D50, idProduct=0x606F)
+
+# The gs_usb driver holds interface 0, which pyusb claims for requests to it.
+dev.detach_kernel_driver(0)
+try:
+    def timing():
+        # Vendor IN request for interface 0's NBTP and DBTP registers.
+        data = dev.ctrl_transfer(0xC0, 4, 0, 0, 24)
+        return struct.unpack("<2I", bytes(data[:8]))
+
+    def send(request, prop_seg, phase_seg1, phase_seg2, sjw, brp):
+        # gs_usb bit timing request (1) or data bit timing request (10)
+        # for channel 0.
+        data = struct.pack("<5I", prop_seg, phase_seg1, phase_seg2, sjw, brp)
+        dev.ctrl_transfer(0x41, request, 0, 0, data)
+
+    # Reset the rejected timing diagnostic.
+    dev.ctrl_transfer(0x40, 1, 21, 0)
+    before = timing()
+
+    print("Nominal prescaler above brp_max (too slow)")
+    send(1, 0, 200, 50, 1, 600)
+    print("Nominal segment 1 above tseg1_max")
+    send(1, 100, 200, 10, 1, 1)
+    print("Data prescaler above brp_max")
+    send(10, 0, 10, 5, 1, 40)
+
+    if timing() != before:
+        raise SystemExit("Rejected timing was applied")
+
+    count, data, requested, nearest = struct.unpack(
+        "<4I", bytes(dev.ctrl_transfer(0xC0, 0, 21, 0, 16))
+    )
+    print(f"rejected={count} data={data} requested={requested} "
+          f"nearest={nearest}")
+    if count != 3 or data != 1 or nearest == 0:
+        raise SystemExit("Rejections not recorded")
+finally:
+    dev.attach_kernel_driver(0)
+PY
+
+echo "Finished..."
@@ -0,0 +1,33 @@
+# Only runs on Linux.
+# Requires probe-rs and usbutils.
+#
+# Boots a normal build, without `WRITE_VPD`, that reads blank product data
+# in place of the OTP, as on the first boot of an unprovisioned unit. The
+# device must warn, keep running and still enumerate. The OTP itself isn't
+# touched.
+
+# Exit if anything returns an error.
+set -e
+
+LOG=$(mktemp)
+trap 'rm -f "$LOG"' EXIT
+
+TEST_BLANK_VPD=1 cargo build --release
+
+# probe-rs keeps running to print logs so stop it once booted.
+TEST_BLANK_VPD=1 timeout 30 cargo run --release > "$LOG" 2>&1 &
+RUN=$!
+
+sleep 20
+lsusb -d 1d50:606f -v | grep -q "CAN FD Adapter"
+wait $RUN || true
+
+grep -q "VPD not provisioned" "$LOG"
+grep -q "Init complete." "$LOG"
+
+if grep -q "panicked\|VPD unreadable" "$LOG"; then
+    echo "Blank VPD not handled"
+    exit 1
+fi
+
+echo "Finished..."
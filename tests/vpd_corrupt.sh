@@ -0,0 +1,34 @@
+# Only runs on Linux.
+# Requires probe-rs and usbutils. Optionally pass the VPD file to corrupt,
+# defaults to `vpd.ron`.
+#
+# Boots firmware that parses a corrupt copy of the VPD in place of the OTP.
+# The device must log the failure, keep running and still enumerate so it
+# can be recovered over DFU. The OTP itself isn't touched.
+
+# Exit if anything returns an error.
+set -e
+
+VPD=${1:-vpd.ron}
+LOG=$(mktemp)
+trap 'rm -f "$LOG"' EXIT
+
+TEST_CORRUPT_VPD="$VPD" cargo build --release
+
+# probe-rs keeps running to print logs so stop it once booted.
+TEST_CORRUPT_VPD="$VPD" timeout 30 cargo run --release > "$LOG" 2>&1 &
+RUN=$!
+
+sleep 20
+lsusb -d 1d50:606f -v | grep -q "CAN FD Adapter"
+wait $RUN || true
+
+grep -q "VPD unreadable" "$LOG"
+grep -q "Init complete." "$LOG"
+
+if grep -q "panicked" "$LOG"; then
+    echo "Firmware panicked"
+    exit 1
+fi
+
+echo "Finished..."
@@ -0,0 +1,34 @@
+# Only runs on Linux.
+# Requires probe-rs and an adapter whose OTP is blank or already holds the
+# VPD being written. OTP can't be erased so only run this on a test unit.
+# Optionally pass the VPD file to write, defaults to `vpd.ron`.
+#
+# Provisions the same VPD twice. `vpd.ron` packs to 60 bytes, which isn't a
+# whole number of double-words, so the last double-word is padded with 0xff.
+# The second run must accept the padded data as already written rather than
+# reporting the OTP as occupied.
+
+# Exit if anything returns an error.
+set -e
+
+VPD=${1:-vpd.ron}
+LOG=$(mktemp)
+trap 'rm -f "$LOG"' EXIT
+
+WRITE_VPD="$VPD" cargo build --release
+
+for n in 1 2
+do
+    echo "Provisioning run $n"
+    # probe-rs keeps running to print logs so stop it once booted.
+    WRITE_VPD="$VPD" timeout 30 cargo run --release > "$LOG" 2>&1 || true
+
+    if grep -q "Occupied" "$LOG"; then
+        echo "VPD reported as occupied"
+        exit 1
+    fi
+
+    grep -q "Init complete." "$LOG"
+done
+
+echo "Finished..."